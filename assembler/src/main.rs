@@ -6,62 +6,121 @@ use std::collections::HashMap;
 
 fn main() {
     let args: Vec<_> = env::args().collect();
-    if args.len() == 1 {
-        panic!("Usage: {} <filename>", args[0]);
-    } 
+    let mut filename = None;
+    let mut symbol_file = None;
+    let mut line_map_file = None;
+    let mut i = 1;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--symbols" if i + 1 < args.len() => {
+                symbol_file = Some(args[i + 1].clone());
+                i += 1;
+            }
+            "--line-map" if i + 1 < args.len() => {
+                line_map_file = Some(args[i + 1].clone());
+                i += 1;
+            }
+            s if s.starts_with("--") => panic!("Usage: {} [--symbols <file>] [--line-map <file>] <filename>", args[0]),
+            s => filename = Some(s.to_string()),
+        }
+        i += 1;
+    }
+    let filename = match filename {
+        Some(f) => f,
+        None => panic!("Usage: {} [--symbols <file>] [--line-map <file>] <filename>", args[0]),
+    };
 
-    let f = match File::open(&args[1]) {
+    let f = match File::open(&filename) {
         Ok(file) => BufReader::new(file),
         Err(err) => panic!("File open error: {:?}", err),
     };
 
     let mut symbols = HashMap::new();
     let mut address = 0;
+    // (address, line number) of every instruction
+    let mut lines = Vec::new();
 
-    for line in f.lines() {
+    for (number, line) in f.lines().enumerate() {
         let l = line.unwrap();
-        let mut opcode = l.split_whitespace().nth(0).unwrap().to_string();
-        if opcode.chars().rev().nth(0).unwrap() == ':' {
+        let mut opcode = l.split_whitespace().next().unwrap().to_string();
+        if opcode.ends_with(':') {
             opcode.pop();
             symbols.insert(opcode, address);
         } else {
-            parse_line(&l, &symbols);
+            parse_line(&l, address, &symbols);
+            lines.push((address, number + 1));
+            // a label takes no space: it names the next instruction
+            address += 4;
         }
-        address += 4;
+    }
+
+    if let Some(path) = symbol_file {
+        write_symbols(&path, &symbols);
+    }
+    if let Some(path) = line_map_file {
+        write_line_map(&path, &filename, &lines);
+    }
+}
+
+// Write the label table as "<address in hex> <name>" lines sorted by address,
+// so that the simulator can map PCs back to functions.
+fn write_symbols(path: &str, symbols: &HashMap<String, u32>) {
+    let mut sorted: Vec<_> = symbols.iter().collect();
+    sorted.sort_by_key(|&(name, addr)| (*addr, name.clone()));
+    let mut f = match File::create(path) {
+        Ok(file) => file,
+        Err(err) => panic!("File open error: {:?}", err),
+    };
+    for (name, addr) in sorted {
+        writeln!(f, "{:08x} {}", addr, name).expect("File write error");
     }
 }
 
-fn parse_line(line: &str, symbols: &HashMap<String, u32>) {
-    let opcode = line.split_whitespace().nth(0).unwrap();
+// Write the source file name on a "source <path>" line, then the source line
+// of every instruction as "<address in hex> <line>" lines, so that the
+// simulator can report coverage per source line.
+fn write_line_map(path: &str, source: &str, lines: &[(u32, usize)]) {
+    let mut f = match File::create(path) {
+        Ok(file) => file,
+        Err(err) => panic!("File open error: {:?}", err),
+    };
+    writeln!(f, "source {}", source).expect("File write error");
+    for &(addr, line) in lines {
+        writeln!(f, "{:08x} {}", addr, line).expect("File write error");
+    }
+}
+
+fn parse_line(line: &str, address: u32, symbols: &HashMap<String, u32>) {
+    let opcode = line.split_whitespace().next().unwrap();
     match opcode {
         "add" | "sub" | "sll" | "slt" | "sltu" | "xor" 
               | "srl" | "sra" | "or" | "and" 
-            => parse_r_type(&line),
+            => parse_r_type(line),
 
         "addi" | "slti" | "sltiu" | "xori" | "ori" | "andi"
                | "lb" | "lh" | "lw" | "lbu" | "lhu"
-            => parse_i_type(&line),
+            => parse_i_type(line),
 
         "sb" | "sh" | "sw"
-            => parse_s_type(&line),
+            => parse_s_type(line),
 
         "beq" | "bne" | "blt" | "bge" | "bltu" | "bgeu"
-            => parse_b_type(&line, &symbols),
+            => parse_b_type(line, address, symbols),
 
-        "jal"  => parse_jal(&line, &symbols),
-        "jalr" => parse_jalr(&line, &symbols),
-        "lui" => parse_lui(&line),
-        "print_int" | "exit" => parse_custom(&line),
+        "jal"  => parse_jal(line, address, symbols),
+        "jalr" => parse_jalr(line, symbols),
+        "lui" => parse_lui(line),
+        "print_int" | "exit" => parse_custom(line),
 
         _ => panic!("Unknown opcode!"),
     }
 }
 
 fn parse_custom(line: &str) {
-    let opcode = line.split_whitespace().nth(0).unwrap();
+    let opcode = line.split_whitespace().next().unwrap();
     match opcode {
         "print_int" => {
-            let mut rd = line.split_whitespace().nth(1).unwrap().to_string();
+            let rd = line.split_whitespace().nth(1).unwrap().to_string();
             println!("00000000000000000001{:05b}0001011", register(&rd));
         }
         "exit" => {
@@ -74,7 +133,7 @@ fn parse_custom(line: &str) {
 }
 
 fn parse_r_type(line: &str) {
-    let opcode = line.split_whitespace().nth(0).unwrap();
+    let opcode = line.split_whitespace().next().unwrap();
     let mut rd = line.split_whitespace().nth(1).unwrap().to_string();
     let mut rs1 = line.split_whitespace().nth(2).unwrap().to_string();
     let mut rs2 = line.split_whitespace().nth(3).unwrap().to_string();
@@ -97,7 +156,7 @@ fn parse_r_type(line: &str) {
 }
 
 fn parse_i_type(line: &str) {
-    let opcode = line.split_whitespace().nth(0).unwrap();
+    let opcode = line.split_whitespace().next().unwrap();
     let mut rd = line.split_whitespace().nth(1).unwrap().to_string();
     let mut rs1 = line.split_whitespace().nth(2).unwrap().to_string();
     let imm = line.split_whitespace().nth(3).unwrap().to_string().parse::<u32>().unwrap();
@@ -129,7 +188,7 @@ fn parse_i_type(line: &str) {
 }
 
 fn parse_s_type(line: &str) {
-    let opcode = line.split_whitespace().nth(0).unwrap();
+    let opcode = line.split_whitespace().next().unwrap();
     let mut rs1 = line.split_whitespace().nth(1).unwrap().to_string();
     let mut rs2 = line.split_whitespace().nth(2).unwrap().to_string();
     let imm = line.split_whitespace().nth(3).unwrap().to_string().parse::<u32>().unwrap();
@@ -141,11 +200,12 @@ fn parse_s_type(line: &str) {
         _    => panic!("Unknown I type opcode!"),
     };
     println!("{:07b}{:05b}{:05b}{:03b}{:05b}{:07b}", 
-             imm&0xffe, register(&rs2), register(&rs1), funct3, imm&0x01f, opcode);
+             (imm >> 5) & 0x7f, register(&rs2), register(&rs1), funct3, imm & 0x1f, opcode);
 }
 
-fn parse_b_type(line: &str, symbols: &HashMap<String, u32>) {
-    let opcode = line.split_whitespace().nth(0).unwrap();
+// Branches and jumps encode the offset from their own address to the label.
+fn parse_b_type(line: &str, address: u32, symbols: &HashMap<String, u32>) {
+    let opcode = line.split_whitespace().next().unwrap();
     let mut rs1 = line.split_whitespace().nth(1).unwrap().to_string();
     let mut rs2 = line.split_whitespace().nth(2).unwrap().to_string();
     let label = line.split_whitespace().nth(3).unwrap().to_string();
@@ -159,20 +219,21 @@ fn parse_b_type(line: &str, symbols: &HashMap<String, u32>) {
         "bgeu" => (0b1100011, 0b111),
         _      => panic!("Unknown B type opcode!"),
     };
-    let addr = symbols.get(&label).unwrap();
-    println!("{:01b}{:06b}{:05b}{:05b}{:03b}{:04b}{:01b}{:07b}", 
-             addr&0x1000, addr&0x07e0, register(&rs2), register(&rs1),
-             funct3, addr&0x001e, addr&0x0800, opcode);
+    let offset = symbols.get(&label).unwrap().wrapping_sub(address);
+    println!("{:01b}{:06b}{:05b}{:05b}{:03b}{:04b}{:01b}{:07b}",
+             (offset >> 12) & 1, (offset >> 5) & 0x3f, register(&rs2), register(&rs1),
+             funct3, (offset >> 1) & 0xf, (offset >> 11) & 1, opcode);
 }
 
-fn parse_jal(line: &str, symbols: &HashMap<String, u32>) {
+fn parse_jal(line: &str, address: u32, symbols: &HashMap<String, u32>) {
     let mut rd = line.split_whitespace().nth(1).unwrap().to_string();
     let label = line.split_whitespace().nth(2).unwrap().to_string();
     rd.pop();
-    let addr = symbols.get(&label).unwrap();
+    let offset = symbols.get(&label).unwrap().wrapping_sub(address);
 
-    println!("{:01b}{:010b}{:01b}{:08b}{:05b}1101111", 
-             addr&0x100000, addr&0x07fe, addr&0x0800, addr&0xff000, register(&rd));
+    println!("{:01b}{:010b}{:01b}{:08b}{:05b}1101111",
+             (offset >> 20) & 1, (offset >> 1) & 0x3ff, (offset >> 11) & 1, (offset >> 12) & 0xff,
+             register(&rd));
 }
 
 fn parse_jalr(line: &str, symbols: &HashMap<String, u32>) {
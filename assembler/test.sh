@@ -1,2 +1,4 @@
 cargo run tests/test1.asm > result1.bin
 diff result1.bin tests/expect1.bin
+cargo run -- --symbols result2.sym tests/labels.asm > result2.bin
+diff result2.bin tests/expect_labels.bin && diff result2.sym tests/expect_labels.sym
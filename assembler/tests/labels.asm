@@ -0,0 +1,8 @@
+start:
+addi $x1, $x0, 1
+loop:
+addi $x1, $x1, 1
+beq $x1, $x2, loop
+done:
+jal $x0, done
+jal $x1, start
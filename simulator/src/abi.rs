@@ -0,0 +1,141 @@
+// Calling-convention checker.
+//
+// Keeps a shadow call stack like the profiler's: a JAL or JALR through ra
+// pushes a frame with sp and s0-s11 as they were at the call, and a return
+// pops it and checks that
+//   - it goes back to the instruction after the call,
+//   - sp and the callee-saved registers s0-s11 have their values again.
+// Calls through the alternate link register x5 are tracked but not
+// checked, as they are used for millicode such as register save routines.
+// Each problem is printed the first time a function has it and counted
+// after that.
+
+use std::fmt;
+
+use disasm;
+use profile::{is_call, is_return};
+use symbols::Symbols;
+
+// s0, s1 and s2-s11
+const SAVED: [usize; 12] = [8, 9, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27];
+
+struct Frame {
+    entry: u32,
+    call_site: u32,
+    sp: u64,
+    saved: [u64; 12],
+    checked: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Problem {
+    // the register with this index does not have its value from the call
+    Register(u32),
+    // the return does not go back to the call site
+    ReturnAddress,
+}
+
+#[derive(Debug, Clone)]
+pub struct Report {
+    // entry address of the function that returned
+    pub function: u32,
+    pub name: String,
+    // the return instruction
+    pub pc: u32,
+    pub problem: Problem,
+    pub expected: u64,
+    pub actual: u64,
+    pub count: u64,
+}
+
+pub struct AbiChecker {
+    symbols: Symbols,
+    stack: Vec<Frame>,
+    pub reports: Vec<Report>,
+    // put in front of reports, to tell harts apart
+    pub(crate) label: String,
+}
+
+impl AbiChecker {
+    pub fn new(symbols: Symbols) -> AbiChecker {
+        AbiChecker { symbols, stack: Vec::new(), reports: Vec::new(), label: String::new() }
+    }
+
+    // Called after every retired instruction with the registers it left.
+    pub fn retire(&mut self, pc: u32, instruction: u32, next_pc: u32, register: &[u64; 32]) {
+        if is_call(instruction) {
+            let mut saved = [0; 12];
+            for (value, &index) in saved.iter_mut().zip(SAVED.iter()) {
+                *value = register[index];
+            }
+            self.stack.push(Frame {
+                entry: next_pc,
+                call_site: pc,
+                sp: register[2],
+                saved,
+                checked: (instruction & 0xf80) >> 7 == 1,
+            });
+        } else if is_return(instruction) {
+            // a return from the function the program started in
+            let frame = match self.stack.pop() {
+                Some(frame) => frame,
+                None => return,
+            };
+            if !frame.checked {
+                return;
+            }
+            if next_pc != frame.call_site.wrapping_add(4) {
+                self.add(&frame, pc, Problem::ReturnAddress, frame.call_site.wrapping_add(4) as u64,
+                         next_pc as u64);
+            }
+            if register[2] != frame.sp {
+                self.add(&frame, pc, Problem::Register(2), frame.sp, register[2]);
+            }
+            for (&value, &index) in frame.saved.iter().zip(SAVED.iter()) {
+                if register[index] != value {
+                    self.add(&frame, pc, Problem::Register(index as u32), value, register[index]);
+                }
+            }
+        }
+    }
+
+    fn add(&mut self, frame: &Frame, pc: u32, problem: Problem, expected: u64, actual: u64) {
+        let found = self.reports.iter_mut()
+            .find(|r| r.function == frame.entry && r.problem == problem);
+        match found {
+            Some(report) => report.count += 1,
+            None => {
+                let name = self.symbols.function(frame.entry);
+                let report = Report { function: frame.entry, name, pc, problem, expected, actual,
+                                      count: 1 };
+                eprintln!("{}Calling convention: {}", self.label, report);
+                self.reports.push(report);
+            }
+        }
+    }
+
+    // Print how often each problem happened.
+    pub fn report(&self) {
+        if self.reports.is_empty() {
+            return;
+        }
+        eprintln!("Calling convention summary:");
+        for r in self.reports.iter() {
+            eprintln!("  {:>8}x  {}", r.count, r);
+        }
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.problem {
+            Problem::Register(index) =>
+                write!(f, "{} returns at pc {:#010x} with {} = {:#x} instead of {:#x}",
+                       self.name, self.pc, disasm::register_name(index), self.actual,
+                       self.expected),
+            Problem::ReturnAddress =>
+                write!(f, "{} returns at pc {:#010x} to {:#010x} instead of {:#010x}",
+                       self.name, self.pc, self.actual, self.expected),
+        }
+    }
+}
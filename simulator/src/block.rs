@@ -0,0 +1,134 @@
+// Basic-block execution.
+//
+// With `State::blocks` set, run() executes a basic block at a time instead of
+// one instruction per call.  A block starts wherever control arrives and
+// runs up to the first instruction that may leave the straight line: a
+// branch, jump, system instruction, fence.i, exit or illegal instruction.
+// It also ends at a page boundary, at the end of the image and after
+// MAX_BLOCK instructions.  Its length is cached per program word next to
+// the decoded instructions, so executing a block is a tight loop over
+// micro-ops that were decoded once.
+//
+// Only the start of a block checks for interrupts and translates the fetch
+// address, which also covers the PMP check for the whole block, so a pending
+// interrupt waits until the block is done and the TLB sees one fetch per
+// block.  An exception stops the block at the faulting instruction, exactly
+// as it does in step(), and an instruction that changes code in the block,
+// which drops its decoded slots, is the last one the block runs.  Every
+// instruction retires on its own: instret, cycles, the caches, the branch
+// predictor and mtime end up the same as without blocks.
+
+use csr::PRV_M;
+use decode::{decode, Op};
+use mmu::Access;
+use State;
+
+pub(crate) const MAX_BLOCK: usize = 64;
+
+// instructions in a 4 KiB page
+const PAGE_WORDS: usize = 1024;
+
+// True for the ops that end a block.
+fn ends_block(op: Op) -> bool {
+    matches!(op, Op::Jal | Op::Jalr | Op::Beq | Op::Bne | Op::Blt | Op::Bge | Op::Bltu | Op::Bgeu
+                 | Op::System | Op::FenceI | Op::Exit | Op::Illegal)
+}
+
+// True for the ops that can see mtime, through the bus or the time CSR.
+fn reads_time(op: Op) -> bool {
+    matches!(op, Op::Lb | Op::Lh | Op::Lw | Op::Lbu | Op::Lhu | Op::Lwu | Op::Ld
+                 | Op::Sb | Op::Sh | Op::Sw | Op::Sd | Op::System | Op::Amo)
+}
+
+impl State {
+    // Forget every block that contains the word at index.
+    pub(crate) fn invalidate_blocks(&mut self, index: usize) {
+        for i in index.saturating_sub(MAX_BLOCK - 1)..=index {
+            self.block_lens[i] = 0;
+        }
+    }
+
+    // Length of the block starting at index, decoding it on first use.
+    fn block_len(&mut self, index: usize) -> usize {
+        if self.block_lens[index] != 0 {
+            return self.block_lens[index] as usize;
+        }
+        let mut len = 0;
+        loop {
+            let i = index + len;
+            let mut d = self.decoded[i];
+            if d.op == Op::Undecoded {
+                d = decode(self.imem[i], self.xlen);
+                self.decoded[i] = d;
+            }
+            len += 1;
+            let next = i + 1;
+            if ends_block(d.op) || len == MAX_BLOCK || next == self.imem.len()
+                || next.is_multiple_of(PAGE_WORDS) {
+                break;
+            }
+        }
+        self.block_lens[index] = len as u8;
+        len
+    }
+
+    // Execute the block at the pc, or at most its first n instructions (n is
+    // at least 1), taking a pending interrupt first.  Returns the number of
+    // instructions executed and the pc of the last one.
+    pub(crate) fn run_block(&mut self, n: u64) -> (u64, u32) {
+        if self.csr.mie != 0 {
+            self.check_interrupts();
+        }
+        let start = self.address;
+        let (index, paddr) = match self.fetch_index(start) {
+            Ok(found) => found,
+            Err(exception) => {
+                self.raise(exception);
+                self.instret += 1;
+                self.cycle += 1;
+                self.bus.borrow_mut().tick();
+                return (1, start);
+            }
+        };
+        let mut len = self.block_len(index).min(n.min(MAX_BLOCK as u64) as usize);
+        // a PMP entry that covers only part of the block has to be checked
+        // for every instruction
+        let checked = self.privilege != PRV_M || self.csr.pmp_locked_any();
+        if len > 1 && checked && !self.pmp_allows(paddr, 4 * len as u32, Access::Fetch, self.privilege) {
+            len = 1;
+        }
+
+        let mut pc = start;
+        let mut executed = 0;
+        // instructions the bus has not been told about yet; mtime only has
+        // to be up to date when something can read it
+        let mut unticked = 0;
+        // a store to tohost ends the program in the middle of a block
+        while executed < len && !self.is_exit {
+            pc = self.address;
+            let d = self.decoded[index + executed];
+            if let Some(ref mut cache) = self.observers.icache {
+                self.cycle += cache.access(paddr + 4 * executed as u32, false);
+            }
+            if unticked != 0 && reads_time(d.op) {
+                self.bus.borrow_mut().tick_n(unticked);
+                unticked = 0;
+            }
+            executed += 1;
+            if let Err(exception) = self.execute(&d) {
+                self.raise(exception);
+                len = executed;
+            }
+            // a store or a disk read changed code in the block, whose
+            // decoded slots are gone now
+            if self.block_lens[index] == 0 {
+                len = executed;
+            }
+            self.instret += 1;
+            self.cycle += self.cost(d.op);
+            unticked += 1;
+        }
+        self.bus.borrow_mut().tick_n(unticked);
+        (executed as u64, pc)
+    }
+}
@@ -0,0 +1,287 @@
+// Branch predictor models.
+// Conditional branches are predicted by one of the direction predictors
+// below.  JAL targets are known at decode time and never mispredict; JALR
+// returns use the return address stack and other JALRs a last-target table.
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Model {
+    NotTaken,
+    Btfn,
+    Bimodal,
+    Gshare,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    Branch,
+    Jal,
+    Return,
+    Indirect,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct BranchStats {
+    pub executed: u64,
+    pub taken: u64,
+    pub mispredicted: u64,
+}
+
+pub struct Predictor {
+    model: Model,
+    bits: u32,
+    counters: Vec<u8>,
+    history: u32,
+    ras: Vec<u32>,
+    ras_size: usize,
+    targets: HashMap<u32, u32>,
+    pub penalty: u64,
+    pub stats: BTreeMap<u32, (Kind, BranchStats)>,
+}
+
+impl Predictor {
+    // Parse a spec such as "gshare,bits=12,ras=8,penalty=3".
+    // The first item selects the model: nt, btfn, bimodal or gshare.
+    pub fn parse(spec: &str) -> Predictor {
+        let mut items = spec.split(',').filter(|s| !s.is_empty());
+        let model = match items.next() {
+            Some("nt") => Model::NotTaken,
+            Some("btfn") => Model::Btfn,
+            Some("bimodal") => Model::Bimodal,
+            Some("gshare") => Model::Gshare,
+            Some(m) => panic!("Unknown branch predictor: {}", m),
+            None => panic!("Branch predictor spec is empty"),
+        };
+        let mut bits = 10;
+        let mut ras_size = 8;
+        let mut penalty = 0;
+        for item in items {
+            let mut kv = item.splitn(2, '=');
+            let key = kv.next().unwrap();
+            let value = match kv.next().map(|v| v.parse::<u64>()) {
+                Some(Ok(n)) => n,
+                _ => panic!("Branch predictor option {} needs a numeric value", key),
+            };
+            match key {
+                "bits" => bits = value as u32,
+                "ras" => ras_size = value as usize,
+                "penalty" => penalty = value,
+                _ => panic!("Unknown branch predictor option: {}", key),
+            }
+        }
+        if bits == 0 || bits > 24 {
+            panic!("Branch predictor table bits must be between 1 and 24");
+        }
+        Predictor {
+            model,
+            bits,
+            // 2-bit saturating counters, initialised to weakly not-taken
+            counters: vec![1; 1 << bits],
+            history: 0,
+            ras: Vec::new(),
+            ras_size,
+            targets: HashMap::new(),
+            penalty,
+            stats: BTreeMap::new(),
+        }
+    }
+
+    fn record(&mut self, pc: u32, kind: Kind, taken: bool, correct: bool) -> bool {
+        let entry = self.stats.entry(pc).or_insert((kind, BranchStats::default()));
+        entry.1.executed += 1;
+        if taken {
+            entry.1.taken += 1;
+        }
+        if !correct {
+            entry.1.mispredicted += 1;
+        }
+        correct
+    }
+
+    fn index(&self, pc: u32) -> usize {
+        let mask = (1 << self.bits) - 1;
+        match self.model {
+            Model::Gshare => (((pc >> 2) ^ self.history) & mask) as usize,
+            _ => ((pc >> 2) & mask) as usize,
+        }
+    }
+
+    // Predict and train on a conditional branch.  Returns true if the
+    // prediction was correct.
+    pub fn branch(&mut self, pc: u32, target: u32, taken: bool) -> bool {
+        let index = self.index(pc);
+        let prediction = match self.model {
+            Model::NotTaken => false,
+            Model::Btfn => target < pc,
+            Model::Bimodal | Model::Gshare => self.counters[index] >= 2,
+        };
+        if self.model == Model::Bimodal || self.model == Model::Gshare {
+            let counter = &mut self.counters[index];
+            if taken && *counter < 3 {
+                *counter += 1;
+            } else if !taken && *counter > 0 {
+                *counter -= 1;
+            }
+            let mask = (1 << self.bits) - 1;
+            self.history = ((self.history << 1) | taken as u32) & mask;
+        }
+        self.record(pc, Kind::Branch, taken, prediction == taken)
+    }
+
+    // Handle JAL and JALR.  rd and rs1 follow the calling convention hints
+    // of the ISA manual: a link register (x1 or x5) as rd marks a call and as
+    // rs1 marks a return, unless rd is the same link register.  With two
+    // different link registers (a coroutine switch) the stack pops, then
+    // pushes.
+    pub fn jump(&mut self, pc: u32, target: u32, rd: u32, rs1: Option<u32>) -> bool {
+        let is_link = |r: u32| r == 1 || r == 5;
+        let correct = match rs1 {
+            None => self.record(pc, Kind::Jal, true, true),
+            Some(rs1) if is_link(rs1) && rs1 != rd && self.ras_size > 0 => {
+                let predicted = self.ras.pop();
+                self.record(pc, Kind::Return, true, predicted == Some(target))
+            }
+            Some(_) => {
+                let predicted = self.targets.insert(pc, target);
+                self.record(pc, Kind::Indirect, true, predicted == Some(target))
+            }
+        };
+        if is_link(rd) && self.ras_size > 0 {
+            if self.ras.len() == self.ras_size {
+                self.ras.remove(0);
+            }
+            self.ras.push(pc.wrapping_add(4));
+        }
+        correct
+    }
+
+    pub fn report(&self) {
+        let mut total = BranchStats::default();
+        let mut branches = BranchStats::default();
+        for &(kind, ref s) in self.stats.values() {
+            total.executed += s.executed;
+            total.mispredicted += s.mispredicted;
+            if kind == Kind::Branch {
+                branches.executed += s.executed;
+                branches.mispredicted += s.mispredicted;
+            }
+        }
+        eprintln!("branch predictor: {:?}, {} table bits, {} RAS entries",
+                  self.model, self.bits, self.ras_size);
+        eprintln!("  conditional: {} executed, {} mispredicted ({:.2}%)",
+                  branches.executed, branches.mispredicted, rate(&branches));
+        eprintln!("  all:         {} executed, {} mispredicted ({:.2}%)",
+                  total.executed, total.mispredicted, rate(&total));
+        eprintln!("  {:>10}  {:<8}  {:>10}  {:>10}  {:>10}  {:>7}",
+                  "pc", "type", "executed", "taken", "mispred", "rate");
+        for (pc, &(kind, ref s)) in &self.stats {
+            eprintln!("  {:#010x}  {:<8}  {:>10}  {:>10}  {:>10}  {:>6.2}%",
+                      pc, format!("{:?}", kind), s.executed, s.taken, s.mispredicted, rate(s));
+        }
+    }
+}
+
+fn rate(s: &BranchStats) -> f64 {
+    if s.executed == 0 {
+        0.0
+    } else {
+        s.mispredicted as f64 * 100.0 / s.executed as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn static_predictors() {
+        let mut p = Predictor::parse("nt");
+        assert!(p.branch(0x100, 0x80, false));
+        assert!(!p.branch(0x100, 0x80, true));
+
+        let mut p = Predictor::parse("btfn");
+        assert!(p.branch(0x100, 0x80, true));
+        assert!(!p.branch(0x100, 0x80, false));
+        assert!(p.branch(0x100, 0x180, false));
+        assert!(!p.branch(0x100, 0x180, true));
+    }
+
+    #[test]
+    fn bimodal_counters_saturate() {
+        let mut p = Predictor::parse("bimodal,bits=4");
+        // the counters start weakly not-taken
+        assert!(!p.branch(0x100, 0x80, true));
+        assert!(p.branch(0x100, 0x80, true));
+        assert!(p.branch(0x100, 0x80, true));
+        // one not-taken only takes a saturated counter down to weakly taken
+        assert!(!p.branch(0x100, 0x80, false));
+        assert!(p.branch(0x100, 0x80, true));
+        // pcs that share an entry alias
+        assert!(p.branch(0x100 + (16 << 2), 0x80, true));
+        let stats = &p.stats[&0x100].1;
+        assert_eq!((stats.executed, stats.taken, stats.mispredicted), (5, 4, 2));
+    }
+
+    #[test]
+    fn gshare_learns_alternating_branch() {
+        let mut p = Predictor::parse("gshare,bits=8");
+        let mut bimodal = Predictor::parse("bimodal,bits=8");
+        let (mut wrong, mut bimodal_wrong) = (0, 0);
+        for i in 0..100 {
+            let taken = i % 2 == 0;
+            let (correct, bimodal_correct) = (p.branch(0x40, 0x20, taken),
+                                              bimodal.branch(0x40, 0x20, taken));
+            if i >= 50 {
+                wrong += !correct as u32;
+                bimodal_wrong += !bimodal_correct as u32;
+            }
+        }
+        assert_eq!(wrong, 0);
+        assert!(bimodal_wrong >= 25);
+    }
+
+    #[test]
+    fn return_stack_and_indirect_targets() {
+        let mut p = Predictor::parse("nt,ras=2");
+        assert!(p.jump(0x10, 0x100, 1, None));
+        // an indirect call pushes as well, but its target is new
+        assert!(!p.jump(0x100, 0x200, 1, Some(6)));
+        assert!(p.jump(0x200, 0x104, 0, Some(1)));
+        assert!(p.jump(0x104, 0x14, 0, Some(1)));
+        // the stack is empty now
+        assert!(!p.jump(0x104, 0x14, 0, Some(1)));
+
+        // three calls deep with two entries: the oldest return is lost
+        p.jump(0x10, 0x100, 1, None);
+        p.jump(0x100, 0x200, 1, None);
+        p.jump(0x200, 0x300, 1, None);
+        assert!(p.jump(0x300, 0x204, 0, Some(1)));
+        assert!(p.jump(0x204, 0x104, 0, Some(1)));
+        assert!(!p.jump(0x104, 0x14, 0, Some(1)));
+
+        // other jalrs predict the target they took last time
+        assert!(!p.jump(0x50, 0x400, 0, Some(6)));
+        assert!(p.jump(0x50, 0x400, 0, Some(6)));
+        assert!(!p.jump(0x50, 0x500, 0, Some(6)));
+    }
+
+    #[test]
+    fn coroutine_switch_pops_then_pushes() {
+        let mut p = Predictor::parse("nt,ras=4");
+        p.jump(0x10, 0x100, 1, None);
+        // jalr x5, 0(x1) returns to 0x14 and leaves 0x104 for the other side
+        assert!(p.jump(0x100, 0x14, 5, Some(1)));
+        assert_eq!(p.ras, vec![0x104]);
+        // and jalr x1, 0(x5) switches back
+        assert!(p.jump(0x14, 0x104, 1, Some(5)));
+        assert_eq!(p.ras, vec![0x18]);
+        // the same link register on both sides is only a call
+        p.jump(0x104, 0x200, 1, Some(1));
+        assert_eq!(p.ras, vec![0x18, 0x108]);
+        // a link register popped into a non-link rd is still a return
+        assert!(p.jump(0x200, 0x108, 6, Some(1)));
+        assert_eq!(p.ras, vec![0x18]);
+    }
+}
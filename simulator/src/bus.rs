@@ -0,0 +1,573 @@
+// The system bus shared by all harts.
+//
+// Memory map:
+//   0x00000000  data RAM, 4 KiB unless configured otherwise; an ELF program
+//               moves it to the page its lowest segment is loaded at
+//   0x02000000  CLINT with the usual SiFive layout: msip (4 bytes per hart)
+//               at +0x0, mtimecmp (8 bytes per hart) at +0x4000 and mtime
+//               at +0xbff8
+//   0x10000000  framebuffer control registers, if there is a framebuffer
+//   0x10001000  block device registers, if there is a disk, see disk.rs
+//   0x20000000  framebuffer pixels, see framebuffer.rs
+//
+// Instruction memory is not on the bus; every hart fetches from its own
+// copy of the program image.
+//
+// mtime advances by one for every N instructions retired by the machine,
+// N being the number of harts, so it keeps pace with a single hart's
+// instruction stream and does not depend on the scheduling.
+//
+// Test suites that follow the riscv-tests convention end a run by storing
+// to the word at their `tohost` symbol: 1 for success, (n << 1) | 1 if test
+// n failed.  No other host requests are supported: any other nonzero value
+// ends the run with Stop::HostRequest, and nothing is ever written to
+// `fromhost`.
+
+use std::collections::HashMap;
+use std::mem;
+
+use disk::{Disk, COMMAND_READ, COMMAND_WRITE, DISK_BASE, DISK_COMMAND, DISK_REGISTERS};
+use disk::{STATUS_BAD_ADDRESS, STATUS_BAD_COMMAND, STATUS_BAD_SECTOR, STATUS_OK};
+use framebuffer::{Framebuffer, FB_BASE, FB_MEMORY, FB_REGISTERS};
+
+// default size of the data RAM in bytes
+pub const RAM_SIZE: u32 = 4096;
+pub const CLINT_BASE: u32 = 0x0200_0000;
+const CLINT_SIZE: u32 = 0x1_0000;
+const MTIMECMP: u32 = 0x4000;
+const MTIME: u32 = 0xbff8;
+
+#[derive(Clone)]
+pub struct Clint {
+    pub msip: Vec<bool>,
+    pub mtimecmp: Vec<u64>,
+    pub mtime: u64,
+}
+
+#[derive(Clone)]
+pub struct Bus {
+    pub(crate) ram: Vec<u32>,
+    pub(crate) ram_base: u32,
+    pub(crate) clint: Clint,
+    // address and size of the word or doubleword reserved by each hart's
+    // last LR, cleared by any store to it
+    pub(crate) reservations: Vec<Option<(u32, u32)>>,
+    // instructions retired since mtime last advanced
+    ticks: u64,
+    // address of the tohost word, and the exit code once it was written
+    pub(crate) tohost: Option<u32>,
+    pub(crate) host_exit: Option<u32>,
+    // a request written to tohost that is not an exit code
+    pub(crate) bad_request: Option<u32>,
+    // bytes at the start of RAM that hold the loaded program
+    pub(crate) preloaded: u32,
+    // a bit per byte of RAM, set once it is written; only kept for the
+    // sanitizer
+    pub(crate) written: Option<Vec<u64>>,
+    pub(crate) framebuffer: Option<Framebuffer>,
+    pub(crate) disk: Option<Disk>,
+    // (address, word) of the RAM words the last disk read wrote, for the
+    // hart that started it to copy into its program image
+    pub(crate) loaded: Vec<(u32, u32)>,
+    // (address, old word, new word) of the memory words disk reads write,
+    // kept while a hart records history
+    pub(crate) journal: Option<Vec<(u32, u32, u32)>>,
+    // framebuffer pixels and disk sectors as they were at the last history
+    // snapshot, kept while a hart records history
+    pub(crate) payloads: Option<Payloads>,
+}
+
+// The state of the CLINT and the devices that a store can change besides
+// memory, saved before it so that reverse execution can put it back without
+// running the store again.
+#[derive(Debug, Clone)]
+pub struct Devices {
+    msip: Vec<bool>,
+    mtimecmp: Vec<u64>,
+    host_exit: Option<u32>,
+    bad_request: Option<u32>,
+    frames: Option<u32>,
+    // sector, address, count and status
+    disk: Option<[u32; 4]>,
+    // where in the image the sectors of a disk write start, and their old
+    // contents
+    sectors: Option<(usize, Vec<u8>)>,
+}
+
+// The old contents of the framebuffer pixels and disk sectors changed since
+// a history snapshot, in chunks of CHUNK bytes, so that snapshots can leave
+// them out.
+#[derive(Clone, Default)]
+pub(crate) struct Payloads {
+    pixels: HashMap<usize, Vec<u8>>,
+    sectors: HashMap<usize, Vec<u8>>,
+}
+
+const CHUNK: usize = 512;
+
+// Save the chunks of data that bytes start..end fall in, unless they have
+// been saved already.
+fn save_chunks(saved: &mut HashMap<usize, Vec<u8>>, data: &[u8], start: usize, end: usize) {
+    for chunk in start / CHUNK..end.div_ceil(CHUNK) {
+        saved.entry(chunk)
+             .or_insert_with(|| data[chunk * CHUNK..data.len().min((chunk + 1) * CHUNK)].to_vec());
+    }
+}
+
+fn write_pixels(fb: &mut Framebuffer, payloads: &mut Option<Payloads>, offset: u32, value: u32,
+                width: u32) {
+    if let Some(ref mut payloads) = *payloads {
+        save_chunks(&mut payloads.pixels, &fb.pixels, offset as usize, (offset + width) as usize);
+    }
+    fb.write_pixels(offset, value, width);
+}
+
+impl Bus {
+    // ram_size is in bytes and must be a multiple of 4.
+    pub fn new(harts: usize, ram_size: u32) -> Bus {
+        Bus {
+            ram: vec![0; (ram_size / 4) as usize],
+            ram_base: 0,
+            clint: Clint {
+                msip: vec![false; harts],
+                // no timer interrupt until software programs one
+                mtimecmp: vec![u64::MAX; harts],
+                mtime: 0,
+            },
+            reservations: vec![None; harts],
+            ticks: 0,
+            tohost: None,
+            host_exit: None,
+            bad_request: None,
+            preloaded: 0,
+            written: None,
+            framebuffer: None,
+            disk: None,
+            loaded: Vec::new(),
+            journal: None,
+            payloads: None,
+        }
+    }
+
+    pub fn harts(&self) -> usize {
+        self.reservations.len()
+    }
+
+    pub fn ram_size(&self) -> u32 {
+        4 * self.ram.len() as u32
+    }
+
+    // Called once for every retired instruction.
+    #[inline]
+    pub fn tick(&mut self) {
+        self.ticks += 1;
+        if self.ticks == self.harts() as u64 {
+            self.ticks = 0;
+            self.clint.mtime += 1;
+        }
+    }
+
+    // The same as n calls of tick().
+    #[inline]
+    pub fn tick_n(&mut self, n: u64) {
+        let harts = self.harts() as u64;
+        if harts == 1 {
+            self.clint.mtime += n;
+            return;
+        }
+        self.ticks += n;
+        self.clint.mtime += self.ticks / harts;
+        self.ticks %= harts;
+    }
+
+    // mip bits (MSIP and MTIP) the CLINT raises for a hart.
+    pub fn interrupts(&self, hart: usize) -> u32 {
+        let mut mip = 0;
+        if self.clint.msip[hart] {
+            mip |= 1 << 3;
+        }
+        if self.clint.mtime >= self.clint.mtimecmp[hart] {
+            mip |= 1 << 7;
+        }
+        mip
+    }
+
+    // Start keeping track of which bytes of RAM have been written.  The
+    // loaded program counts as written.
+    pub(crate) fn track_writes(&mut self) {
+        self.written = Some(vec![0; self.ram.len().div_ceil(16)]);
+        self.mark_written(0, self.preloaded);
+    }
+
+    fn mark_written(&mut self, offset: u32, len: u32) {
+        if let Some(ref mut written) = self.written {
+            for byte in offset..offset + len {
+                written[(byte / 64) as usize] |= 1 << (byte % 64);
+            }
+        }
+    }
+
+    // False if some byte of the access is RAM that has never been written
+    // while writes are tracked.
+    pub(crate) fn is_written(&self, addr: u32, width: u32) -> bool {
+        let written = match self.written {
+            Some(ref written) => written,
+            None => return true,
+        };
+        if !in_range(addr, width, self.ram_base, self.ram_size()) {
+            return true;
+        }
+        let offset = addr - self.ram_base;
+        (offset..offset + width).all(|byte| written[(byte / 64) as usize] & (1 << (byte % 64)) != 0)
+    }
+
+    // Read 1, 2 or 4 bytes, little endian.  None if nothing answers there.
+    pub fn read(&self, addr: u32, width: u32) -> Option<u32> {
+        let offset = addr.wrapping_sub(self.ram_base);
+        if width == 4 && offset.is_multiple_of(4) && offset < self.ram_size() {
+            return Some(self.ram[(offset / 4) as usize]);
+        }
+        if in_range(addr, width, self.ram_base, self.ram_size()) {
+            let mut value = 0;
+            for i in 0..width {
+                let a = offset + i;
+                value |= ((self.ram[(a / 4) as usize] >> ((a % 4) * 8)) & 0xff) << (8 * i);
+            }
+            Some(value)
+        } else if in_range(addr, width, CLINT_BASE, CLINT_SIZE) {
+            self.read_clint(addr - CLINT_BASE, width)
+        } else {
+            self.read_device(addr, width)
+        }
+    }
+
+    // Read 1, 2, 4 or 8 bytes; a doubleword is read as two words.
+    pub(crate) fn read_wide(&self, addr: u32, width: u32) -> Option<u64> {
+        if width != 8 {
+            return self.read(addr, width).map(|value| value as u64);
+        }
+        let low = self.read(addr, 4)?;
+        let high = self.read(addr.wrapping_add(4), 4)?;
+        Some(low as u64 | (high as u64) << 32)
+    }
+
+    // Write the low `width` bytes of value.  None if nothing answers there.
+    pub fn write(&mut self, addr: u32, value: u32, width: u32) -> Option<()> {
+        let end = addr.saturating_add(width);
+        for r in self.reservations.iter_mut() {
+            if r.is_some_and(|(start, size)| start < end && addr < start.saturating_add(size)) {
+                *r = None;
+            }
+        }
+        if self.tohost == Some(addr) && width == 4 && value != 0 {
+            self.host_request(value);
+        }
+        let offset = addr.wrapping_sub(self.ram_base);
+        if width == 4 && offset.is_multiple_of(4) && offset < self.ram_size() {
+            self.ram[(offset / 4) as usize] = value;
+            if self.written.is_some() {
+                self.mark_written(offset, 4);
+            }
+            return Some(());
+        }
+        if in_range(addr, width, self.ram_base, self.ram_size()) {
+            self.mark_written(offset, width);
+            self.write_ram(offset, value, width);
+            Some(())
+        } else if in_range(addr, width, CLINT_BASE, CLINT_SIZE) {
+            self.write_clint(addr - CLINT_BASE, value, width)
+        } else {
+            self.write_device(addr, value, width)
+        }
+    }
+
+    fn write_ram(&mut self, offset: u32, value: u32, width: u32) {
+        for i in 0..width {
+            let a = offset + i;
+            let index = (a / 4) as usize;
+            let shift = (a % 4) * 8;
+            self.ram[index] = (self.ram[index] & !(0xff << shift))
+                            | (((value >> (8 * i)) & 0xff) << shift);
+        }
+    }
+
+    // Put bytes back into RAM or the framebuffer pixels without any of the
+    // effects of a store: reservations, tohost and the sanitizer's record of
+    // written bytes are left alone.  None anywhere else.
+    pub(crate) fn poke(&mut self, addr: u32, value: u32, width: u32) -> Option<()> {
+        if in_range(addr, width, self.ram_base, self.ram_size()) {
+            let offset = addr - self.ram_base;
+            self.write_ram(offset, value, width);
+            return Some(());
+        }
+        match self.framebuffer {
+            Some(ref mut fb) if in_range(addr, width, FB_MEMORY, fb.size()) => {
+                write_pixels(fb, &mut self.payloads, addr - FB_MEMORY, value, width);
+                Some(())
+            }
+            _ => None,
+        }
+    }
+
+    // True if a store there does more than change memory: tohost, the CLINT
+    // and the device registers.
+    pub(crate) fn has_effects(&self, addr: u32, width: u32) -> bool {
+        if self.tohost == Some(addr) {
+            return true;
+        }
+        let pixels = self.framebuffer.as_ref()
+            .is_some_and(|fb| in_range(addr, width, FB_MEMORY, fb.size()));
+        !pixels && !in_range(addr, width, self.ram_base, self.ram_size())
+    }
+
+    // A copy for a history snapshot, without the framebuffer pixels and
+    // disk sectors, which `payloads` takes care of.
+    pub(crate) fn snapshot(&mut self) -> Bus {
+        let pixels = self.framebuffer.as_mut().map(|fb| mem::take(&mut fb.pixels));
+        let data = self.disk.as_mut().map(|disk| mem::take(&mut disk.data));
+        let copy = self.clone();
+        self.put_payloads(pixels, data);
+        copy
+    }
+
+    // Go back to a snapshot, keeping the current pixels and sectors; the
+    // caller puts back the ones changed since with undo_payloads().
+    pub(crate) fn restore_snapshot(&mut self, snapshot: &Bus) {
+        let pixels = self.framebuffer.as_mut().map(|fb| mem::take(&mut fb.pixels));
+        let data = self.disk.as_mut().map(|disk| mem::take(&mut disk.data));
+        *self = snapshot.clone();
+        self.put_payloads(pixels, data);
+        self.payloads = Some(Payloads::default());
+    }
+
+    fn put_payloads(&mut self, pixels: Option<Vec<u8>>, data: Option<Vec<u8>>) {
+        if let (Some(fb), Some(pixels)) = (self.framebuffer.as_mut(), pixels) {
+            fb.pixels = pixels;
+        }
+        if let (Some(disk), Some(data)) = (self.disk.as_mut(), data) {
+            disk.data = data;
+        }
+    }
+
+    // Put back the pixels and sectors saved in payloads.  Sectors go back to
+    // the image file as well.
+    pub(crate) fn undo_payloads(&mut self, payloads: Payloads) {
+        if let Some(ref mut fb) = self.framebuffer {
+            for (chunk, bytes) in payloads.pixels {
+                let start = chunk * CHUNK;
+                fb.pixels[start..start + bytes.len()].copy_from_slice(&bytes);
+            }
+        }
+        if let Some(ref mut disk) = self.disk {
+            for (chunk, bytes) in payloads.sectors {
+                let start = chunk * CHUNK;
+                disk.data[start..start + bytes.len()].copy_from_slice(&bytes);
+                disk.flush(start, start + bytes.len());
+            }
+        }
+    }
+
+    // The device state before storing value to addr.
+    pub(crate) fn save_devices(&self, addr: u32, value: u32, width: u32) -> Devices {
+        let is_write = addr == DISK_BASE + DISK_COMMAND && width == 4 && value == COMMAND_WRITE;
+        let sectors = match self.disk {
+            Some(ref disk) if is_write => {
+                disk.range().map(|(start, end)| (start, disk.data[start..end].to_vec()))
+            }
+            _ => None,
+        };
+        Devices {
+            msip: self.clint.msip.clone(),
+            mtimecmp: self.clint.mtimecmp.clone(),
+            host_exit: self.host_exit,
+            bad_request: self.bad_request,
+            frames: self.framebuffer.as_ref().map(|fb| fb.frames),
+            disk: self.disk.as_ref().map(Disk::registers),
+            sectors,
+        }
+    }
+
+    // Undo a store to a device.  Sectors it wrote go back to the image file.
+    pub(crate) fn restore_devices(&mut self, devices: &Devices) {
+        self.clint.msip.clone_from(&devices.msip);
+        self.clint.mtimecmp.clone_from(&devices.mtimecmp);
+        self.host_exit = devices.host_exit;
+        self.bad_request = devices.bad_request;
+        if let (Some(fb), Some(frames)) = (self.framebuffer.as_mut(), devices.frames) {
+            fb.frames = frames;
+        }
+        if let (Some(disk), Some(registers)) = (self.disk.as_mut(), devices.disk) {
+            disk.set_registers(registers);
+            if let Some((start, ref bytes)) = devices.sectors {
+                if let Some(ref mut payloads) = self.payloads {
+                    save_chunks(&mut payloads.sectors, &disk.data, start, start + bytes.len());
+                }
+                disk.data[start..start + bytes.len()].copy_from_slice(bytes);
+                disk.flush(start, start + bytes.len());
+            }
+        }
+    }
+
+    // The optional devices.
+    fn read_device(&self, addr: u32, width: u32) -> Option<u32> {
+        if let Some(ref fb) = self.framebuffer {
+            if in_range(addr, width, FB_MEMORY, fb.size()) {
+                return Some(fb.read_pixels(addr - FB_MEMORY, width));
+            } else if in_range(addr, width, FB_BASE, FB_REGISTERS) {
+                return fb.read_register(addr - FB_BASE, width);
+            }
+        }
+        if let Some(ref disk) = self.disk {
+            if in_range(addr, width, DISK_BASE, DISK_REGISTERS) {
+                return disk.read_register(addr - DISK_BASE, width);
+            }
+        }
+        None
+    }
+
+    fn write_device(&mut self, addr: u32, value: u32, width: u32) -> Option<()> {
+        if let Some(ref mut fb) = self.framebuffer {
+            if in_range(addr, width, FB_MEMORY, fb.size()) {
+                write_pixels(fb, &mut self.payloads, addr - FB_MEMORY, value, width);
+                return Some(());
+            } else if in_range(addr, width, FB_BASE, FB_REGISTERS) {
+                return fb.write_register(addr - FB_BASE, value, width);
+            }
+        }
+        if self.disk.is_some() && in_range(addr, width, DISK_BASE, DISK_REGISTERS) {
+            if addr - DISK_BASE == DISK_COMMAND && width == 4 {
+                self.disk_command(value);
+                return Some(());
+            }
+            return self.disk.as_mut().unwrap().write_register(addr - DISK_BASE, value, width);
+        }
+        None
+    }
+
+    // Run a command written to the disk's command register.
+    fn disk_command(&mut self, command: u32) {
+        let mut disk = self.disk.take().unwrap();
+        disk.status = self.transfer(&mut disk, command);
+        self.disk = Some(disk);
+    }
+
+    // Move the sectors of a read or write command by DMA, a word at a time.
+    fn transfer(&mut self, disk: &mut Disk, command: u32) -> u32 {
+        if command != COMMAND_READ && command != COMMAND_WRITE {
+            return STATUS_BAD_COMMAND;
+        }
+        let (start, end) = match disk.range() {
+            Some(range) => range,
+            None => return STATUS_BAD_SECTOR,
+        };
+        if !disk.address.is_multiple_of(4) {
+            return STATUS_BAD_ADDRESS;
+        }
+        if command == COMMAND_WRITE {
+            if let Some(ref mut payloads) = self.payloads {
+                save_chunks(&mut payloads.sectors, &disk.data, start, end);
+            }
+        }
+        let mut status = STATUS_OK;
+        let mut done = start;
+        while done < end {
+            let addr = match disk.address.checked_add((done - start) as u32) {
+                Some(addr) => addr,
+                None => {
+                    status = STATUS_BAD_ADDRESS;
+                    break;
+                }
+            };
+            let bytes = &mut disk.data[done..done + 4];
+            if command == COMMAND_READ {
+                let word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+                if self.journal.is_some() && !self.has_effects(addr, 4) {
+                    let old = self.read(addr, 4).unwrap();
+                    if let Some(ref mut journal) = self.journal {
+                        journal.push((addr, old, word));
+                    }
+                }
+                if self.write(addr, word, 4).is_none() {
+                    status = STATUS_BAD_ADDRESS;
+                    break;
+                }
+                if in_range(addr, 4, self.ram_base, self.ram_size()) {
+                    self.loaded.push((addr, word));
+                }
+            } else {
+                match self.read(addr, 4) {
+                    Some(word) => bytes.copy_from_slice(&word.to_le_bytes()),
+                    None => {
+                        status = STATUS_BAD_ADDRESS;
+                        break;
+                    }
+                }
+            }
+            done += 4;
+        }
+        if command == COMMAND_WRITE && done > start {
+            disk.flush(start, done);
+        }
+        status
+    }
+
+    // A request from the program to the host.
+    fn host_request(&mut self, value: u32) {
+        if value & 1 == 0 {
+            self.bad_request = Some(value);
+        } else {
+            self.host_exit = Some(value >> 1);
+        }
+    }
+
+    // The CLINT only takes aligned word accesses.
+    fn read_clint(&self, offset: u32, width: u32) -> Option<u32> {
+        if width != 4 || !offset.is_multiple_of(4) {
+            return None;
+        }
+        let harts = self.harts() as u32;
+        if offset < 4 * harts {
+            Some(self.clint.msip[(offset / 4) as usize] as u32)
+        } else if offset >= MTIMECMP && offset < MTIMECMP + 8 * harts {
+            let value = self.clint.mtimecmp[((offset - MTIMECMP) / 8) as usize];
+            Some(half(value, offset))
+        } else if offset == MTIME || offset == MTIME + 4 {
+            Some(half(self.clint.mtime, offset))
+        } else {
+            Some(0)
+        }
+    }
+
+    fn write_clint(&mut self, offset: u32, value: u32, width: u32) -> Option<()> {
+        if width != 4 || !offset.is_multiple_of(4) {
+            return None;
+        }
+        let harts = self.harts() as u32;
+        if offset < 4 * harts {
+            self.clint.msip[(offset / 4) as usize] = value & 1 != 0;
+        } else if offset >= MTIMECMP && offset < MTIMECMP + 8 * harts {
+            let cmp = &mut self.clint.mtimecmp[((offset - MTIMECMP) / 8) as usize];
+            *cmp = set_half(*cmp, offset, value);
+        } else if offset == MTIME || offset == MTIME + 4 {
+            self.clint.mtime = set_half(self.clint.mtime, offset, value);
+        }
+        Some(())
+    }
+}
+
+fn in_range(addr: u32, width: u32, base: u32, size: u32) -> bool {
+    addr >= base && addr - base < size && size - (addr - base) >= width
+}
+
+// The low or high word of a 64-bit register, by the offset's bit 2.
+fn half(value: u64, offset: u32) -> u32 {
+    if offset & 4 == 0 { value as u32 } else { (value >> 32) as u32 }
+}
+
+fn set_half(old: u64, offset: u32, value: u32) -> u64 {
+    if offset & 4 == 0 {
+        (old & !0xffff_ffff) | value as u64
+    } else {
+        (old & 0xffff_ffff) | ((value as u64) << 32)
+    }
+}
@@ -0,0 +1,332 @@
+// Set-associative cache model.
+// Only tags are kept; the data itself always lives in the backing memory,
+// so the cache affects statistics and timing but never the program result.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Replacement {
+    Lru,
+    Fifo,
+    Random,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WritePolicy {
+    WriteBack,
+    WriteThrough,
+}
+
+#[derive(Debug, Clone)]
+pub struct CacheConfig {
+    pub size: u32,
+    pub assoc: u32,
+    pub line: u32,
+    pub replacement: Replacement,
+    pub write_policy: WritePolicy,
+    pub write_allocate: bool,
+    pub miss_latency: u64,
+}
+
+impl CacheConfig {
+    // Parse a spec such as "size=4096,assoc=2,line=32,repl=lru,write=wb,alloc=1,latency=10".
+    // Keys that are left out keep their default values.
+    pub fn parse(spec: &str) -> Result<CacheConfig, String> {
+        let mut config = CacheConfig {
+            size: 4096,
+            assoc: 1,
+            line: 32,
+            replacement: Replacement::Lru,
+            write_policy: WritePolicy::WriteBack,
+            write_allocate: true,
+            miss_latency: 0,
+        };
+        for item in spec.split(',').filter(|s| !s.is_empty()) {
+            let mut kv = item.splitn(2, '=');
+            let key = kv.next().unwrap();
+            if !KEYS.contains(&key) {
+                return Err(format!("Unknown cache option: {}", key));
+            }
+            let value = match kv.next() {
+                Some(v) => v,
+                None => return Err(format!("Cache option {} needs a value", key)),
+            };
+            match key {
+                "size" => config.size = parse_number(key, value)? as u32,
+                "assoc" => config.assoc = parse_number(key, value)? as u32,
+                "line" => config.line = parse_number(key, value)? as u32,
+                "latency" => config.miss_latency = parse_number(key, value)?,
+                "repl" => {
+                    config.replacement = match value {
+                        "lru" => Replacement::Lru,
+                        "fifo" => Replacement::Fifo,
+                        "random" => Replacement::Random,
+                        _ => return Err(format!("Unknown replacement policy: {}", value)),
+                    }
+                }
+                "write" => {
+                    config.write_policy = match value {
+                        "wb" => WritePolicy::WriteBack,
+                        "wt" => WritePolicy::WriteThrough,
+                        _ => return Err(format!("Unknown write policy: {}", value)),
+                    }
+                }
+                // alloc, the only key left
+                _ => {
+                    config.write_allocate = match value {
+                        "1" | "yes" => true,
+                        "0" | "no" => false,
+                        _ => return Err(format!("alloc must be 1 or 0: {}", value)),
+                    }
+                }
+            }
+        }
+        config.check()?;
+        Ok(config)
+    }
+
+    fn check(&self) -> Result<(), String> {
+        if !self.line.is_power_of_two() || self.line < 4 {
+            return Err("Cache line size must be a power of two and at least 4".to_string());
+        }
+        if self.assoc == 0 || !self.size.is_multiple_of(self.line * self.assoc) {
+            return Err("Cache size must be a multiple of line size * associativity".to_string());
+        }
+        if !(self.size / (self.line * self.assoc)).is_power_of_two() {
+            return Err("Number of cache sets must be a power of two".to_string());
+        }
+        Ok(())
+    }
+
+    fn sets(&self) -> u32 {
+        self.size / (self.line * self.assoc)
+    }
+}
+
+const KEYS: [&str; 7] = ["size", "assoc", "line", "latency", "repl", "write", "alloc"];
+
+fn parse_number(key: &str, value: &str) -> Result<u64, String> {
+    match value.parse::<u64>() {
+        Ok(n) => Ok(n),
+        Err(_) => Err(format!("Cache option {} must be a number: {}", key, value)),
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Line {
+    valid: bool,
+    dirty: bool,
+    tag: u32,
+    // time of the last access (LRU) or of the fill (FIFO)
+    stamp: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CacheStats {
+    pub reads: u64,
+    pub writes: u64,
+    pub read_misses: u64,
+    pub write_misses: u64,
+    pub evictions: u64,
+    pub writebacks: u64,
+    pub mem_writes: u64,
+}
+
+pub struct Cache {
+    name: String,
+    config: CacheConfig,
+    sets: Vec<Vec<Line>>,
+    time: u64,
+    seed: u32,
+    pub stats: CacheStats,
+}
+
+impl Cache {
+    pub fn new(name: &str, config: CacheConfig) -> Cache {
+        let sets = vec![vec![Line::default(); config.assoc as usize]; config.sets() as usize];
+        Cache {
+            name: name.to_string(),
+            config,
+            sets,
+            time: 0,
+            seed: 0x2545f491,
+            stats: CacheStats::default(),
+        }
+    }
+
+    // Access the cache and return the number of stall cycles it costs.
+    pub fn access(&mut self, addr: u32, is_write: bool) -> u64 {
+        self.time += 1;
+        let block = addr / self.config.line;
+        let index = (block % self.config.sets()) as usize;
+        let tag = block / self.config.sets();
+
+        if is_write {
+            self.stats.writes += 1;
+            if self.config.write_policy == WritePolicy::WriteThrough {
+                self.stats.mem_writes += 1;
+            }
+        } else {
+            self.stats.reads += 1;
+        }
+
+        let time = self.time;
+        let write_back = self.config.write_policy == WritePolicy::WriteBack;
+        if let Some(line) = self.sets[index].iter_mut().find(|l| l.valid && l.tag == tag) {
+            if self.config.replacement == Replacement::Lru {
+                line.stamp = time;
+            }
+            if is_write && write_back {
+                line.dirty = true;
+            }
+            return 0;
+        }
+
+        if is_write {
+            self.stats.write_misses += 1;
+            if !self.config.write_allocate {
+                if write_back {
+                    // the store goes around the cache straight to memory
+                    self.stats.mem_writes += 1;
+                }
+                return self.config.miss_latency;
+            }
+        } else {
+            self.stats.read_misses += 1;
+        }
+
+        let way = self.victim(index);
+        let victim = self.sets[index][way];
+        if victim.valid {
+            self.stats.evictions += 1;
+            if victim.dirty {
+                self.stats.writebacks += 1;
+                self.stats.mem_writes += 1;
+            }
+        }
+        self.sets[index][way] = Line {
+            valid: true,
+            dirty: is_write && write_back,
+            tag,
+            stamp: time,
+        };
+        self.config.miss_latency
+    }
+
+    fn victim(&mut self, index: usize) -> usize {
+        if let Some(way) = self.sets[index].iter().position(|l| !l.valid) {
+            return way;
+        }
+        match self.config.replacement {
+            Replacement::Lru | Replacement::Fifo => {
+                let set = &self.sets[index];
+                (0..set.len()).min_by_key(|&w| set[w].stamp).unwrap()
+            }
+            Replacement::Random => {
+                // xorshift32, seeded with a constant so runs are reproducible
+                self.seed ^= self.seed << 13;
+                self.seed ^= self.seed >> 17;
+                self.seed ^= self.seed << 5;
+                (self.seed % self.config.assoc) as usize
+            }
+        }
+    }
+
+    pub fn report(&self) {
+        let s = &self.stats;
+        let accesses = s.reads + s.writes;
+        let misses = s.read_misses + s.write_misses;
+        let rate = if accesses == 0 { 0.0 } else { misses as f64 * 100.0 / accesses as f64 };
+        eprintln!("{}: {} bytes, {}-way, {}-byte lines, {:?}, {:?}, {}",
+                  self.name, self.config.size, self.config.assoc, self.config.line,
+                  self.config.replacement, self.config.write_policy,
+                  if self.config.write_allocate { "write-allocate" } else { "no-write-allocate" });
+        eprintln!("  accesses:   {} ({} reads, {} writes)", accesses, s.reads, s.writes);
+        eprintln!("  hits:       {}", accesses - misses);
+        eprintln!("  misses:     {} ({} reads, {} writes), miss rate {:.2}%",
+                  misses, s.read_misses, s.write_misses, rate);
+        eprintln!("  evictions:  {}", s.evictions);
+        eprintln!("  writebacks: {}", s.writebacks);
+        eprintln!("  mem writes: {}", s.mem_writes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // One set of two 16-byte lines, so that A, B and C all compete for it.
+    fn cache(spec: &str) -> Cache {
+        let spec = format!("size=32,assoc=2,line=16,latency=10,{}", spec);
+        Cache::new("test", CacheConfig::parse(&spec).unwrap())
+    }
+
+    const A: u32 = 0x000;
+    const B: u32 = 0x100;
+    const C: u32 = 0x200;
+
+    #[test]
+    fn lru_evicts_least_recently_used() {
+        let mut c = cache("repl=lru");
+        assert_eq!(c.access(A, false), 10);
+        assert_eq!(c.access(B, false), 10);
+        assert_eq!(c.access(A + 4, false), 0);
+        assert_eq!(c.access(C, false), 10);
+        assert_eq!(c.access(A, false), 0);
+        assert_eq!(c.access(B, false), 10);
+        assert_eq!(c.stats.evictions, 2);
+    }
+
+    #[test]
+    fn fifo_evicts_oldest_fill() {
+        let mut c = cache("repl=fifo");
+        c.access(A, false);
+        c.access(B, false);
+        assert_eq!(c.access(A, false), 0);
+        assert_eq!(c.access(C, false), 10);
+        assert_eq!(c.access(B, false), 0);
+        assert_eq!(c.access(A, false), 10);
+    }
+
+    #[test]
+    fn random_is_reproducible() {
+        let (mut c1, mut c2) = (cache("repl=random"), cache("repl=random"));
+        for i in 0..100 {
+            let addr = (i * 7 % 5) * 0x100;
+            assert_eq!(c1.access(addr, false), c2.access(addr, false));
+        }
+        assert_eq!(c1.stats.read_misses, c2.stats.read_misses);
+        assert!(c1.stats.read_misses < 100);
+    }
+
+    #[test]
+    fn write_policies() {
+        let mut c = cache("write=wb");
+        c.access(A, true);
+        c.access(B, false);
+        c.access(C, false);
+        assert_eq!((c.stats.writebacks, c.stats.mem_writes), (1, 1));
+
+        let mut c = cache("write=wt");
+        c.access(A, true);
+        c.access(A, true);
+        c.access(B, false);
+        c.access(C, false);
+        assert_eq!((c.stats.writebacks, c.stats.mem_writes), (0, 2));
+
+        let mut c = cache("alloc=0");
+        assert_eq!(c.access(A, true), 10);
+        assert_eq!(c.access(A, false), 10);
+        assert_eq!((c.stats.write_misses, c.stats.read_misses, c.stats.mem_writes), (1, 1, 1));
+    }
+
+    #[test]
+    fn malformed_specs() {
+        let error = |spec| CacheConfig::parse(spec).unwrap_err();
+        assert_eq!(error("garbage"), "Unknown cache option: garbage");
+        assert_eq!(error("size"), "Cache option size needs a value");
+        assert_eq!(error("size=big"), "Cache option size must be a number: big");
+        assert_eq!(error("repl=plru"), "Unknown replacement policy: plru");
+        assert!(error("line=24").starts_with("Cache line size"));
+        assert!(error("size=96,line=32").starts_with("Number of cache sets"));
+        assert_eq!(CacheConfig::parse("").unwrap().sets(), 128);
+    }
+}
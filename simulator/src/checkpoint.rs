@@ -0,0 +1,292 @@
+// Checkpoint files.
+//
+// A checkpoint starts with the magic "KSIMCKPT" and a u32 format version,
+// followed by tagged sections: a 4-byte tag, a u32 payload length and the
+// payload.  All integers are little endian.  The file ends with an "END "
+// section.  Sections:
+//   "CPU " pc, exit flag (u8), the 32 integer registers (u64 each), the
+//          privilege mode (u8) and XLEN (u8)
+//   "CNT " retired instructions and cycles (u64 each)
+//   "CSR " CSR number (u32) and value (u64) pairs
+//   "CLNT" mtime, then msip (u32) and mtimecmp (u64) of each hart
+//   "BASE" physical addresses of the first IMEM and DMEM words, and 1 if
+//          IMEM is a copy of the program in DMEM, as for ELF programs
+//   "HOST" address of the tohost word, only if the program has one
+//   "IMEM" instruction memory words
+//   "DMEM" data memory words
+//   "FB  " framebuffer width, height, bytes per pixel and frames dumped, the
+//          length of the frame file prefix and the prefix, then the pixels;
+//          only if there is a framebuffer
+//   "DISK" disk sector, address, count and status registers, a hash of the
+//          image contents (u64), then the path of the image file; only if
+//          there is a disk
+// Version 1 files have no CSR and CLNT sections; those start from reset.
+// In version 2 the CPU section has no privilege mode (M-mode is assumed),
+// and the CSR section holds just mstatus, mie, mtvec, mscratch, mepc,
+// mcause and mtval in that order.  Before version 4 there is no BASE
+// section and both memories start at address 0.  Before version 5 the
+// registers are u32 and there is no XLEN; those are RV32 harts.  Before
+// version 6 IMEM is always separate from DMEM.  Before version 7 there are
+// no FB and DISK sections, so devices start out blank.  Before version 8 the
+// CSR values are u32.  Before version 9 the DISK section has no hash.
+//
+// Only architectural state of a single hart and its devices is saved.
+// Caches, branch predictors and the other models start cold after a restore.
+// The disk's sectors are not saved: the program's writes are already in the
+// image file, which is opened again on restore.  The restore stops if the
+// image changed after the save, as the run would not go on the same.  --framebuffer and --disk
+// given with --restore replace the restored devices but keep their state,
+// see Machine::set_framebuffer() and Machine::set_disk().
+
+use std::io::prelude::*;
+use std::fs::File;
+
+use csr;
+use disk::Disk;
+use framebuffer::{Format, Framebuffer};
+use State;
+
+const MAGIC: &[u8; 8] = b"KSIMCKPT";
+const VERSION: u32 = 9;
+
+fn put_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u64(buf: &mut Vec<u8>, value: u64) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_section(buf: &mut Vec<u8>, tag: &[u8; 4], payload: &[u8]) {
+    buf.extend_from_slice(tag);
+    put_u32(buf, payload.len() as u32);
+    buf.extend_from_slice(payload);
+}
+
+fn words(values: &[u32]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(values.len() * 4);
+    for &v in values {
+        put_u32(&mut buf, v);
+    }
+    buf
+}
+
+pub fn save(state: &State, path: &str) {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(MAGIC);
+    put_u32(&mut buf, VERSION);
+
+    let mut cpu = Vec::new();
+    put_u32(&mut cpu, state.address);
+    cpu.push(state.is_exit as u8);
+    for &value in state.register.iter() {
+        put_u64(&mut cpu, value);
+    }
+    cpu.push(state.privilege as u8);
+    cpu.push(state.xlen as u8);
+    put_section(&mut buf, b"CPU ", &cpu);
+
+    let mut counters = Vec::new();
+    put_u64(&mut counters, state.instret);
+    put_u64(&mut counters, state.cycle);
+    put_section(&mut buf, b"CNT ", &counters);
+
+    let mut csrs = Vec::new();
+    let mut plain = state.csr.clone();
+    for number in csr::plain() {
+        put_u32(&mut csrs, number);
+        put_u64(&mut csrs, plain.get(number).unwrap());
+    }
+    put_section(&mut buf, b"CSR ", &csrs);
+
+    let bus = state.bus.borrow();
+    let mut clint = Vec::new();
+    put_u64(&mut clint, bus.clint.mtime);
+    for (&msip, &mtimecmp) in bus.clint.msip.iter().zip(&bus.clint.mtimecmp) {
+        put_u32(&mut clint, msip as u32);
+        put_u64(&mut clint, mtimecmp);
+    }
+    put_section(&mut buf, b"CLNT", &clint);
+
+    put_section(&mut buf, b"BASE",
+                &words(&[state.image_base, bus.ram_base, state.code_in_ram as u32]));
+    if let Some(tohost) = bus.tohost {
+        put_section(&mut buf, b"HOST", &words(&[tohost]));
+    }
+    put_section(&mut buf, b"IMEM", &words(&state.imem));
+    put_section(&mut buf, b"DMEM", &words(&bus.ram));
+    if let Some(ref fb) = bus.framebuffer {
+        let mut payload = words(&[fb.width, fb.height, fb.format.bytes(), fb.frames,
+                                  fb.prefix.len() as u32]);
+        payload.extend_from_slice(fb.prefix.as_bytes());
+        payload.extend_from_slice(&fb.pixels);
+        put_section(&mut buf, b"FB  ", &payload);
+    }
+    if let Some(ref disk) = bus.disk {
+        let mut payload = words(&disk.registers());
+        put_u64(&mut payload, disk.digest());
+        payload.extend_from_slice(disk.path.as_bytes());
+        put_section(&mut buf, b"DISK", &payload);
+    }
+    put_section(&mut buf, b"END ", &[]);
+
+    let mut f = match File::create(path) {
+        Ok(file) => file,
+        Err(err) => panic!("File open error: {:?}", err),
+    };
+    f.write_all(&buf).expect("File write error");
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> &'a [u8] {
+        if self.pos + n > self.buf.len() {
+            panic!("Checkpoint is truncated");
+        }
+        let slice = &self.buf[self.pos..self.pos + n];
+        self.pos += n;
+        slice
+    }
+    fn u8(&mut self) -> u8 {
+        self.bytes(1)[0]
+    }
+    fn u32(&mut self) -> u32 {
+        let b = self.bytes(4);
+        u32::from_le_bytes([b[0], b[1], b[2], b[3]])
+    }
+    fn u64(&mut self) -> u64 {
+        (self.u32() as u64) | ((self.u32() as u64) << 32)
+    }
+    fn string(&mut self, len: usize) -> String {
+        match String::from_utf8(self.bytes(len).to_vec()) {
+            Ok(s) => s,
+            Err(_) => panic!("Checkpoint has a malformed string"),
+        }
+    }
+    fn words(&mut self, len: usize) -> Vec<u32> {
+        if !len.is_multiple_of(4) {
+            panic!("Checkpoint section length is not a multiple of 4");
+        }
+        (0..len / 4).map(|_| self.u32()).collect()
+    }
+}
+
+pub fn restore(path: &str) -> State {
+    let mut f = match File::open(path) {
+        Ok(file) => file,
+        Err(err) => panic!("File open error: {:?}", err),
+    };
+    let mut buf = Vec::new();
+    f.read_to_end(&mut buf).expect("File read error");
+
+    let mut r = Reader { buf: &buf, pos: 0 };
+    if r.bytes(8) != MAGIC {
+        panic!("{} is not a ksim checkpoint", path);
+    }
+    let version = r.u32();
+    if version == 0 || version > VERSION {
+        panic!("Unsupported checkpoint version {} (expected {})", version, VERSION);
+    }
+
+    let mut state = State::init(Vec::new());
+    loop {
+        let tag = r.bytes(4);
+        let len = r.u32() as usize;
+        let end = r.pos + len;
+        match tag {
+            b"CPU " => {
+                state.address = r.u32();
+                state.is_exit = r.u8() != 0;
+                for i in 0..32 {
+                    state.register[i] = if version >= 5 { r.u64() } else { r.u32() as i32 as u64 };
+                }
+                state.privilege = if version >= 3 { r.u8() as u32 } else { csr::PRV_M };
+                if version >= 5 {
+                    state.set_xlen(r.u8() as u32);
+                }
+            }
+            b"CNT " => {
+                state.instret = r.u64();
+                state.cycle = r.u64();
+            }
+            b"CSR " if version == 2 => {
+                for &number in [csr::MSTATUS, csr::MIE, csr::MTVEC, csr::MSCRATCH,
+                                csr::MEPC, csr::MCAUSE, csr::MTVAL].iter() {
+                    state.csr.set(number, r.u32() as u64);
+                }
+            }
+            b"CSR " => {
+                while r.pos < end {
+                    let number = r.u32();
+                    let value = if version >= 8 { r.u64() } else { r.u32() as u64 };
+                    if !state.csr.set(number, value) {
+                        panic!("Checkpoint has unknown CSR {:#x}", number);
+                    }
+                }
+            }
+            b"CLNT" => {
+                let mut bus = state.bus.borrow_mut();
+                bus.clint.mtime = r.u64();
+                if len != 8 + 12 * bus.harts() {
+                    panic!("Checkpoint has CLINT state for a different number of harts");
+                }
+                for i in 0..bus.harts() {
+                    bus.clint.msip[i] = r.u32() != 0;
+                    bus.clint.mtimecmp[i] = r.u64();
+                }
+            }
+            b"BASE" => {
+                state.image_base = r.u32();
+                state.bus.borrow_mut().ram_base = r.u32();
+                if version >= 6 {
+                    state.code_in_ram = r.u32() != 0;
+                }
+            }
+            b"HOST" => state.bus.borrow_mut().tohost = Some(r.u32()),
+            b"IMEM" => state.set_image(r.words(len)),
+            b"DMEM" => {
+                // nothing is known about what was written before
+                let mut bus = state.bus.borrow_mut();
+                bus.ram = r.words(len);
+                bus.preloaded = len as u32;
+            }
+            b"FB  " => {
+                let (width, height) = (r.u32(), r.u32());
+                let format = match r.u32() {
+                    1 => Format::Gray8,
+                    2 => Format::Rgb565,
+                    4 => Format::Xrgb8888,
+                    bytes => panic!("Checkpoint has a framebuffer with {} bytes per pixel", bytes),
+                };
+                let frames = r.u32();
+                let prefix_len = r.u32() as usize;
+                let prefix = r.string(prefix_len);
+                let mut fb = Framebuffer::new(width, height, format, prefix);
+                fb.frames = frames;
+                let size = fb.pixels.len();
+                fb.pixels.copy_from_slice(r.bytes(size));
+                state.bus.borrow_mut().framebuffer = Some(fb);
+            }
+            b"DISK" => {
+                let registers = [r.u32(), r.u32(), r.u32(), r.u32()];
+                let digest = if version >= 9 { Some(r.u64()) } else { None };
+                let mut disk = Disk::open(&r.string(end - r.pos));
+                if digest.is_some_and(|digest| digest != disk.digest()) {
+                    panic!("Disk image {} changed after the checkpoint was saved", disk.path);
+                }
+                disk.set_registers(registers);
+                state.bus.borrow_mut().disk = Some(disk);
+            }
+            b"END " => break,
+            _ => panic!("Unknown checkpoint section {:?}", String::from_utf8_lossy(tag)),
+        }
+        if r.pos != end {
+            panic!("Checkpoint section {:?} has the wrong length", String::from_utf8_lossy(tag));
+        }
+    }
+    state
+}
@@ -0,0 +1,215 @@
+// Lockstep co-simulation against a reference commit trace.
+//
+// The trace has a line per retired instruction:
+//   <pc> <instruction> [<register>=<value> ...]
+// with numbers in hex, 0x optional, and registers as x0-x31 or ABI names;
+// # starts a comment.  Spike's --log-commits output works as well:
+//   core   0: 3 0x80000000 (0x00000297) x5  0x80000000 mem 0x80001000
+// Its privilege level, memory accesses and writes to other than integer
+// registers are skipped.
+//
+// After every instruction ksim retires, the next record must have the same
+// pc and instruction, and the registers ksim changed must be exactly the
+// ones the record writes, with the same values.  An instruction that traps
+// is only compared if the record has its pc, since simulators like Spike do
+// not log those.  The run stops at the first difference.
+
+use std::fmt;
+use std::io::prelude::*;
+use std::io::{BufReader, Lines};
+use std::fs::File;
+
+use disasm;
+use {Retired, State};
+
+// One retired instruction: pc, instruction and register writes.
+#[derive(Debug, Clone)]
+pub struct Commit {
+    pub pc: u32,
+    pub instruction: u32,
+    pub writes: Vec<(u32, u64)>,
+}
+
+/// Where ksim and the reference part ways.
+#[derive(Debug, Clone)]
+pub struct Divergence {
+    /// instret of ksim after the instruction that differs
+    pub instret: u64,
+    /// line of the reference record, None past the end of the trace
+    pub line: Option<usize>,
+    pub ksim: Commit,
+    pub reference: Option<Commit>,
+}
+
+pub struct Cosim {
+    lines: Lines<BufReader<File>>,
+    // number of the last line read
+    line: usize,
+    // the next record and its line, if it has been read already
+    peeked: Option<(usize, Commit)>,
+    // registers before the instruction that is executing
+    before: [u64; 32],
+    pub compared: u64,
+}
+
+fn parse_number(text: &str) -> Option<u64> {
+    let digits = text.trim_start_matches("0x");
+    u64::from_str_radix(digits, 16).ok()
+}
+
+// RV64 traces have 64-bit pcs, which have to be in ksim's address space.
+fn parse_word(text: &str) -> Option<u32> {
+    parse_number(text).filter(|&n| n >> 32 == 0).map(|n| n as u32)
+}
+
+// A record from a line, None if it has none.
+fn parse_line(text: &str) -> Result<Option<Commit>, String> {
+    let text = text.split('#').next().unwrap();
+    let mut tokens: Vec<&str> = text.split_whitespace().collect();
+    if tokens.is_empty() {
+        return Ok(None);
+    }
+    // Spike: "core   0: 3 0x80000000 (0x00000297) ..."
+    if tokens[0] == "core" {
+        if tokens.len() < 5 {
+            return Err("truncated Spike record".to_string());
+        }
+        tokens.drain(..3);
+    }
+    if tokens.len() < 2 {
+        return Err("expected a pc and an instruction".to_string());
+    }
+    let pc = parse_word(tokens[0]).ok_or(format!("bad pc {}", tokens[0]))?;
+    let raw = tokens[1].trim_start_matches('(').trim_end_matches(')');
+    let instruction = parse_word(raw).ok_or(format!("bad instruction {}", tokens[1]))?;
+    let mut writes = Vec::new();
+    let mut rest = tokens[2..].iter();
+    while let Some(&token) = rest.next() {
+        let (name, value) = match token.find('=') {
+            Some(i) => (&token[..i], &token[i + 1..]),
+            None => match rest.next() {
+                Some(&value) => (token, value),
+                None => return Err(format!("{} has no value", token)),
+            },
+        };
+        if name == "mem" {
+            // a store also has the value; skip it unless it names a register
+            if let Some(next) = rest.clone().next() {
+                if parse_number(next).is_some() && disasm::register_index(next).is_none() {
+                    rest.next();
+                }
+            }
+            continue;
+        }
+        let index = match disasm::register_index(name) {
+            Some(index) => index,
+            // an FP register, a CSR or something else ksim does not have
+            None => continue,
+        };
+        let value = parse_number(value).ok_or(format!("bad value {}", value))?;
+        if index != 0 {
+            writes.push((index, value));
+        }
+    }
+    Ok(Some(Commit { pc, instruction, writes }))
+}
+
+impl Cosim {
+    pub fn open(path: &str, state: &State) -> Cosim {
+        let f = match File::open(path) {
+            Ok(file) => BufReader::new(file),
+            Err(err) => panic!("File open error: {:?}", err),
+        };
+        let mut before = [0; 32];
+        for (i, value) in before.iter_mut().enumerate() {
+            *value = state.register(i);
+        }
+        Cosim { lines: f.lines(), line: 0, peeked: None, before, compared: 0 }
+    }
+
+    // The next record of the trace without consuming it.
+    fn peek(&mut self) -> Option<&(usize, Commit)> {
+        while self.peeked.is_none() {
+            let text = match self.lines.next() {
+                Some(line) => line.expect("File read error"),
+                None => return None,
+            };
+            self.line += 1;
+            match parse_line(&text) {
+                Ok(Some(commit)) => self.peeked = Some((self.line, commit)),
+                Ok(None) => {}
+                Err(message) => panic!("Malformed trace line {}: {}: {}", self.line, message, text),
+            }
+        }
+        self.peeked.as_ref()
+    }
+
+    /// True if the trace has records that were not compared.
+    pub fn has_more(&mut self) -> bool {
+        self.peek().is_some()
+    }
+
+    // Called by step() after every retired instruction.
+    pub(crate) fn retire(&mut self, state: &State, retired: &Retired) -> Option<Divergence> {
+        let mut writes = Vec::new();
+        for i in 1..32 {
+            let value = state.register(i);
+            if value != self.before[i] {
+                writes.push((i as u32, value));
+            }
+            self.before[i] = value;
+        }
+        let ksim = Commit { pc: retired.pc, instruction: retired.instruction, writes };
+        let next_pc = self.peek().map(|r| r.1.pc);
+        if retired.trapped && next_pc.is_some_and(|pc| pc != retired.pc) {
+            return None;
+        }
+        let (line, reference) = match self.peeked.take() {
+            Some((line, reference)) => (line, reference),
+            None => return Some(Divergence { instret: state.instret(), line: None, ksim,
+                                             reference: None }),
+        };
+        self.compared += 1;
+        let same = reference.pc == ksim.pc && reference.instruction == ksim.instruction
+            && reference.writes.iter().all(|&(i, v)| state.register(i as usize) == v)
+            && ksim.writes.iter().all(|w| reference.writes.iter().any(|r| r.0 == w.0));
+        if same {
+            None
+        } else {
+            Some(Divergence { instret: state.instret(), line: Some(line), ksim,
+                              reference: Some(reference) })
+        }
+    }
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.line {
+            Some(line) => writeln!(f, "Co-simulation diverged at instruction {} (trace line {}):",
+                                   self.instret, line)?,
+            None => writeln!(f, "Co-simulation diverged at instruction {} (end of the trace):",
+                             self.instret)?,
+        }
+        let hex = |value: Option<u64>| value.map_or("-".to_string(), |v| format!("{:#010x}", v));
+        let reference = self.reference.as_ref();
+        write!(f, "  {:<8}{:<20}reference", "", "ksim")?;
+        let rows = [("pc", self.ksim.pc as u64, reference.map(|r| r.pc as u64)),
+                    ("insn", self.ksim.instruction as u64, reference.map(|r| r.instruction as u64))];
+        for &(name, ksim, reference) in rows.iter() {
+            write!(f, "\n  {:<8}{:<20}{}", name, hex(Some(ksim)), hex(reference))?;
+        }
+        // every register either side writes, in order
+        let mut registers: Vec<u32> = self.ksim.writes.iter().map(|w| w.0)
+            .chain(reference.iter().flat_map(|r| r.writes.iter().map(|w| w.0)))
+            .collect();
+        registers.sort();
+        registers.dedup();
+        let find = |writes: &[(u32, u64)], index| writes.iter().find(|w| w.0 == index).map(|w| w.1);
+        for index in registers {
+            let ours = find(&self.ksim.writes, index);
+            let theirs = reference.and_then(|r| find(&r.writes, index));
+            write!(f, "\n  {:<8}{:<20}{}", disasm::register_name(index), hex(ours), hex(theirs))?;
+        }
+        Ok(())
+    }
+}
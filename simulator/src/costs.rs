@@ -0,0 +1,166 @@
+// A per-instruction latency and energy model.
+//
+// A table gives every class of instruction a cost in cycles, which takes
+// the place of the one cycle an instruction takes otherwise, and an energy
+// estimate.  Cache miss and branch misprediction penalties still come on
+// top.  The cycles count in mcycle and cycle, so a program can time itself
+// under the model.  The table file has a line per class:
+//   <class> <cycles> [<energy>]
+// with # starting a comment.  The energy is in whatever unit the table
+// uses, picojoules say, and is reported in that unit.  Classes:
+//   alu               integer arithmetic, logic, shifts, lui and auipc
+//   mul, div          the M extension's multiplies, and divides and remainders
+//   load, store
+//   branch-taken, branch-not-taken
+//   jump              jal and jalr
+//   csr               CSR instructions, ecall, ebreak, xret, wfi, sfence.vma
+//   atomic            lr, sc and the AMOs
+//   other             fences, exit, print_int and illegal instructions
+// Classes the table leaves out cost one cycle and no energy.  An instruction
+// is charged to its class even if it traps; a fetch that fails costs one
+// cycle.
+
+use std::io::prelude::*;
+use std::fs::File;
+
+use decode::Op;
+
+pub const CLASSES: [&str; 11] = ["alu", "mul", "div", "load", "store", "branch-taken",
+                                 "branch-not-taken", "jump", "csr", "atomic", "other"];
+
+const ALU: usize = 0;
+const MUL: usize = 1;
+pub(crate) const DIV: usize = 2;
+const LOAD: usize = 3;
+const STORE: usize = 4;
+const TAKEN: usize = 5;
+const NOT_TAKEN: usize = 6;
+const JUMP: usize = 7;
+const CSR: usize = 8;
+const ATOMIC: usize = 9;
+const OTHER: usize = 10;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ClassStats {
+    pub instructions: u64,
+    pub cycles: u64,
+    pub energy: f64,
+}
+
+#[derive(Clone)]
+pub struct CostModel {
+    // cycles and energy of one instruction of each class
+    cycles: [u64; CLASSES.len()],
+    energy: [f64; CLASSES.len()],
+    pub stats: [ClassStats; CLASSES.len()],
+}
+
+// The class of an instruction, an index into CLASSES; taken only matters
+// for branches.
+pub(crate) fn class(op: Op, taken: bool) -> usize {
+    match op {
+        Op::Lui | Op::Auipc | Op::Addi | Op::Slti | Op::Sltiu | Op::Xori | Op::Ori | Op::Andi
+        | Op::Slli | Op::Srli | Op::Srai | Op::Add | Op::Sub | Op::Sll | Op::Slt | Op::Sltu
+        | Op::Xor | Op::Srl | Op::Sra | Op::Or | Op::And | Op::Addiw | Op::Slliw | Op::Srliw
+        | Op::Sraiw | Op::Addw | Op::Subw | Op::Sllw | Op::Srlw | Op::Sraw => ALU,
+        Op::Mul | Op::Mulh | Op::Mulhsu | Op::Mulhu | Op::Mulw => MUL,
+        Op::Div | Op::Divu | Op::Rem | Op::Remu | Op::Divw | Op::Divuw | Op::Remw
+        | Op::Remuw => DIV,
+        Op::Lb | Op::Lh | Op::Lw | Op::Lbu | Op::Lhu | Op::Lwu | Op::Ld => LOAD,
+        Op::Sb | Op::Sh | Op::Sw | Op::Sd => STORE,
+        Op::Beq | Op::Bne | Op::Blt | Op::Bge | Op::Bltu | Op::Bgeu => {
+            if taken { TAKEN } else { NOT_TAKEN }
+        }
+        Op::Jal | Op::Jalr => JUMP,
+        Op::System => CSR,
+        Op::Amo => ATOMIC,
+        Op::Fence | Op::FenceI | Op::Exit | Op::PrintInt | Op::Illegal | Op::Undecoded => OTHER,
+    }
+}
+
+impl CostModel {
+    /// Read a cost table from the file at path.
+    pub fn load(path: &str) -> CostModel {
+        let mut text = String::new();
+        match File::open(path) {
+            Ok(mut file) => file.read_to_string(&mut text).expect("File read error"),
+            Err(err) => panic!("File open error: {:?}", err),
+        };
+        match CostModel::parse(&text) {
+            Ok(model) => model,
+            Err(message) => panic!("Malformed cost table {}: {}", path, message),
+        }
+    }
+
+    /// Parse the lines of a cost table.
+    pub fn parse(text: &str) -> Result<CostModel, String> {
+        let mut model = CostModel {
+            cycles: [1; CLASSES.len()],
+            energy: [0.0; CLASSES.len()],
+            stats: [ClassStats::default(); CLASSES.len()],
+        };
+        for (number, line) in text.lines().enumerate() {
+            let fields: Vec<&str> = line.split('#').next().unwrap().split_whitespace().collect();
+            if fields.is_empty() {
+                continue;
+            }
+            let index = match CLASSES.iter().position(|&c| c == fields[0]) {
+                Some(index) => index,
+                None => return Err(format!("line {}: unknown class {}", number + 1, fields[0])),
+            };
+            if fields.len() > 3 {
+                return Err(format!("line {}: expected <class> <cycles> [<energy>]", number + 1));
+            }
+            model.cycles[index] = match fields.get(1).map(|s| s.parse::<u64>()) {
+                Some(Ok(cycles)) => cycles,
+                _ => return Err(format!("line {}: {} needs a number of cycles", number + 1, fields[0])),
+            };
+            if let Some(energy) = fields.get(2) {
+                model.energy[index] = match energy.parse::<f64>() {
+                    Ok(energy) if energy >= 0.0 => energy,
+                    _ => return Err(format!("line {}: bad energy {}", number + 1, energy)),
+                };
+            }
+        }
+        Ok(model)
+    }
+
+    /// Cycles of each class, in the order of CLASSES.
+    pub fn cycles(&self) -> [u64; CLASSES.len()] {
+        self.cycles
+    }
+
+    // Account for an instruction and return its cycles.
+    #[inline(always)]
+    pub(crate) fn charge(&mut self, op: Op, taken: bool) -> u64 {
+        let index = class(op, taken);
+        let stats = &mut self.stats[index];
+        stats.instructions += 1;
+        stats.cycles += self.cycles[index];
+        stats.energy += self.energy[index];
+        self.cycles[index]
+    }
+
+    /// Energy of all instructions so far.
+    pub fn energy(&self) -> f64 {
+        self.stats.iter().map(|s| s.energy).sum()
+    }
+
+    pub fn report(&self) {
+        let instructions: u64 = self.stats.iter().map(|s| s.instructions).sum();
+        let cycles: u64 = self.stats.iter().map(|s| s.cycles).sum();
+        let energy = self.energy();
+        eprintln!("cost model:");
+        eprintln!("  {:<16}  {:>12}  {:>12}  {:>14}", "class", "instructions", "cycles", "energy");
+        for (name, s) in CLASSES.iter().zip(self.stats.iter()) {
+            if s.instructions != 0 {
+                eprintln!("  {:<16}  {:>12}  {:>12}  {:>14.2}", name, s.instructions, s.cycles,
+                          s.energy);
+            }
+        }
+        eprintln!("  {:<16}  {:>12}  {:>12}  {:>14.2}", "total", instructions, cycles, energy);
+        if instructions != 0 {
+            eprintln!("  energy per instruction: {:.2}", energy / instructions as f64);
+        }
+    }
+}
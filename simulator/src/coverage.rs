@@ -0,0 +1,198 @@
+// Code coverage.
+// Counts how often each instruction retired and how often each conditional
+// branch was taken and not taken.  With the line map written by
+// `kasm --line-map`, the counts are reported per line of the assembly source,
+// either as an lcov tracefile for genhtml and coverage services or as a
+// listing annotated in the style of gcov.
+
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+
+use {Retired, State};
+
+// Source line of every instruction, read from `kasm --line-map` output: a
+// "source <path>" line followed by "<address in hex> <line>" lines.
+pub struct LineMap {
+    pub source: String,
+    // (address, line number), in address order
+    lines: Vec<(u32, u32)>,
+}
+
+impl LineMap {
+    pub fn load(path: &str) -> LineMap {
+        let f = match File::open(path) {
+            Ok(file) => BufReader::new(file),
+            Err(err) => panic!("File open error: {:?}", err),
+        };
+        let mut source = None;
+        let mut lines = Vec::new();
+        for line in f.lines() {
+            let l = line.unwrap();
+            if let Some(path) = l.strip_prefix("source ") {
+                source = Some(path.to_string());
+                continue;
+            }
+            let mut fields = l.split_whitespace();
+            let (addr, number) = match (fields.next(), fields.next()) {
+                (Some(addr), Some(number)) => (addr, number),
+                (None, _) => continue,
+                _ => panic!("Malformed line map line: {}", l),
+            };
+            match (u32::from_str_radix(addr, 16), number.parse::<u32>()) {
+                (Ok(addr), Ok(number)) => lines.push((addr, number)),
+                _ => panic!("Malformed line map line: {}", l),
+            }
+        }
+        let source = match source {
+            Some(source) => source,
+            None => panic!("Line map has no source line: {}", path),
+        };
+        lines.sort();
+        LineMap { source, lines }
+    }
+}
+
+#[derive(Default, Clone, Copy)]
+struct Counts {
+    executed: u64,
+    taken: u64,
+    not_taken: u64,
+}
+
+// Counts of one source line.
+#[derive(Default)]
+struct Line {
+    executed: u64,
+    // (taken, not taken) of each branch on the line
+    branches: Vec<(u64, u64)>,
+}
+
+fn is_branch(instruction: u32) -> bool {
+    instruction & 0x7f == 0b1100011
+}
+
+#[derive(Default)]
+pub struct Coverage {
+    counts: HashMap<u32, Counts>,
+}
+
+impl Coverage {
+    pub fn retire(&mut self, retired: &Retired) {
+        // an instruction that trapped did not complete
+        if retired.trapped {
+            return;
+        }
+        let counts = self.counts.entry(retired.pc).or_default();
+        counts.executed += 1;
+        if is_branch(retired.instruction) {
+            if retired.taken {
+                counts.taken += 1;
+            } else {
+                counts.not_taken += 1;
+            }
+        }
+    }
+
+    // Counts of every source line holding an instruction of the program.
+    fn per_line(&self, map: &LineMap, program: &State) -> BTreeMap<u32, Line> {
+        let mut lines: BTreeMap<u32, Line> = BTreeMap::new();
+        for &(addr, number) in map.lines.iter() {
+            let counts = self.counts.get(&addr).cloned().unwrap_or_default();
+            let line = lines.entry(number).or_default();
+            line.executed = line.executed.max(counts.executed);
+            if program.image_word(addr).is_some_and(is_branch) {
+                line.branches.push((counts.taken, counts.not_taken));
+            }
+        }
+        lines
+    }
+
+    // lcov tracefile with line (DA) and branch (BRDA) records.
+    pub fn write_lcov(&self, path: &str, map: &LineMap, program: &State) {
+        let lines = self.per_line(map, program);
+        let mut f = match File::create(path) {
+            Ok(file) => file,
+            Err(err) => panic!("File open error: {:?}", err),
+        };
+        writeln!(f, "TN:").unwrap();
+        writeln!(f, "SF:{}", map.source).unwrap();
+        let (mut found, mut hit) = (0, 0);
+        for (number, line) in lines.iter() {
+            for (block, &(taken, not_taken)) in line.branches.iter().enumerate() {
+                for (branch, &count) in [taken, not_taken].iter().enumerate() {
+                    found += 1;
+                    if line.executed == 0 {
+                        writeln!(f, "BRDA:{},{},{},-", number, block, branch).unwrap();
+                    } else {
+                        writeln!(f, "BRDA:{},{},{},{}", number, block, branch, count).unwrap();
+                        if count > 0 {
+                            hit += 1;
+                        }
+                    }
+                }
+            }
+        }
+        writeln!(f, "BRF:{}", found).unwrap();
+        writeln!(f, "BRH:{}", hit).unwrap();
+        for (number, line) in lines.iter() {
+            writeln!(f, "DA:{},{}", number, line.executed).unwrap();
+        }
+        writeln!(f, "LF:{}", lines.len()).unwrap();
+        writeln!(f, "LH:{}", lines.values().filter(|l| l.executed > 0).count()).unwrap();
+        writeln!(f, "end_of_record").unwrap();
+    }
+
+    // The source with the execution count in front of each line: "-" for
+    // lines without an instruction and "#####" for ones that never ran.
+    // Branches get their taken and not taken counts after the line.
+    pub fn write_listing(&self, path: &str, map: &LineMap, program: &State) {
+        let lines = self.per_line(map, program);
+        let source = match fs::read_to_string(&map.source) {
+            Ok(text) => text,
+            Err(err) => panic!("File open error: {:?}", err),
+        };
+        let executed = lines.values().filter(|l| l.executed > 0).count();
+        let directions = lines.values().map(|l| 2 * l.branches.len()).sum::<usize>();
+        let taken = lines.values()
+            .flat_map(|l| l.branches.iter())
+            .map(|&(t, n)| (t > 0) as usize + (n > 0) as usize)
+            .sum::<usize>();
+
+        let mut f = match File::create(path) {
+            Ok(file) => file,
+            Err(err) => panic!("File open error: {:?}", err),
+        };
+        writeln!(f, "{:>9}:{:>5}:Source:{}", "-", 0, map.source).unwrap();
+        writeln!(f, "{:>9}:{:>5}:Lines executed:{} of {}", "-", 0,
+                 percent(executed, lines.len()), lines.len()).unwrap();
+        writeln!(f, "{:>9}:{:>5}:Branch directions taken:{} of {}", "-", 0,
+                 percent(taken, directions), directions).unwrap();
+        for (i, text) in source.lines().enumerate() {
+            let number = i as u32 + 1;
+            match lines.get(&number) {
+                None => writeln!(f, "{:>9}:{:>5}:{}", "-", number, text).unwrap(),
+                Some(line) => {
+                    let count = match line.executed {
+                        0 => "#####".to_string(),
+                        n => n.to_string(),
+                    };
+                    write!(f, "{:>9}:{:>5}:{}", count, number, text).unwrap();
+                    for &(taken, not_taken) in line.branches.iter() {
+                        write!(f, "  [taken {}, not taken {}]", taken, not_taken).unwrap();
+                    }
+                    writeln!(f).unwrap();
+                }
+            }
+        }
+    }
+}
+
+fn percent(part: usize, total: usize) -> String {
+    if total == 0 {
+        "0.00%".to_string()
+    } else {
+        format!("{:.2}%", part as f64 * 100.0 / total as f64)
+    }
+}
@@ -0,0 +1,527 @@
+// Control and status registers (Zicsr) of the M, S and U privilege modes.
+// The counters are views of the simulator's instruction and cycle counts
+// and of the CLINT's mtime.  sstatus, sie and sip are restricted views of
+// mstatus, mie and mip.  The PMP registers are checked in pmp.rs.
+//
+// The CSRs hold 32 bits, except for the scratch, xepc and xtval registers,
+// which hold 64 so that they keep whole registers and faulting addresses;
+// mret and sret go to the low 32 bits of xepc, as the pc has 32 bits.
+// On RV64 instructions see them XLEN bits wide, see read_csr_xlen(): the
+// counters are whole, misa.MXL and the UXL and SXL fields of mstatus read as
+// 64-bit, the interrupt bit of mcause and scause moves to bit 63 and pmpcfg0
+// and pmpcfg2 hold eight entries each.  The upper half of the other 32-bit
+// CSRs reads as zero and is dropped on writes.  satp stays zero, as RV64
+// only has Bare addressing here.
+
+use std::mem;
+
+use history::Write;
+use pmp::PMP_ENTRIES;
+use trap::Exception;
+use State;
+
+pub const SSTATUS: u32 = 0x100;
+pub const SIE: u32 = 0x104;
+pub const STVEC: u32 = 0x105;
+pub const SCOUNTEREN: u32 = 0x106;
+pub const SSCRATCH: u32 = 0x140;
+pub const SEPC: u32 = 0x141;
+pub const SCAUSE: u32 = 0x142;
+pub const STVAL: u32 = 0x143;
+pub const SIP: u32 = 0x144;
+pub const SATP: u32 = 0x180;
+pub const MSTATUS: u32 = 0x300;
+pub const MISA: u32 = 0x301;
+pub const MEDELEG: u32 = 0x302;
+pub const MIDELEG: u32 = 0x303;
+pub const MIE: u32 = 0x304;
+pub const MTVEC: u32 = 0x305;
+pub const MCOUNTEREN: u32 = 0x306;
+pub const MSCRATCH: u32 = 0x340;
+pub const MEPC: u32 = 0x341;
+pub const MCAUSE: u32 = 0x342;
+pub const MTVAL: u32 = 0x343;
+pub const MIP: u32 = 0x344;
+pub const PMPCFG0: u32 = 0x3a0;
+pub const PMPCFG1: u32 = 0x3a1;
+pub const PMPCFG2: u32 = 0x3a2;
+pub const PMPCFG3: u32 = 0x3a3;
+pub const PMPADDR0: u32 = 0x3b0;
+pub const PMPADDR15: u32 = 0x3bf;
+pub const MCYCLE: u32 = 0xb00;
+pub const MINSTRET: u32 = 0xb02;
+pub const MCYCLEH: u32 = 0xb80;
+pub const MINSTRETH: u32 = 0xb82;
+pub const CYCLE: u32 = 0xc00;
+pub const TIME: u32 = 0xc01;
+pub const INSTRET: u32 = 0xc02;
+pub const CYCLEH: u32 = 0xc80;
+pub const TIMEH: u32 = 0xc81;
+pub const INSTRETH: u32 = 0xc82;
+pub const MVENDORID: u32 = 0xf11;
+pub const MARCHID: u32 = 0xf12;
+pub const MIMPID: u32 = 0xf13;
+pub const MHARTID: u32 = 0xf14;
+
+// privilege modes
+pub const PRV_U: u32 = 0;
+pub const PRV_S: u32 = 1;
+pub const PRV_M: u32 = 3;
+
+pub const MSTATUS_SIE: u32 = 1 << 1;
+pub const MSTATUS_MIE: u32 = 1 << 3;
+pub const MSTATUS_SPIE: u32 = 1 << 5;
+pub const MSTATUS_MPIE: u32 = 1 << 7;
+pub const MSTATUS_SPP: u32 = 1 << 8;
+pub const MSTATUS_MPP: u32 = 3 << 11;
+pub const MSTATUS_MPRV: u32 = 1 << 17;
+pub const MSTATUS_SUM: u32 = 1 << 18;
+pub const MSTATUS_MXR: u32 = 1 << 19;
+pub const MSTATUS_TVM: u32 = 1 << 20;
+pub const MSTATUS_TW: u32 = 1 << 21;
+pub const MSTATUS_TSR: u32 = 1 << 22;
+const MSTATUS_MASK: u32 = MSTATUS_SIE | MSTATUS_MIE | MSTATUS_SPIE | MSTATUS_MPIE | MSTATUS_SPP
+                        | MSTATUS_MPP | MSTATUS_MPRV | MSTATUS_SUM | MSTATUS_MXR | MSTATUS_TVM
+                        | MSTATUS_TW | MSTATUS_TSR;
+const SSTATUS_MASK: u32 = MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP | MSTATUS_SUM | MSTATUS_MXR;
+// XLEN of U- and S-mode on RV64, always 64
+const MSTATUS_UXL: u64 = 2 << 32;
+const MSTATUS_SXL: u64 = 2 << 34;
+
+pub const MIP_SSIP: u32 = 1 << 1;
+pub const MIP_MSIP: u32 = 1 << 3;
+pub const MIP_STIP: u32 = 1 << 5;
+pub const MIP_MTIP: u32 = 1 << 7;
+pub const MIP_SEIP: u32 = 1 << 9;
+pub const MIP_MEIP: u32 = 1 << 11;
+// the pending bits software can write; the CLINT drives the others
+const MIP_WRITABLE: u32 = MIP_SSIP | MIP_STIP | MIP_SEIP;
+const MIE_MASK: u32 = MIP_SSIP | MIP_MSIP | MIP_STIP | MIP_MTIP | MIP_SEIP | MIP_MEIP;
+// exceptions that can be delegated: all but ecall from M-mode
+const MEDELEG_MASK: u32 = 0xb3ff;
+
+pub const SATP_MODE: u32 = 1 << 31;
+
+// RV32 with the A, I, M, S and U extensions
+const MISA_VALUE: u32 = (1 << 30) | (1 << 0) | (1 << 8) | (1 << 12) | (1 << 18) | (1 << 20);
+// the extension bits of misa
+const MISA_EXTENSIONS: u32 = (1 << 26) - 1;
+
+const PLAIN: [u32; 19] = [
+    MSTATUS, MEDELEG, MIDELEG, MIE, MTVEC, MCOUNTEREN, MSCRATCH, MEPC, MCAUSE, MTVAL, MIP,
+    STVEC, SCOUNTEREN, SSCRATCH, SEPC, SCAUSE, STVAL, SATP, MHARTID,
+];
+
+// The CSRs that are plain registers, saved in checkpoints.
+pub fn plain() -> Vec<u32> {
+    PLAIN.iter().cloned().chain(PMPCFG0..=PMPCFG3).chain(PMPADDR0..=PMPADDR15).collect()
+}
+
+#[derive(Debug, Clone)]
+pub struct Csr {
+    pub mhartid: u32,
+    pub mstatus: u32,
+    pub medeleg: u32,
+    pub mideleg: u32,
+    pub mie: u32,
+    pub mtvec: u32,
+    pub mcounteren: u32,
+    pub mscratch: u64,
+    pub mepc: u64,
+    pub mcause: u32,
+    pub mtval: u64,
+    // only the software-writable bits; see State::mip()
+    pub mip: u32,
+    pub stvec: u32,
+    pub scounteren: u32,
+    pub sscratch: u64,
+    pub sepc: u64,
+    pub scause: u32,
+    pub stval: u64,
+    pub satp: u32,
+    pub pmpcfg: [u32; PMP_ENTRIES / 4],
+    pub pmpaddr: [u32; PMP_ENTRIES],
+}
+
+impl Csr {
+    pub fn new(hartid: u32) -> Csr {
+        Csr {
+            mhartid: hartid,
+            mstatus: 0,
+            medeleg: 0,
+            mideleg: 0,
+            mie: 0,
+            mtvec: 0,
+            mcounteren: 0,
+            mscratch: 0,
+            mepc: 0,
+            mcause: 0,
+            mtval: 0,
+            mip: 0,
+            stvec: 0,
+            scounteren: 0,
+            sscratch: 0,
+            sepc: 0,
+            scause: 0,
+            stval: 0,
+            satp: 0,
+            pmpcfg: [0; PMP_ENTRIES / 4],
+            pmpaddr: [0; PMP_ENTRIES],
+        }
+    }
+
+    // The storage behind the CSRs that are plain registers.
+    pub(crate) fn field(&mut self, csr: u32) -> Option<&mut u32> {
+        match csr {
+            MSTATUS => Some(&mut self.mstatus),
+            MEDELEG => Some(&mut self.medeleg),
+            MIDELEG => Some(&mut self.mideleg),
+            MIE => Some(&mut self.mie),
+            MTVEC => Some(&mut self.mtvec),
+            MCOUNTEREN => Some(&mut self.mcounteren),
+            MCAUSE => Some(&mut self.mcause),
+            MIP => Some(&mut self.mip),
+            STVEC => Some(&mut self.stvec),
+            SCOUNTEREN => Some(&mut self.scounteren),
+            SCAUSE => Some(&mut self.scause),
+            SATP => Some(&mut self.satp),
+            MHARTID => Some(&mut self.mhartid),
+            PMPCFG0..=PMPCFG3 => Some(&mut self.pmpcfg[(csr - PMPCFG0) as usize]),
+            PMPADDR0..=PMPADDR15 => Some(&mut self.pmpaddr[(csr - PMPADDR0) as usize]),
+            _ => None,
+        }
+    }
+
+    // The storage behind the plain CSRs that are 64 bits wide.
+    pub(crate) fn wide_field(&mut self, csr: u32) -> Option<&mut u64> {
+        match csr {
+            MSCRATCH => Some(&mut self.mscratch),
+            MEPC => Some(&mut self.mepc),
+            MTVAL => Some(&mut self.mtval),
+            SSCRATCH => Some(&mut self.sscratch),
+            SEPC => Some(&mut self.sepc),
+            STVAL => Some(&mut self.stval),
+            _ => None,
+        }
+    }
+
+    // The value of a plain CSR, and setting it without any WARL masks.
+    pub(crate) fn get(&mut self, csr: u32) -> Option<u64> {
+        match self.wide_field(csr) {
+            Some(field) => Some(*field),
+            None => self.field(csr).map(|field| *field as u64),
+        }
+    }
+
+    pub(crate) fn set(&mut self, csr: u32, value: u64) -> bool {
+        if let Some(field) = self.wide_field(csr) {
+            *field = value;
+        } else if let Some(field) = self.field(csr) {
+            *field = value as u32;
+        } else {
+            return false;
+        }
+        true
+    }
+}
+
+impl State {
+    /// Value of a CSR, or None if it does not exist.
+    pub fn read_csr(&self, csr: u32) -> Option<u64> {
+        let value = match csr {
+            SSTATUS => self.csr.mstatus & SSTATUS_MASK,
+            SIE => self.csr.mie & self.csr.mideleg,
+            STVEC => self.csr.stvec,
+            SCOUNTEREN => self.csr.scounteren,
+            SSCRATCH => return Some(self.csr.sscratch),
+            SEPC => return Some(self.csr.sepc),
+            SCAUSE => self.csr.scause,
+            STVAL => return Some(self.csr.stval),
+            SIP => self.mip() & self.csr.mideleg,
+            SATP => self.csr.satp,
+            MSTATUS => self.csr.mstatus,
+            MISA => MISA_VALUE,
+            MEDELEG => self.csr.medeleg,
+            MIDELEG => self.csr.mideleg,
+            MIE => self.csr.mie,
+            MTVEC => self.csr.mtvec,
+            MCOUNTEREN => self.csr.mcounteren,
+            MSCRATCH => return Some(self.csr.mscratch),
+            MEPC => return Some(self.csr.mepc),
+            MCAUSE => self.csr.mcause,
+            MTVAL => return Some(self.csr.mtval),
+            MIP => self.mip(),
+            PMPCFG0..=PMPCFG3 => self.csr.pmpcfg[(csr - PMPCFG0) as usize],
+            PMPADDR0..=PMPADDR15 => self.csr.pmpaddr[(csr - PMPADDR0) as usize],
+            MCYCLE | CYCLE => self.cycle as u32,
+            MCYCLEH | CYCLEH => (self.cycle >> 32) as u32,
+            MINSTRET | INSTRET => self.instret as u32,
+            MINSTRETH | INSTRETH => (self.instret >> 32) as u32,
+            TIME => self.bus.borrow().clint.mtime as u32,
+            TIMEH => (self.bus.borrow().clint.mtime >> 32) as u32,
+            MVENDORID | MARCHID | MIMPID => 0,
+            MHARTID => self.csr.mhartid,
+            _ => return None,
+        };
+        Some(value as u64)
+    }
+
+    // A CSR as instructions see it, XLEN bits wide.
+    fn read_csr_xlen(&self, csr: u32) -> Option<u64> {
+        let value = self.read_csr(csr)?;
+        if self.xlen == 32 {
+            return Some(value);
+        }
+        let wide = match csr {
+            MCYCLEH | MINSTRETH | CYCLEH | TIMEH | INSTRETH | PMPCFG1 | PMPCFG3 => return None,
+            MCYCLE | CYCLE => self.cycle,
+            MINSTRET | INSTRET => self.instret,
+            TIME => self.bus.borrow().clint.mtime,
+            MISA => (2 << 62) | (value & MISA_EXTENSIONS as u64),
+            MSTATUS => value | MSTATUS_UXL | MSTATUS_SXL,
+            SSTATUS => value | MSTATUS_UXL,
+            MCAUSE | SCAUSE => (value & 0x7fff_ffff) | (value >> 31) << 63,
+            PMPCFG0 | PMPCFG2 => value | self.read_csr(csr + 1)? << 32,
+            _ => value,
+        };
+        Some(wide)
+    }
+
+    // Write a CSR as instructions see it, see read_csr_xlen().
+    fn write_csr_xlen(&mut self, csr: u32, value: u64) -> bool {
+        if self.xlen == 32 {
+            return self.write_csr(csr, value as u32);
+        }
+        match csr {
+            MCYCLEH | MINSTRETH | PMPCFG1 | PMPCFG3 => false,
+            MCYCLE | MINSTRET => {
+                self.set_counter(csr, value);
+                true
+            }
+            SATP => true,
+            MSCRATCH | MTVAL | SSCRATCH | STVAL => {
+                self.set_wide_csr(csr, value);
+                true
+            }
+            MEPC | SEPC => {
+                self.set_wide_csr(csr, value & !3);
+                true
+            }
+            MCAUSE | SCAUSE => {
+                let interrupt = ((value >> 63) as u32) << 31;
+                self.write_csr(csr, interrupt | (value as u32 & 0x7fff_ffff))
+            }
+            PMPCFG0 | PMPCFG2 => {
+                self.write_csr(csr, value as u32) && self.write_csr(csr + 1, (value >> 32) as u32)
+            }
+            _ => self.write_csr(csr, value as u32),
+        }
+    }
+
+    // Whether the current privilege mode may access a CSR at all.
+    fn csr_accessible(&self, csr: u32) -> bool {
+        // bits 9:8 hold the lowest privilege that may access it
+        if self.privilege < (csr >> 8) & 3 {
+            return false;
+        }
+        if csr == SATP && self.privilege == PRV_S && self.csr.mstatus & MSTATUS_TVM != 0 {
+            return false;
+        }
+        // user counters, enabled per counter by mcounteren and scounteren
+        if csr & 0xf60 == 0xc00 {
+            let bit = 1 << (csr & 0x1f);
+            if self.privilege < PRV_M && self.csr.mcounteren & bit == 0 {
+                return false;
+            }
+            if self.privilege < PRV_S && self.csr.scounteren & bit == 0 {
+                return false;
+            }
+        }
+        true
+    }
+
+    // Write a CSR as an instruction would, applying the WARL masks.
+    // Returns false if the CSR does not exist or is read-only.
+    fn write_csr(&mut self, csr: u32, value: u32) -> bool {
+        let (csr, value) = match csr {
+            SSTATUS => (MSTATUS, (self.csr.mstatus & !SSTATUS_MASK) | (value & SSTATUS_MASK)),
+            SIE => (MIE, (self.csr.mie & !self.csr.mideleg) | (value & self.csr.mideleg & MIE_MASK)),
+            SIP => {
+                let mask = self.csr.mideleg & MIP_SSIP;
+                (MIP, (self.csr.mip & !mask) | (value & mask))
+            }
+            MSTATUS => {
+                let mut value = value & MSTATUS_MASK;
+                // MPP = 2 is reserved
+                if value & MSTATUS_MPP == 2 << 11 {
+                    value &= !MSTATUS_MPP;
+                }
+                (MSTATUS, value)
+            }
+            MEDELEG => (MEDELEG, value & MEDELEG_MASK),
+            MIDELEG => (MIDELEG, value & (MIP_SSIP | MIP_STIP | MIP_SEIP)),
+            MIE => (MIE, value & MIE_MASK),
+            MIP => (MIP, value & MIP_WRITABLE),
+            // direct or vectored mode
+            MTVEC | STVEC => (csr, value & !2),
+            MEPC | SEPC => (csr, value & !3),
+            MCOUNTEREN | SCOUNTEREN => (csr, value & 7),
+            // only Bare and Sv32 exist, so any MODE bit pattern is legal
+            SATP => (SATP, value),
+            PMPCFG0..=PMPCFG3 => (csr, self.csr.pmp_write_cfg((csr - PMPCFG0) as usize, value)),
+            // writes to a locked pmpaddr are ignored
+            PMPADDR0..=PMPADDR15 => {
+                if !self.csr.pmp_addr_writable((csr - PMPADDR0) as usize) {
+                    return true;
+                }
+                (csr, value)
+            }
+            // misa is fixed
+            MISA => return true,
+            MCYCLE => {
+                self.set_counter(MCYCLE, (self.cycle & !0xffff_ffff) | value as u64);
+                return true;
+            }
+            MCYCLEH => {
+                self.set_counter(MCYCLE, (self.cycle & 0xffff_ffff) | ((value as u64) << 32));
+                return true;
+            }
+            MINSTRET => {
+                self.set_counter(MINSTRET, (self.instret & !0xffff_ffff) | value as u64);
+                return true;
+            }
+            MINSTRETH => {
+                self.set_counter(MINSTRET, (self.instret & 0xffff_ffff) | ((value as u64) << 32));
+                return true;
+            }
+            MHARTID => return false,
+            _ => (csr, value),
+        };
+        if self.csr.wide_field(csr).is_some() {
+            self.set_wide_csr(csr, value as u64);
+        } else if self.csr.field(csr).is_some() {
+            self.set_csr(csr, value);
+        } else {
+            return false;
+        }
+        true
+    }
+
+    // Set one of the plain 32-bit CSRs, recording the change for reverse
+    // execution.
+    pub(crate) fn set_csr(&mut self, csr: u32, value: u32) {
+        let field = self.csr.field(csr).expect("not a plain 32-bit CSR");
+        let old = *field;
+        *field = value;
+        if let Some(ref mut history) = self.history {
+            history.record(Write::Csr { index: csr, old: old as u64, new: value as u64 });
+        }
+    }
+
+    // The same for the 64-bit ones.
+    pub(crate) fn set_wide_csr(&mut self, csr: u32, value: u64) {
+        let field = self.csr.wide_field(csr).expect("not a plain 64-bit CSR");
+        let old = *field;
+        *field = value;
+        if let Some(ref mut history) = self.history {
+            history.record(Write::Csr { index: csr, old, new: value });
+        }
+    }
+
+    // Set mcycle or minstret, whole, recording the old count.
+    fn set_counter(&mut self, csr: u32, value: u64) {
+        let counter = if csr == MCYCLE { &mut self.cycle } else { &mut self.instret };
+        let old = mem::replace(counter, value);
+        if let Some(ref mut history) = self.history {
+            history.record(Write::Csr { index: csr, old, new: value });
+        }
+    }
+
+    // Undo a recorded CSR write.
+    pub(crate) fn restore_csr(&mut self, csr: u32, value: u64) {
+        match csr {
+            MCYCLE => self.cycle = value,
+            MINSTRET => self.instret = value,
+            _ => {
+                self.csr.set(csr, value);
+            }
+        }
+    }
+
+    pub(crate) fn exec_system(&mut self, instruction: u32) -> Result<(), Exception> {
+        let funct3 = (instruction & 0x7000) >> 12;
+        let rd =     (instruction & 0xf80) >> 7;
+        let rs1 =    (instruction & 0xf8000) >> 15;
+        let rs2 =    (instruction & 0x1f00000) >> 20;
+        let funct7 = (instruction & 0xfe000000) >> 25;
+        let csr =    instruction >> 20;
+        let illegal = Exception::IllegalInstruction(instruction);
+        let mstatus = self.csr.mstatus;
+
+        if funct3 == 0b000 {
+            match instruction {
+                0x00000073 => return Err(Exception::EnvironmentCall(self.privilege)),
+                0x00100073 => return Err(Exception::Breakpoint),
+                0x30200073 => {
+                    if self.privilege < PRV_M {
+                        return Err(illegal);
+                    }
+                    self.mret();
+                    return Ok(());
+                }
+                0x10200073 => {
+                    if self.privilege < PRV_S
+                        || (self.privilege == PRV_S && mstatus & MSTATUS_TSR != 0) {
+                        return Err(illegal);
+                    }
+                    self.sret();
+                    return Ok(());
+                }
+                // wfi may return at any time, so it is a nop here
+                0x10500073 => {
+                    if self.privilege == PRV_U
+                        || (self.privilege == PRV_S && mstatus & MSTATUS_TW != 0) {
+                        return Err(illegal);
+                    }
+                }
+                _ if funct7 == 0b0001001 && rd == 0 => {
+                    if self.privilege == PRV_U
+                        || (self.privilege == PRV_S && mstatus & MSTATUS_TVM != 0) {
+                        return Err(illegal);
+                    }
+                    let addr = if rs1 == 0 { None } else { Some(self.register[rs1 as usize] as u32) };
+                    let asid = if rs2 == 0 { None } else { Some(self.register[rs2 as usize] as u32) };
+                    self.tlb.flush(addr, asid);
+                }
+                _ => return Err(illegal),
+            }
+            self.address += 4;
+            return Ok(());
+        }
+
+        if !self.csr_accessible(csr) {
+            return Err(illegal);
+        }
+        let old = match self.read_csr_xlen(csr) {
+            Some(value) => value,
+            None => return Err(illegal),
+        };
+        // csrrs and csrrc with rs1 = x0 only read, so they work on read-only CSRs
+        let src = if funct3 & 0b100 != 0 { rs1 as u64 } else { self.register[rs1 as usize] };
+        let new = match funct3 & 0b011 {
+            0b01 => Some(src),
+            0b10 if rs1 != 0 => Some(old | src),
+            0b11 if rs1 != 0 => Some(old & !src),
+            0b10 | 0b11 => None,
+            _ => return Err(illegal),
+        };
+        if let Some(new) = new {
+            if csr >> 10 == 0b11 || !self.write_csr_xlen(csr, new) {
+                return Err(illegal);
+            }
+        }
+        self.write_register(rd, old);
+        self.address += 4;
+        Ok(())
+    }
+}
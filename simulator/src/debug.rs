@@ -0,0 +1,340 @@
+// Interactive debugger, started with --debug.
+// Commands are read line by line from stdin; run "help" for the list.
+// History is recorded while debugging, so execution can also run backwards.
+
+use std::io;
+use std::io::prelude::*;
+
+use csr;
+use disasm;
+use history;
+use symbols::Symbols;
+use trap;
+use watch::{Watchpoint, Watchpoints};
+use State;
+
+const HELP: &str = "\
+step [n]        (s)   execute n instructions (default 1)
+continue        (c)   run until a breakpoint or exit
+rstep [n]       (rs)  undo n instructions (default 1)
+rcontinue       (rc)  run backwards until a breakpoint or the start of history
+break [loc]     (b)   set a breakpoint at loc, or list breakpoints
+delete <loc>    (d)   delete the breakpoint at loc
+regs            (r)   show all registers
+print <reg|csr> (p)   show one register or CSR
+x <addr> [n]          show n memory words from addr (default 1)
+who <reg|addr>        show the instruction that last wrote a register or word
+watch [spec]          add a watchpoint, or list watchpoints; spec is a register
+                      name or <addr>[:<len>][:r|w|rw] (default 4 bytes, w)
+unwatch <n>           delete watchpoint number n
+quit            (q)   leave the debugger
+loc is a symbol name or an address like 0x40";
+
+struct Debugger<'a> {
+    symbols: &'a Symbols,
+    breakpoints: Vec<u32>,
+}
+
+impl<'a> Debugger<'a> {
+    fn parse_address(&self, s: &str) -> Option<u32> {
+        if let Some(hex) = s.strip_prefix("0x") {
+            u32::from_str_radix(hex, 16).ok()
+        } else if let Ok(n) = s.parse::<u32>() {
+            Some(n)
+        } else {
+            self.symbols.address(s)
+        }
+    }
+
+    // " <symbol+offset>" for addr, or nothing without a symbol table.
+    fn location(&self, addr: u32) -> String {
+        match self.symbols.lookup(addr) {
+            Some(_) => format!(" <{}>", self.symbols.describe(addr)),
+            None => String::new(),
+        }
+    }
+
+    fn show_location(&self, state: &State) {
+        if let Some(exception) = state.unhandled {
+            println!("[{}] {} at pc {:#010x}", state.instret, exception, state.address);
+            return;
+        }
+        if let Some(request) = state.bus.borrow().bad_request {
+            println!("[{}] unsupported tohost request {:#010x}", state.instret, request);
+            return;
+        }
+        if state.is_exit() {
+            println!("[{}] program has exited", state.instret);
+            return;
+        }
+        // outside M-mode, show the privilege mode as well
+        let mode = if state.privilege == csr::PRV_M {
+            String::new()
+        } else {
+            format!(" {}-mode", trap::mode_name(state.privilege))
+        };
+        let instruction = state.probe(state.address)
+            .and_then(|paddr| state.image_word(paddr));
+        let text = match instruction {
+            Some(i) => disasm::disassemble(i, state.address),
+            None => "(not mapped)".to_string(),
+        };
+        println!("[{}]{} {:#010x}{}  {}", state.instret, mode, state.address,
+                 self.location(state.address), text);
+    }
+
+    // Print watchpoint hits of the last instruction; true if any fired.
+    fn watch_hit(&self, state: &mut State) -> bool {
+        let hits = state.watch_hits();
+        for hit in &hits {
+            println!("{}", hit);
+        }
+        !hits.is_empty()
+    }
+
+    fn step(&self, state: &mut State, n: u64) {
+        for _ in 0..n {
+            if state.is_exit() {
+                break;
+            }
+            state.step();
+            if self.watch_hit(state) {
+                break;
+            }
+        }
+        self.show_location(state);
+    }
+
+    fn cont(&self, state: &mut State) {
+        while !state.is_exit() {
+            state.step();
+            if self.watch_hit(state) {
+                break;
+            }
+            if self.breakpoints.contains(&state.address) {
+                println!("Breakpoint at {:#010x}", state.address);
+                break;
+            }
+        }
+        self.show_location(state);
+    }
+
+    fn reverse_step(&self, state: &mut State, n: u64) {
+        for _ in 0..n {
+            if !state.reverse_step() {
+                println!("Reached the start of the recorded history");
+                break;
+            }
+        }
+        self.show_location(state);
+    }
+
+    fn reverse_cont(&self, state: &mut State) {
+        loop {
+            if !state.reverse_step() {
+                println!("Reached the start of the recorded history");
+                break;
+            }
+            if self.breakpoints.contains(&state.address) {
+                println!("Breakpoint at {:#010x}", state.address);
+                break;
+            }
+        }
+        self.show_location(state);
+    }
+
+    fn who(&self, state: &State, target: &str) {
+        let history = state.history.as_ref().unwrap();
+        let found = match disasm::register_index(target) {
+            Some(index) => history.last_register_write(index),
+            None => match self.parse_address(target) {
+                Some(addr) => history.last_memory_write(addr & !3, 4),
+                None => {
+                    println!("Unknown register or address: {}", target);
+                    return;
+                }
+            },
+        };
+        match found {
+            Some((pc, age, write)) => {
+                let mask = u64::MAX >> (64 - state.xlen());
+                let change = match write {
+                    history::Write::Reg { index, old, new } =>
+                        format!("{}: {:#x} -> {:#x}", disasm::register_name(index), old & mask,
+                                new & mask),
+                    history::Write::Mem { addr, width, old, new } =>
+                        format!("{} bytes at {:#010x}: {:#x} -> {:#x}", width, addr, old, new),
+                    history::Write::Csr { index, old, new } =>
+                        format!("{}: {:#x} -> {:#x}", disasm::csr_name(index), old, new),
+                    history::Write::Image { addr, old, new } =>
+                        format!("code at {:#010x}: {:#010x} -> {:#010x}", addr, old, new),
+                    history::Write::Devices(_) => "device state".to_string(),
+                };
+                let instruction = state.probe(pc).and_then(|paddr| state.image_word(paddr))
+                    .unwrap_or(0);
+                println!("{} instructions ago at {:#010x}{}  {}", age, pc,
+                         self.location(pc), disasm::disassemble(instruction, pc));
+                println!("  {}", change);
+            }
+            None => println!("No write to {} in the last {} instructions", target, history.depth()),
+        }
+    }
+
+    fn command(&mut self, state: &mut State, line: &str) -> bool {
+        let words: Vec<_> = line.split_whitespace().collect();
+        let count = |i: usize| words.get(i).and_then(|s| s.parse::<u64>().ok()).unwrap_or(1);
+        match words.first().cloned().unwrap_or("") {
+            "" => {}
+            "s" | "step" => self.step(state, count(1)),
+            "c" | "continue" => self.cont(state),
+            "rs" | "rstep" => self.reverse_step(state, count(1)),
+            "rc" | "rcontinue" => self.reverse_cont(state),
+            "b" | "break" => match words.get(1) {
+                Some(loc) => match self.parse_address(loc) {
+                    Some(addr) => {
+                        self.breakpoints.push(addr);
+                        println!("Breakpoint at {:#010x}{}", addr, self.location(addr));
+                    }
+                    None => println!("Unknown location: {}", loc),
+                },
+                None => {
+                    for &addr in &self.breakpoints {
+                        println!("{:#010x}{}", addr, self.location(addr));
+                    }
+                }
+            },
+            "d" | "delete" => match words.get(1).and_then(|loc| self.parse_address(loc)) {
+                Some(addr) => self.breakpoints.retain(|&b| b != addr),
+                None => println!("Usage: delete <loc>"),
+            },
+            "r" | "regs" => state.show_register(),
+            "p" | "print" => {
+                let name = words.get(1).cloned().unwrap_or("");
+                match (disasm::register_index(name), disasm::csr_index(name)) {
+                    (Some(index), _) => {
+                        // registers hold RV32 values sign-extended
+                        let signed = state.register[index as usize] as i64;
+                        println!("{} = {:#010x} ({})", disasm::register_name(index),
+                                 state.register(index as usize), signed);
+                    }
+                    (None, Some(csr)) => println!("{} = {:#010x}", name, state.read_csr(csr).unwrap()),
+                    _ => println!("Usage: print <reg|csr>"),
+                }
+            }
+            "x" => match words.get(1).and_then(|a| self.parse_address(a)) {
+                Some(addr) => {
+                    for i in 0..count(2) as u32 {
+                        let a = (addr & !3).wrapping_add(4 * i);
+                        match state.bus.borrow().read(a, 4) {
+                            Some(value) => println!("{:#010x}: {:#010x}", a, value),
+                            None => {
+                                println!("{:#010x}: out of range", a);
+                                break;
+                            }
+                        }
+                    }
+                }
+                None => println!("Usage: x <addr> [n]"),
+            },
+            "who" => match words.get(1) {
+                Some(target) => self.who(state, target),
+                None => println!("Usage: who <reg|addr>"),
+            },
+            "watch" => match words.get(1) {
+                Some(spec) => match Watchpoint::parse(spec) {
+                    Some(w) => {
+                        let watch = state.observers.watch.get_or_insert_with(Watchpoints::default);
+                        println!("Watchpoint {}: {}", watch.list.len(), w);
+                        watch.list.push(w);
+                    }
+                    None => println!("Bad watchpoint: {}", spec),
+                },
+                None => {
+                    if let Some(ref watch) = state.observers.watch {
+                        for (i, w) in watch.list.iter().enumerate() {
+                            println!("{}: {}", i, w);
+                        }
+                    }
+                }
+            },
+            "unwatch" => match (words.get(1).and_then(|n| n.parse::<usize>().ok()),
+                                state.observers.watch.as_mut()) {
+                (Some(n), Some(watch)) if n < watch.list.len() => {
+                    watch.list.remove(n);
+                }
+                _ => println!("Usage: unwatch <n>"),
+            },
+            "q" | "quit" => return false,
+            "h" | "help" => println!("{}", HELP),
+            cmd => println!("Unknown command: {} (try help)", cmd),
+        }
+        true
+    }
+}
+
+pub fn run(state: &mut State, symbols: &Symbols) {
+    state.enable_history();
+    let mut debugger = Debugger {
+        symbols,
+        breakpoints: Vec::new(),
+    };
+    debugger.show_location(state);
+    let stdin = io::stdin();
+    loop {
+        print!("(ksim) ");
+        io::stdout().flush().unwrap();
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).unwrap() == 0 {
+            break;
+        }
+        if !debugger.command(state, &line) {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Counts t1 up to 3 and exits.
+    fn hart() -> State {
+        let program = vec![
+            0x0000_0313, // li t1, 0
+            0x0030_0393, // li t2, 3
+            0x0013_0313, // loop: addi t1, t1, 1
+            0xfe73_1ee3, // bne t1, t2, loop
+            0x0000_000b, // exit
+        ];
+        let mut state = State::init(program);
+        state.enable_history();
+        state
+    }
+
+    #[test]
+    fn breakpoints_both_ways() {
+        let symbols = Symbols::default();
+        let mut debugger = Debugger { symbols: &symbols, breakpoints: Vec::new() };
+        let mut state = hart();
+        debugger.command(&mut state, "break 0x8");
+        let mut stops = Vec::new();
+        for _ in 0..3 {
+            debugger.command(&mut state, "c");
+            stops.push((state.address, state.instret, state.register(6)));
+        }
+        assert_eq!(stops, vec![(8, 2, 0), (8, 4, 1), (8, 6, 2)]);
+
+        debugger.command(&mut state, "rc");
+        assert_eq!((state.address, state.instret, state.register(6)), (8, 4, 1));
+        debugger.command(&mut state, "rc");
+        assert_eq!((state.address, state.instret, state.register(6)), (8, 2, 0));
+        // no more breakpoints behind, so back to where history starts
+        debugger.command(&mut state, "rc");
+        assert_eq!((state.address, state.instret), (0, 0));
+
+        debugger.command(&mut state, "delete 0x8");
+        debugger.command(&mut state, "c");
+        assert!(state.is_exit());
+        assert_eq!((state.instret, state.register(6)), (9, 3));
+    }
+}
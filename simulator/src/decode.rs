@@ -0,0 +1,177 @@
+// Pre-decoded instructions.
+//
+// Decoding pulls the operation, the register numbers and the sign-extended
+// immediate out of an instruction word once; executing it is then a single
+// match on `Op`.  Every hart keeps one decoded slot per word of its program
+// image, filled the first time the word is fetched.
+//
+// What decodes depends on XLEN: the RV64-only instructions are illegal on
+// an RV32 hart, and shifts by an immediate have a 6-bit shift amount on RV64.
+//
+// A flat image is separate from the data RAM, so stores never change it.
+// An ELF program's image is a copy of its RAM, and stores to it reach the
+// image as well, as do disk reads for both kinds.  The image only changes
+// through load_image(), write_instruction() and checkpoint restores, which
+// drop the affected slots; fence.i drops them all.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Op {
+    // the slot has not been decoded yet
+    Undecoded,
+    Illegal,
+    Lui, Auipc, Jal, Jalr,
+    Beq, Bne, Blt, Bge, Bltu, Bgeu,
+    Lb, Lh, Lw, Lbu, Lhu, Lwu, Ld,
+    Sb, Sh, Sw, Sd,
+    Addi, Slti, Sltiu, Xori, Ori, Andi, Slli, Srli, Srai,
+    Add, Sub, Sll, Slt, Sltu, Xor, Srl, Sra, Or, And,
+    Mul, Mulh, Mulhsu, Mulhu, Div, Divu, Rem, Remu,
+    // the RV64 operations on the low 32 bits, sign-extending the result
+    Addiw, Slliw, Srliw, Sraiw,
+    Addw, Subw, Sllw, Srlw, Sraw,
+    Mulw, Divw, Divuw, Remw, Remuw,
+    Fence, FenceI,
+    // ecall, ebreak, xret, wfi, sfence.vma and the CSR instructions, which
+    // are rare enough to be decoded again each time
+    System,
+    // lr, sc and the AMOs, likewise
+    Amo,
+    Exit, PrintInt,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Decoded {
+    pub op: Op,
+    pub rd: u8,
+    pub rs1: u8,
+    pub rs2: u8,
+    // sign extended; the shift amount for shifts by an immediate
+    pub imm: u32,
+    pub raw: u32,
+}
+
+pub(crate) const UNDECODED: Decoded = Decoded { op: Op::Undecoded, rd: 0, rs1: 0, rs2: 0, imm: 0, raw: 0 };
+
+pub(crate) fn decode(instruction: u32, xlen: u32) -> Decoded {
+    let rv64 = xlen == 64;
+    let opcode = instruction & 0x7f;
+    let funct3 = (instruction & 0x7000) >> 12;
+    let funct7 = (instruction & 0xfe000000) >> 25;
+    let sign = |mask: u32| if instruction >> 31 == 1 { mask } else { 0 };
+    let imm_i = ((instruction & 0xfff00000) >> 20) | sign(0xfffff000);
+    let imm_s = ((instruction & 0xfe000000) >> 20) | ((instruction & 0xf80) >> 7) | sign(0xfffff000);
+    let imm_b = ((instruction & 0x80000000) >> 19) | ((instruction & 0x7e000000) >> 20)
+              | ((instruction & 0xf00) >> 7) | ((instruction & 0x80) << 4) | sign(0xffffe000);
+    let imm_j = ((instruction & 0x80000000) >> 11) | ((instruction & 0x7fe00000) >> 20)
+              | ((instruction & 0x100000) >> 9) | (instruction & 0xff000) | sign(0xffe00000);
+    let shamt = (instruction & 0x3f00000) >> 20;
+    // bits 31:26 above the shift amount of an RV64 shift
+    let funct6 = instruction >> 26;
+
+    let (op, imm) = match opcode {
+        0b0110111 => (Op::Lui, instruction & 0xfffff000),
+        0b0010111 => (Op::Auipc, instruction & 0xfffff000),
+        0b1101111 => (Op::Jal, imm_j),
+        0b1100111 => (Op::Jalr, imm_i),
+        0b1100011 => (match funct3 {
+            0b000 => Op::Beq,
+            0b001 => Op::Bne,
+            0b100 => Op::Blt,
+            0b101 => Op::Bge,
+            0b110 => Op::Bltu,
+            0b111 => Op::Bgeu,
+            _     => Op::Illegal,
+        }, imm_b),
+        0b0000011 => (match funct3 {
+            0b000 => Op::Lb,
+            0b001 => Op::Lh,
+            0b010 => Op::Lw,
+            0b011 if rv64 => Op::Ld,
+            0b100 => Op::Lbu,
+            0b101 => Op::Lhu,
+            0b110 if rv64 => Op::Lwu,
+            _     => Op::Illegal,
+        }, imm_i),
+        0b0100011 => (match funct3 {
+            0b000 => Op::Sb,
+            0b001 => Op::Sh,
+            0b010 => Op::Sw,
+            0b011 if rv64 => Op::Sd,
+            _     => Op::Illegal,
+        }, imm_s),
+        0b0010011 => match funct3 {
+            0b000 => (Op::Addi, imm_i),
+            0b010 => (Op::Slti, imm_i),
+            0b011 => (Op::Sltiu, imm_i),
+            0b100 => (Op::Xori, imm_i),
+            0b110 => (Op::Ori, imm_i),
+            0b111 => (Op::Andi, imm_i),
+            // shamt[5] is reserved on RV32
+            _ if !rv64 && shamt >= 32 => (Op::Illegal, 0),
+            0b001 => (Op::Slli, shamt),
+            _     => (if funct6 == 0b010000 { Op::Srai } else { Op::Srli }, shamt),
+        },
+        0b0011011 if rv64 => match funct3 {
+            0b000 => (Op::Addiw, imm_i),
+            _ if shamt >= 32 => (Op::Illegal, 0),
+            0b001 => (Op::Slliw, shamt),
+            0b101 => (if funct7 == 0b0100000 { Op::Sraiw } else { Op::Srliw }, shamt),
+            _     => (Op::Illegal, 0),
+        },
+        0b0110011 => (match (funct7, funct3) {
+            (0b0000000, 0b000) => Op::Add,
+            (0b0100000, 0b000) => Op::Sub,
+            (0b0000000, 0b001) => Op::Sll,
+            (0b0000000, 0b010) => Op::Slt,
+            (0b0000000, 0b011) => Op::Sltu,
+            (0b0000000, 0b100) => Op::Xor,
+            (0b0000000, 0b101) => Op::Srl,
+            (0b0100000, 0b101) => Op::Sra,
+            (0b0000000, 0b110) => Op::Or,
+            (0b0000000, 0b111) => Op::And,
+            (0b0000001, 0b000) => Op::Mul,
+            (0b0000001, 0b001) => Op::Mulh,
+            (0b0000001, 0b010) => Op::Mulhsu,
+            (0b0000001, 0b011) => Op::Mulhu,
+            (0b0000001, 0b100) => Op::Div,
+            (0b0000001, 0b101) => Op::Divu,
+            (0b0000001, 0b110) => Op::Rem,
+            (0b0000001, 0b111) => Op::Remu,
+            _                  => Op::Illegal,
+        }, 0),
+        0b0111011 if rv64 => (match (funct7, funct3) {
+            (0b0000000, 0b000) => Op::Addw,
+            (0b0100000, 0b000) => Op::Subw,
+            (0b0000000, 0b001) => Op::Sllw,
+            (0b0000000, 0b101) => Op::Srlw,
+            (0b0100000, 0b101) => Op::Sraw,
+            (0b0000001, 0b000) => Op::Mulw,
+            (0b0000001, 0b100) => Op::Divw,
+            (0b0000001, 0b101) => Op::Divuw,
+            (0b0000001, 0b110) => Op::Remw,
+            (0b0000001, 0b111) => Op::Remuw,
+            _                  => Op::Illegal,
+        }, 0),
+        0b0001111 => (match funct3 {
+            0b000 => Op::Fence,
+            0b001 => Op::FenceI,
+            _     => Op::Illegal,
+        }, 0),
+        0b1110011 => (Op::System, 0),
+        0b0101111 => (Op::Amo, 0),
+        0b0001011 => (match funct3 {
+            0b000 => Op::Exit,
+            0b001 => Op::PrintInt,
+            _     => Op::Illegal,
+        }, 0),
+        _ => (Op::Illegal, 0),
+    };
+    Decoded {
+        op,
+        rd: ((instruction & 0xf80) >> 7) as u8,
+        rs1: ((instruction & 0xf8000) >> 15) as u8,
+        rs2: ((instruction & 0x1f00000) >> 20) as u8,
+        imm,
+        raw: instruction,
+    }
+}
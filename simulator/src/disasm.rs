@@ -0,0 +1,266 @@
+// Instruction decoding helpers shared by the statistics and tracing code.
+// They know the instructions of both RV32 and RV64.
+
+// Mnemonic of an instruction, or "unknown".
+pub fn mnemonic(instruction: u32) -> &'static str {
+    let opcode = instruction & 0x7f;
+    let funct3 = (instruction & 0x7000) >> 12;
+    let funct7 = (instruction & 0xfe000000) >> 25;
+    match opcode {
+        0b0000011 => match funct3 {
+            0b000 => "lb",
+            0b001 => "lh",
+            0b010 => "lw",
+            0b011 => "ld",
+            0b100 => "lbu",
+            0b101 => "lhu",
+            0b110 => "lwu",
+            _     => "unknown",
+        },
+        0b0010011 => match funct3 {
+            0b000 => "addi",
+            0b010 => "slti",
+            0b011 => "sltiu",
+            0b100 => "xori",
+            0b110 => "ori",
+            0b111 => "andi",
+            0b001 => "slli",
+            _     => if funct7 >> 1 == 0b010000 { "srai" } else { "srli" },
+        },
+        0b0011011 => match funct3 {
+            0b000 => "addiw",
+            0b001 => "slliw",
+            0b101 => if funct7 == 0b0100000 { "sraiw" } else { "srliw" },
+            _     => "unknown",
+        },
+        0b0010111 => "auipc",
+        0b0100011 => match funct3 {
+            0b000 => "sb",
+            0b001 => "sh",
+            0b010 => "sw",
+            0b011 => "sd",
+            _     => "unknown",
+        },
+        0b0110011 => match (funct7, funct3) {
+            (0b0000000, 0b000) => "add",
+            (0b0100000, 0b000) => "sub",
+            (0b0000000, 0b001) => "sll",
+            (0b0000000, 0b010) => "slt",
+            (0b0000000, 0b011) => "sltu",
+            (0b0000000, 0b100) => "xor",
+            (0b0000000, 0b101) => "srl",
+            (0b0100000, 0b101) => "sra",
+            (0b0000000, 0b110) => "or",
+            (0b0000000, 0b111) => "and",
+            (0b0000001, 0b000) => "mul",
+            (0b0000001, 0b001) => "mulh",
+            (0b0000001, 0b010) => "mulhsu",
+            (0b0000001, 0b011) => "mulhu",
+            (0b0000001, 0b100) => "div",
+            (0b0000001, 0b101) => "divu",
+            (0b0000001, 0b110) => "rem",
+            (0b0000001, 0b111) => "remu",
+            _                  => "unknown",
+        },
+        0b0111011 => match (funct7, funct3) {
+            (0b0000000, 0b000) => "addw",
+            (0b0100000, 0b000) => "subw",
+            (0b0000000, 0b001) => "sllw",
+            (0b0000000, 0b101) => "srlw",
+            (0b0100000, 0b101) => "sraw",
+            (0b0000001, 0b000) => "mulw",
+            (0b0000001, 0b100) => "divw",
+            (0b0000001, 0b101) => "divuw",
+            (0b0000001, 0b110) => "remw",
+            (0b0000001, 0b111) => "remuw",
+            _                  => "unknown",
+        },
+        0b0110111 => "lui",
+        0b1100011 => match funct3 {
+            0b000 => "beq",
+            0b001 => "bne",
+            0b100 => "blt",
+            0b101 => "bge",
+            0b110 => "bltu",
+            0b111 => "bgeu",
+            _     => "unknown",
+        },
+        0b1100111 => "jalr",
+        0b1101111 => "jal",
+        0b0001011 => match funct3 {
+            0b000 => "exit",
+            0b001 => "print_int",
+            _     => "unknown",
+        },
+        0b0001111 => match funct3 {
+            0b000 => "fence",
+            0b001 => "fence.i",
+            _     => "unknown",
+        },
+        0b1110011 => match funct3 {
+            0b000 => match instruction {
+                0x00000073 => "ecall",
+                0x00100073 => "ebreak",
+                0x10200073 => "sret",
+                0x30200073 => "mret",
+                0x10500073 => "wfi",
+                _ if funct7 == 0b0001001 && instruction & 0xf80 == 0 => "sfence.vma",
+                _          => "unknown",
+            },
+            0b001 => "csrrw",
+            0b010 => "csrrs",
+            0b011 => "csrrc",
+            0b101 => "csrrwi",
+            0b110 => "csrrsi",
+            0b111 => "csrrci",
+            _     => "unknown",
+        },
+        0b0101111 if funct3 == 0b010 => match funct7 >> 2 {
+            0b00010 => "lr.w",
+            0b00011 => "sc.w",
+            0b00001 => "amoswap.w",
+            0b00000 => "amoadd.w",
+            0b00100 => "amoxor.w",
+            0b01100 => "amoand.w",
+            0b01000 => "amoor.w",
+            0b10000 => "amomin.w",
+            0b10100 => "amomax.w",
+            0b11000 => "amominu.w",
+            0b11100 => "amomaxu.w",
+            _       => "unknown",
+        },
+        0b0101111 if funct3 == 0b011 => match funct7 >> 2 {
+            0b00010 => "lr.d",
+            0b00011 => "sc.d",
+            0b00001 => "amoswap.d",
+            0b00000 => "amoadd.d",
+            0b00100 => "amoxor.d",
+            0b01100 => "amoand.d",
+            0b01000 => "amoor.d",
+            0b10000 => "amomin.d",
+            0b10100 => "amomax.d",
+            0b11000 => "amominu.d",
+            0b11100 => "amomaxu.d",
+            _       => "unknown",
+        },
+        _ => "unknown",
+    }
+}
+
+// Coarse instruction class used for the instruction mix.
+pub fn class(instruction: u32) -> &'static str {
+    match instruction & 0x7f {
+        0b0000011 => "load",
+        0b0100011 => "store",
+        0b0110011 | 0b0111011 if instruction >> 25 == 1 => "muldiv",
+        0b0010011 | 0b0110011 | 0b0110111 | 0b0010111 | 0b0011011 | 0b0111011 => "alu",
+        0b1100011 => "branch",
+        0b1100111 | 0b1101111 => "jump",
+        0b0001011 => "custom",
+        0b0101111 => "atomic",
+        0b0001111 | 0b1110011 => "system",
+        _ => "unknown",
+    }
+}
+
+const ABI_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2",
+    "s0", "s1", "a0", "a1", "a2", "a3", "a4", "a5",
+    "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7",
+    "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6",
+];
+
+// ABI name of an integer register.
+pub fn register_name(index: u32) -> &'static str {
+    ABI_NAMES[index as usize & 0x1f]
+}
+
+// Register index from "x5", "t0", "fp" and so on.
+pub fn register_index(name: &str) -> Option<u32> {
+    if name == "fp" {
+        return Some(8);
+    }
+    if let Some(i) = ABI_NAMES.iter().position(|&n| n == name) {
+        return Some(i as u32);
+    }
+    match name.strip_prefix('x').map(|n| n.parse::<u32>()) {
+        Some(Ok(i)) if i < 32 => Some(i),
+        _ => None,
+    }
+}
+
+const CSR_NAMES: [(u32, &str); 56] = [
+    (0x100, "sstatus"), (0x104, "sie"), (0x105, "stvec"), (0x106, "scounteren"),
+    (0x140, "sscratch"), (0x141, "sepc"), (0x142, "scause"), (0x143, "stval"),
+    (0x144, "sip"), (0x180, "satp"), (0x300, "mstatus"), (0x301, "misa"),
+    (0x302, "medeleg"), (0x303, "mideleg"), (0x304, "mie"), (0x305, "mtvec"),
+    (0x306, "mcounteren"), (0x340, "mscratch"), (0x341, "mepc"), (0x342, "mcause"),
+    (0x343, "mtval"), (0x344, "mip"), (0x3a0, "pmpcfg0"), (0x3a1, "pmpcfg1"),
+    (0x3a2, "pmpcfg2"), (0x3a3, "pmpcfg3"), (0x3b0, "pmpaddr0"), (0x3b1, "pmpaddr1"),
+    (0x3b2, "pmpaddr2"), (0x3b3, "pmpaddr3"), (0x3b4, "pmpaddr4"), (0x3b5, "pmpaddr5"),
+    (0x3b6, "pmpaddr6"), (0x3b7, "pmpaddr7"), (0x3b8, "pmpaddr8"), (0x3b9, "pmpaddr9"),
+    (0x3ba, "pmpaddr10"), (0x3bb, "pmpaddr11"), (0x3bc, "pmpaddr12"), (0x3bd, "pmpaddr13"),
+    (0x3be, "pmpaddr14"), (0x3bf, "pmpaddr15"), (0xb00, "mcycle"), (0xb02, "minstret"),
+    (0xb80, "mcycleh"), (0xb82, "minstreth"), (0xc00, "cycle"), (0xc01, "time"),
+    (0xc02, "instret"), (0xc80, "cycleh"), (0xc81, "timeh"), (0xc82, "instreth"),
+    (0xf11, "mvendorid"), (0xf12, "marchid"), (0xf13, "mimpid"), (0xf14, "mhartid"),
+];
+
+// Name of a CSR, or its number in hex.
+pub fn csr_name(csr: u32) -> String {
+    match CSR_NAMES.iter().find(|c| c.0 == csr) {
+        Some(&(_, name)) => name.to_string(),
+        None => format!("{:#x}", csr),
+    }
+}
+
+// CSR number from its name.
+pub fn csr_index(name: &str) -> Option<u32> {
+    CSR_NAMES.iter().find(|c| c.1 == name).map(|c| c.0)
+}
+
+// Assembly text of an instruction at pc, e.g. "addi a0, zero, 1".
+pub fn disassemble(instruction: u32, pc: u32) -> String {
+    let name = mnemonic(instruction);
+    let rd =  register_name((instruction & 0xf80) >> 7);
+    let rs1 = register_name((instruction & 0xf8000) >> 15);
+    let rs2 = register_name((instruction & 0x1f00000) >> 20);
+    let imm_i = (instruction as i32) >> 20;
+    let imm_s = ((instruction as i32) >> 25 << 5) | ((instruction >> 7) & 0x1f) as i32;
+    let imm_b = ((instruction as i32) >> 31 << 12) | (((instruction >> 7) & 1) << 11) as i32
+              | (((instruction >> 25) & 0x3f) << 5) as i32 | (((instruction >> 8) & 0xf) << 1) as i32;
+    let imm_j = ((instruction as i32) >> 31 << 20) | (instruction & 0xff000) as i32
+              | (((instruction >> 20) & 1) << 11) as i32 | (((instruction >> 21) & 0x3ff) << 1) as i32;
+    match instruction & 0x7f {
+        0b0000011 => format!("{} {}, {}({})", name, rd, imm_i, rs1),
+        0b0010011 | 0b0011011 => match name {
+            "slli" | "srli" | "srai" | "slliw" | "srliw" | "sraiw" =>
+                format!("{} {}, {}, {}", name, rd, rs1, imm_i & 0x3f),
+            _ => format!("{} {}, {}, {}", name, rd, rs1, imm_i),
+        },
+        0b0010111 | 0b0110111 => format!("{} {}, {:#x}", name, rd, instruction >> 12),
+        0b0100011 => format!("{} {}, {}({})", name, rs2, imm_s, rs1),
+        0b0110011 | 0b0111011 => format!("{} {}, {}, {}", name, rd, rs1, rs2),
+        0b1100011 => format!("{} {}, {}, {:#x}", name, rs1, rs2, pc.wrapping_add(imm_b as u32)),
+        0b1100111 => format!("{} {}, {}({})", name, rd, imm_i, rs1),
+        0b1101111 => format!("{} {}, {:#x}", name, rd, pc.wrapping_add(imm_j as u32)),
+        0b0001011 => match name {
+            "print_int" => format!("{} {}", name, rd),
+            _ => name.to_string(),
+        },
+        0b0001111 => name.to_string(),
+        0b1110011 => match name {
+            "csrrw" | "csrrs" | "csrrc" =>
+                format!("{} {}, {}, {}", name, rd, csr_name(instruction >> 20), rs1),
+            "csrrwi" | "csrrsi" | "csrrci" =>
+                format!("{} {}, {}, {}", name, rd, csr_name(instruction >> 20), (instruction >> 15) & 0x1f),
+            "sfence.vma" => format!("{} {}, {}", name, rs1, rs2),
+            _ => name.to_string(),
+        },
+        0b0101111 => match name {
+            "lr.w" | "lr.d" => format!("{} {}, ({})", name, rd, rs1),
+            _ => format!("{} {}, {}, ({})", name, rd, rs2, rs1),
+        },
+        _ => format!("unknown {:#010x}", instruction),
+    }
+}
@@ -0,0 +1,139 @@
+// A block storage device backed by a host image file.
+//
+// The disk is the image file cut into 512-byte sectors.  Transfers move
+// whole sectors between the disk and memory by DMA, and are done before the
+// store that starts them retires, so there is no completion interrupt.  The
+// registers at DISK_BASE are aligned words:
+//   +0x00  sector: first sector of the transfer
+//   +0x04  address: physical address of the buffer, a multiple of 4
+//   +0x08  count: number of sectors
+//   +0x0c  command: writing 1 reads sectors into memory, 2 writes memory to
+//          sectors; reads give the status of the last command
+//   +0x10  capacity in sectors (read-only)
+// Status codes are below.  A transfer that runs into an address where there
+// is no memory stops there; the words before it have been transferred.
+//
+// Sectors written by the program go to the image file straight away.  Words
+// that a read puts into RAM are also copied into the program image of the
+// hart that started it, so a bootloader can load a program and jump to it;
+// other harts keep their own copy of the image.
+
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::fs::{File, OpenOptions};
+
+pub const DISK_BASE: u32 = 0x1000_1000;
+pub const DISK_REGISTERS: u32 = 0x1000;
+pub const SECTOR_SIZE: u32 = 512;
+
+pub const STATUS_OK: u32 = 0;
+pub const STATUS_BAD_SECTOR: u32 = 1;
+pub const STATUS_BAD_ADDRESS: u32 = 2;
+pub const STATUS_BAD_COMMAND: u32 = 3;
+
+pub(crate) const DISK_COMMAND: u32 = 0x0c;
+
+pub const COMMAND_READ: u32 = 1;
+pub const COMMAND_WRITE: u32 = 2;
+
+#[derive(Clone)]
+pub struct Disk {
+    pub(crate) path: String,
+    pub(crate) data: Vec<u8>,
+    pub(crate) sector: u32,
+    pub(crate) address: u32,
+    pub(crate) count: u32,
+    pub(crate) status: u32,
+}
+
+impl Disk {
+    /// Open the image file at path.  Its size must be a multiple of the
+    /// sector size.
+    pub fn open(path: &str) -> Disk {
+        let mut data = Vec::new();
+        match File::open(path) {
+            Ok(mut file) => file.read_to_end(&mut data).expect("File read error"),
+            Err(err) => panic!("File open error: {:?}", err),
+        };
+        if !(data.len() as u64).is_multiple_of(SECTOR_SIZE as u64) {
+            panic!("Disk image size is not a multiple of {} bytes: {}", SECTOR_SIZE, path);
+        }
+        if data.len() as u64 > (u32::MAX as u64) {
+            panic!("Disk image is too large: {}", path);
+        }
+        Disk { path: path.to_string(), data, sector: 0, address: 0, count: 0, status: STATUS_OK }
+    }
+
+    // The sector, address, count and status registers.
+    pub(crate) fn registers(&self) -> [u32; 4] {
+        [self.sector, self.address, self.count, self.status]
+    }
+
+    pub(crate) fn set_registers(&mut self, registers: [u32; 4]) {
+        self.sector = registers[0];
+        self.address = registers[1];
+        self.count = registers[2];
+        self.status = registers[3];
+    }
+
+    // FNV-1a hash of the contents, which checkpoints keep to tell whether
+    // the image changed.
+    pub(crate) fn digest(&self) -> u64 {
+        self.data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+            (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+        })
+    }
+
+    pub fn capacity(&self) -> u32 {
+        self.data.len() as u32 / SECTOR_SIZE
+    }
+
+    // Byte range of the transfer in the image, None if it is past the end.
+    pub(crate) fn range(&self) -> Option<(usize, usize)> {
+        let end = self.sector as u64 + self.count as u64;
+        if end > self.capacity() as u64 {
+            return None;
+        }
+        let start = (self.sector * SECTOR_SIZE) as usize;
+        Some((start, start + (self.count * SECTOR_SIZE) as usize))
+    }
+
+    // Write bytes start..end of the image back to the file.
+    pub(crate) fn flush(&self, start: usize, end: usize) {
+        let mut f = match OpenOptions::new().write(true).open(&self.path) {
+            Ok(file) => file,
+            Err(err) => panic!("File open error: {:?}", err),
+        };
+        f.seek(SeekFrom::Start(start as u64)).expect("File seek error");
+        f.write_all(&self.data[start..end]).expect("File write error");
+    }
+
+    // Only aligned word accesses reach the registers.
+    pub(crate) fn read_register(&self, offset: u32, width: u32) -> Option<u32> {
+        if width != 4 || !offset.is_multiple_of(4) {
+            return None;
+        }
+        match offset {
+            0x00 => Some(self.sector),
+            0x04 => Some(self.address),
+            0x08 => Some(self.count),
+            0x0c => Some(self.status),
+            0x10 => Some(self.capacity()),
+            _ => Some(0),
+        }
+    }
+
+    // The bus runs the commands, see Bus::disk_command().
+    pub(crate) fn write_register(&mut self, offset: u32, value: u32, width: u32) -> Option<()> {
+        if width != 4 || !offset.is_multiple_of(4) {
+            return None;
+        }
+        match offset {
+            0x00 => self.sector = value,
+            0x04 => self.address = value,
+            0x08 => self.count = value,
+            _ => {}
+        }
+        Some(())
+    }
+}
@@ -0,0 +1,188 @@
+// Loading little-endian RISC-V ELF executables, such as the prebuilt
+// riscv-tests and riscv-arch-test programs.  32-bit files are RV32 programs
+// and 64-bit files RV64 ones; the segments of either have to lie in the
+// 32-bit physical address space.
+//
+// Only what is needed to run them is read: the entry point, the PT_LOAD
+// segments and the symbol table.  Relocations, dynamic linking and the
+// other program headers are ignored.
+
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+
+use symbols::Symbols;
+
+const MAGIC: &[u8; 4] = b"\x7fELF";
+const ELFCLASS32: u8 = 1;
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EM_RISCV: u16 = 243;
+const PT_LOAD: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+
+pub struct Segment {
+    // physical address
+    pub addr: u32,
+    pub data: Vec<u8>,
+    // bytes in memory; the ones past data are zero
+    pub size: u32,
+}
+
+pub struct Elf {
+    /// 32 or 64, from the file's class
+    pub xlen: u32,
+    pub entry: u32,
+    pub segments: Vec<Segment>,
+    pub symbols: Symbols,
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+// Little-endian fields of the file, failing on truncation.
+struct Bytes<'a>(&'a [u8]);
+
+impl<'a> Bytes<'a> {
+    fn slice(&self, offset: u32, len: u32) -> io::Result<&'a [u8]> {
+        let start = offset as usize;
+        match start.checked_add(len as usize) {
+            Some(end) if end <= self.0.len() => Ok(&self.0[start..end]),
+            _ => Err(invalid("ELF file is truncated")),
+        }
+    }
+    fn u16(&self, offset: u32) -> io::Result<u16> {
+        let b = self.slice(offset, 2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+    fn u32(&self, offset: u32) -> io::Result<u32> {
+        let b = self.slice(offset, 4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+    // an address or size: 8 bytes in a 64-bit file, which must fit in 32 bits
+    fn word(&self, offset: u32, wide: bool) -> io::Result<u32> {
+        if !wide {
+            return self.u32(offset);
+        }
+        match self.u32(offset + 4)? {
+            0 => self.u32(offset),
+            _ => Err(invalid("ELF address is outside the 32-bit address space")),
+        }
+    }
+    // NUL-terminated string at offset
+    fn string(&self, offset: u32) -> io::Result<String> {
+        let rest = match self.0.get(offset as usize..) {
+            Some(rest) => rest,
+            None => return Err(invalid("ELF file is truncated")),
+        };
+        match rest.iter().position(|&b| b == 0) {
+            Some(len) => Ok(String::from_utf8_lossy(&rest[..len]).into_owned()),
+            None => Err(invalid("ELF string is not terminated")),
+        }
+    }
+}
+
+/// True if the file at path starts like an ELF file.
+pub fn is_elf(path: &str) -> bool {
+    let mut magic = [0; 4];
+    match File::open(path) {
+        Ok(mut f) => f.read_exact(&mut magic).is_ok() && &magic == MAGIC,
+        Err(_) => false,
+    }
+}
+
+impl Elf {
+    pub fn read(path: &str) -> io::Result<Elf> {
+        let mut buf = Vec::new();
+        File::open(path)?.read_to_end(&mut buf)?;
+        Elf::parse(&buf)
+    }
+
+    pub fn parse(buf: &[u8]) -> io::Result<Elf> {
+        let f = Bytes(buf);
+        if f.slice(0, 4)? != MAGIC {
+            return Err(invalid("not an ELF file"));
+        }
+        let wide = match f.slice(4, 2)? {
+            [ELFCLASS32, ELFDATA2LSB] => false,
+            [ELFCLASS64, ELFDATA2LSB] => true,
+            _ => return Err(invalid("not a little-endian ELF file")),
+        };
+        if f.u16(18)? != EM_RISCV {
+            return Err(invalid("not a RISC-V ELF file"));
+        }
+        // e_entry, e_phoff and e_shoff have 8 bytes in 64-bit files, which
+        // moves the fields after them
+        let (shift, half) = if wide { (12, 4) } else { (0, 0) };
+        let entry = f.word(24, wide)?;
+        let (phoff, shoff) = (f.word(28 + half, wide)?, f.word(32 + 2 * half, wide)?);
+        let (phentsize, phnum) = (f.u16(42 + shift)? as u32, f.u16(44 + shift)? as u32);
+        let (shentsize, shnum) = (f.u16(46 + shift)? as u32, f.u16(48 + shift)? as u32);
+
+        let mut segments = Vec::new();
+        for i in 0..phnum {
+            let ph = phoff + i * phentsize;
+            if f.u32(ph)? != PT_LOAD {
+                continue;
+            }
+            // p_offset, p_paddr, p_filesz and p_memsz
+            let (offset, addr, filesz, size) = if wide {
+                (f.word(ph + 8, true)?, f.word(ph + 24, true)?, f.word(ph + 32, true)?,
+                 f.word(ph + 40, true)?)
+            } else {
+                (f.u32(ph + 4)?, f.u32(ph + 12)?, f.u32(ph + 16)?, f.u32(ph + 20)?)
+            };
+            if filesz > size {
+                return Err(invalid("ELF segment is larger in the file than in memory"));
+            }
+            if size != 0 {
+                segments.push(Segment { addr, data: f.slice(offset, filesz)?.to_vec(), size });
+            }
+        }
+
+        // the symbol table, with its string table in the linked section
+        let mut entries = Vec::new();
+        for i in 0..shnum {
+            let sh = shoff + i * shentsize;
+            if f.u32(sh + 4)? != SHT_SYMTAB {
+                continue;
+            }
+            // sh_offset, sh_size, sh_link and sh_entsize, and the sh_offset of
+            // the linked section
+            let (offset, size, link, entsize) = if wide {
+                (f.word(sh + 24, true)?, f.word(sh + 32, true)?, f.u32(sh + 40)?,
+                 f.word(sh + 56, true)?)
+            } else {
+                (f.u32(sh + 16)?, f.u32(sh + 20)?, f.u32(sh + 24)?, f.u32(sh + 36)?)
+            };
+            let linked = shoff + link * shentsize;
+            let strtab = if wide { f.word(linked + 24, true)? } else { f.u32(linked + 16)? };
+            // entry 0 is the undefined symbol
+            for j in 1..size / entsize.max(1) {
+                let sym = offset + j * entsize;
+                let name = f.string(strtab + f.u32(sym)?)?;
+                // st_shndx and st_value
+                let (section, value) = if wide {
+                    (f.u16(sym + 6)?, f.word(sym + 8, true).ok())
+                } else {
+                    (f.u16(sym + 14)?, Some(f.u32(sym + 4)?))
+                };
+                // skip unnamed and undefined symbols, and ones out of reach
+                if !name.is_empty() && section != 0 {
+                    if let Some(value) = value {
+                        entries.push((value, name));
+                    }
+                }
+            }
+        }
+        Ok(Elf { xlen: if wide { 64 } else { 32 }, entry, segments, symbols: Symbols::new(entries) })
+    }
+
+    /// Lowest and one past the highest address of the loaded segments.
+    pub fn extent(&self) -> Option<(u32, u64)> {
+        let start = self.segments.iter().map(|s| s.addr).min()?;
+        let end = self.segments.iter().map(|s| s.addr as u64 + s.size as u64).max()?;
+        Some((start, end))
+    }
+}
@@ -0,0 +1,176 @@
+// A memory-mapped framebuffer that is written out as image files.
+//
+// The pixels are rows of `width` pixels, top row first, at FB_MEMORY.  The
+// control registers at FB_BASE are aligned words:
+//   +0x0  control: writing a nonzero value dumps the current frame to the
+//         next numbered PPM file, <prefix>0000.ppm, <prefix>0001.ppm and
+//         so on; reads give the number of frames dumped so far
+//   +0x4  width in pixels (read-only)
+//   +0x8  height in pixels (read-only)
+//   +0xc  bits per pixel of the format (read-only)
+// Pixel formats:
+//   gray8     one byte per pixel, written as a PGM (P5) file
+//   rgb565    a halfword per pixel, red in the top 5 bits
+//   xrgb8888  a word per pixel, 0x00RRGGBB
+// Colour frames are written as PPM (P6) files with 8 bits per channel.
+
+use std::io::prelude::*;
+use std::fs::File;
+
+pub const FB_BASE: u32 = 0x1000_0000;
+pub const FB_REGISTERS: u32 = 0x1000;
+pub const FB_MEMORY: u32 = 0x2000_0000;
+// the pixels stay below 0x40000000
+const FB_MAX_SIZE: u64 = 0x2000_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Gray8,
+    Rgb565,
+    Xrgb8888,
+}
+
+impl Format {
+    pub(crate) fn bytes(self) -> u32 {
+        match self {
+            Format::Gray8 => 1,
+            Format::Rgb565 => 2,
+            Format::Xrgb8888 => 4,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Framebuffer {
+    pub width: u32,
+    pub height: u32,
+    pub format: Format,
+    // frame files are called <prefix><number>.ppm
+    pub prefix: String,
+    pub(crate) pixels: Vec<u8>,
+    pub(crate) frames: u32,
+}
+
+impl Framebuffer {
+    /// Parse "width=320,height=240,format=rgb565,prefix=frame"; every key is
+    /// optional.
+    pub fn parse(spec: &str) -> Framebuffer {
+        let (mut width, mut height) = (320, 240);
+        let mut format = Format::Xrgb8888;
+        let mut prefix = "frame".to_string();
+        for item in spec.split(',').filter(|s| !s.is_empty()) {
+            let mut kv = item.splitn(2, '=');
+            let key = kv.next().unwrap();
+            let value = match kv.next() {
+                Some(v) => v,
+                None => panic!("Framebuffer option {} needs a value", key),
+            };
+            match key {
+                "width" => width = parse_size(key, value),
+                "height" => height = parse_size(key, value),
+                "format" => {
+                    format = match value {
+                        "gray8" => Format::Gray8,
+                        "rgb565" => Format::Rgb565,
+                        "xrgb8888" => Format::Xrgb8888,
+                        _ => panic!("Unknown pixel format: {}", value),
+                    }
+                }
+                "prefix" => prefix = value.to_string(),
+                _ => panic!("Unknown framebuffer option: {}", key),
+            }
+        }
+        Framebuffer::new(width, height, format, prefix)
+    }
+
+    pub fn new(width: u32, height: u32, format: Format, prefix: String) -> Framebuffer {
+        let size = width as u64 * height as u64 * format.bytes() as u64;
+        if size > FB_MAX_SIZE {
+            panic!("Framebuffer of {}x{} pixels is too large", width, height);
+        }
+        Framebuffer { width, height, format, prefix, pixels: vec![0; size as usize], frames: 0 }
+    }
+
+    pub(crate) fn size(&self) -> u32 {
+        self.pixels.len() as u32
+    }
+
+    pub(crate) fn read_pixels(&self, offset: u32, width: u32) -> u32 {
+        let mut value = 0;
+        for i in 0..width {
+            value |= (self.pixels[(offset + i) as usize] as u32) << (8 * i);
+        }
+        value
+    }
+
+    pub(crate) fn write_pixels(&mut self, offset: u32, value: u32, width: u32) {
+        for i in 0..width {
+            self.pixels[(offset + i) as usize] = (value >> (8 * i)) as u8;
+        }
+    }
+
+    // Only aligned word accesses reach the registers.
+    pub(crate) fn read_register(&self, offset: u32, width: u32) -> Option<u32> {
+        if width != 4 || !offset.is_multiple_of(4) {
+            return None;
+        }
+        match offset {
+            0x0 => Some(self.frames),
+            0x4 => Some(self.width),
+            0x8 => Some(self.height),
+            0xc => Some(8 * self.format.bytes()),
+            _ => Some(0),
+        }
+    }
+
+    pub(crate) fn write_register(&mut self, offset: u32, value: u32, width: u32) -> Option<()> {
+        if width != 4 || !offset.is_multiple_of(4) {
+            return None;
+        }
+        if offset == 0 && value != 0 {
+            self.dump();
+        }
+        Some(())
+    }
+
+    // The name of the file frame number `frame` is written to.
+    pub fn frame_path(&self, frame: u32) -> String {
+        format!("{}{:04}.ppm", self.prefix, frame)
+    }
+
+    fn dump(&mut self) {
+        let mut data = Vec::new();
+        let magic = if self.format == Format::Gray8 { "P5" } else { "P6" };
+        data.extend_from_slice(format!("{}\n{} {}\n255\n", magic, self.width, self.height).as_bytes());
+        let bytes = self.format.bytes();
+        for offset in (0..self.size()).step_by(bytes as usize) {
+            let pixel = self.read_pixels(offset, bytes);
+            match self.format {
+                Format::Gray8 => data.push(pixel as u8),
+                Format::Rgb565 => {
+                    // widen each channel so that full scale stays full scale
+                    let (r, g, b) = ((pixel >> 11) & 0x1f, (pixel >> 5) & 0x3f, pixel & 0x1f);
+                    data.extend_from_slice(&[(r << 3 | r >> 2) as u8, (g << 2 | g >> 4) as u8,
+                                             (b << 3 | b >> 2) as u8]);
+                }
+                Format::Xrgb8888 => {
+                    data.extend_from_slice(&[(pixel >> 16) as u8, (pixel >> 8) as u8, pixel as u8]);
+                }
+            }
+        }
+        let path = self.frame_path(self.frames);
+        let mut f = match File::create(&path) {
+            Ok(file) => file,
+            Err(err) => panic!("File open error: {:?}", err),
+        };
+        f.write_all(&data).expect("File write error");
+        self.frames += 1;
+    }
+}
+
+fn parse_size(key: &str, value: &str) -> u32 {
+    match value.parse::<u32>() {
+        Ok(n) if n > 0 => n,
+        _ => panic!("Framebuffer option {} must be a positive number: {}", key, value),
+    }
+}
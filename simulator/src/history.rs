@@ -0,0 +1,414 @@
+// Execution history for reverse debugging.
+//
+// Every retired instruction leaves a record of the registers and memory
+// it overwrote, so it can be undone exactly.  The log is bounded; to
+// reach further back, full snapshots are taken every `interval` instructions
+// and the machine is restored from the nearest one and re-executed forward.
+// Snapshots leave out the framebuffer pixels and disk sectors, which can be
+// large; instead the bus saves the old contents of the parts that change
+// between one snapshot and the next, see Payloads in bus.rs.
+//
+// Time is counted in instructions retired since history started, not in
+// instret, which the program can write.
+//
+// Only architectural state is rewound.  The observers (caches, predictors,
+// the other models and tools, watchpoints and retire hooks) are not, and
+// they are detached while history is replayed.
+
+use std::collections::VecDeque;
+use std::mem;
+
+use bus::{Bus, Devices, Payloads};
+use csr::Csr;
+use State;
+
+// Stores to memory are undone by putting the old bytes back directly.  A
+// store that does more, to tohost, the CLINT or a device register, also
+// saves the device state before it, which is restored as a whole; the words
+// a disk read writes by DMA are recorded as stores of their own.
+#[derive(Debug, Clone)]
+pub enum Write {
+    Reg { index: u32, old: u64, new: u64 },
+    Mem { addr: u32, width: u32, old: u32, new: u32 },
+    Csr { index: u32, old: u64, new: u64 },
+    // a word of the hart's program image
+    Image { addr: u32, old: u32, new: u32 },
+    Devices(Box<Devices>),
+}
+
+struct Record {
+    pc: u32,
+    privilege: u32,
+    cycle: u64,
+    mtime: u64,
+    is_exit: bool,
+    writes: Vec<Write>,
+}
+
+struct Snapshot {
+    address: u32,
+    register: [u64; 32],
+    privilege: u32,
+    csr: Csr,
+    bus: Bus,
+    // pixels and sectors as they were here, for those changed before the
+    // next snapshot
+    payloads: Payloads,
+    is_exit: bool,
+    instret: u64,
+    cycle: u64,
+    retired: u64,
+}
+
+pub struct History {
+    interval: u64,
+    limit: usize,
+    max_snapshots: usize,
+    // records of the most recent instructions, oldest first
+    log: VecDeque<Record>,
+    snapshots: VecDeque<Snapshot>,
+    current: Option<Record>,
+    // instructions retired since history started
+    retired: u64,
+}
+
+impl History {
+    fn new() -> History {
+        History {
+            interval: 100_000,
+            limit: 1_000_000,
+            max_snapshots: 32,
+            log: VecDeque::new(),
+            snapshots: VecDeque::new(),
+            current: None,
+            retired: 0,
+        }
+    }
+
+    pub fn record(&mut self, write: Write) {
+        if let Some(ref mut record) = self.current {
+            record.writes.push(write);
+        }
+    }
+
+    // The most recent write to register `index`, searching back from now.
+    // Returns the pc of the writer, how many instructions ago it retired and
+    // the write itself.
+    pub fn last_register_write(&self, index: u32) -> Option<(u32, usize, Write)> {
+        self.find(|w| match *w {
+            Write::Reg { index: i, .. } => i == index,
+            _ => false,
+        })
+    }
+
+    // The most recent store touching any byte in [addr, addr + len).
+    pub fn last_memory_write(&self, addr: u32, len: u32) -> Option<(u32, usize, Write)> {
+        self.find(|w| match *w {
+            Write::Mem { addr: a, width, .. } =>
+                a.wrapping_sub(addr) < len || addr.wrapping_sub(a) < width,
+            _ => false,
+        })
+    }
+
+    fn find<F: Fn(&Write) -> bool>(&self, matches: F) -> Option<(u32, usize, Write)> {
+        for (age, record) in self.log.iter().rev().enumerate() {
+            if let Some(w) = record.writes.iter().rev().find(|w| matches(w)) {
+                return Some((record.pc, age + 1, w.clone()));
+            }
+        }
+        None
+    }
+
+    // Number of instructions that can be undone without a snapshot.
+    pub fn depth(&self) -> usize {
+        self.log.len()
+    }
+}
+
+impl State {
+    // Called by step() before an instruction executes.
+    pub fn begin_record(&mut self) {
+        if self.history.is_none() {
+            return;
+        }
+        let (pc, privilege, cycle, is_exit) = (self.address, self.privilege, self.cycle, self.is_exit);
+        let mtime = self.bus.borrow().clint.mtime;
+        if let Some(ref mut history) = self.history {
+            history.current = Some(Record { pc, privilege, cycle, mtime, is_exit, writes: Vec::new() });
+        }
+    }
+
+    // Called by step() after an instruction retired.
+    pub fn end_record(&mut self) {
+        if self.history.is_none() {
+            return;
+        }
+        let mut history = match self.history.take() {
+            Some(h) => h,
+            None => return,
+        };
+        if let Some(record) = history.current.take() {
+            history.log.push_back(record);
+            if history.log.len() > history.limit {
+                history.log.pop_front();
+            }
+        }
+        history.retired += 1;
+        if history.retired.is_multiple_of(history.interval) {
+            self.snapshot(&mut history);
+        }
+        self.history = Some(history);
+    }
+
+    fn snapshot(&self, history: &mut History) {
+        let mut bus = self.bus.borrow_mut();
+        let payloads = bus.payloads.replace(Payloads::default()).unwrap_or_default();
+        if let Some(last) = history.snapshots.back_mut() {
+            last.payloads = payloads;
+        }
+        history.snapshots.push_back(Snapshot {
+            address: self.address,
+            register: self.register,
+            privilege: self.privilege,
+            csr: self.csr.clone(),
+            bus: bus.snapshot(),
+            payloads: Payloads::default(),
+            is_exit: self.is_exit,
+            instret: self.instret,
+            cycle: self.cycle,
+            retired: history.retired,
+        });
+        if history.snapshots.len() > history.max_snapshots {
+            history.snapshots.pop_front();
+        }
+    }
+
+    // Start recording history from the current state.
+    pub fn enable_history(&mut self) {
+        let mut history = History::new();
+        self.snapshot(&mut history);
+        self.history = Some(history);
+    }
+
+    // Undo the last retired instruction.  Returns false when the history
+    // does not reach back that far.
+    pub fn reverse_step(&mut self) -> bool {
+        let record = match self.history {
+            Some(ref mut history) if history.retired > 0 => match history.log.pop_back() {
+                Some(record) => {
+                    history.retired -= 1;
+                    record
+                }
+                None => {
+                    let target = history.retired - 1;
+                    return self.replay_to(target);
+                }
+            },
+            _ => return false,
+        };
+        // before the writes, which include any to minstret
+        self.instret -= 1;
+        for w in record.writes.iter().rev() {
+            match *w {
+                Write::Reg { index, old, .. } => self.register[index as usize] = old,
+                Write::Mem { addr, width, old, .. } => {
+                    // device registers come back with the Devices write
+                    self.bus.borrow_mut().poke(addr, old, width);
+                }
+                Write::Csr { index, old, .. } => self.restore_csr(index, old),
+                Write::Image { addr, old, .. } => self.write_instruction(addr, old),
+                Write::Devices(ref devices) => self.bus.borrow_mut().restore_devices(devices),
+            }
+        }
+        self.address = record.pc;
+        self.privilege = record.privilege;
+        self.cycle = record.cycle;
+        self.tlb.flush(None, None);
+        self.bus.borrow_mut().clint.mtime = record.mtime;
+        self.is_exit = record.is_exit;
+        self.unhandled = None;
+        true
+    }
+
+    // Restore the latest snapshot at or before `target` and re-execute up to
+    // it.  The undo log is rebuilt on the way, the models are left out.
+    fn replay_to(&mut self, target: u64) -> bool {
+        let mut history = match self.history.take() {
+            Some(h) => h,
+            None => return false,
+        };
+        if history.snapshots.front().is_none_or(|s| s.retired > target) {
+            self.history = Some(history);
+            return false;
+        }
+        // take the pixels and sectors back to the snapshot, newest changes
+        // first
+        {
+            let mut bus = self.bus.borrow_mut();
+            let payloads = bus.payloads.take().unwrap_or_default();
+            bus.undo_payloads(payloads);
+            while history.snapshots.back().is_some_and(|s| s.retired > target) {
+                history.snapshots.pop_back();
+                if let Some(last) = history.snapshots.back_mut() {
+                    bus.undo_payloads(mem::take(&mut last.payloads));
+                }
+            }
+        }
+        let snapshot = match history.snapshots.back() {
+            Some(s) => s,
+            None => return false,
+        };
+        self.address = snapshot.address;
+        self.register = snapshot.register;
+        self.privilege = snapshot.privilege;
+        self.csr = snapshot.csr.clone();
+        self.tlb.flush(None, None);
+        self.bus.borrow_mut().restore_snapshot(&snapshot.bus);
+        self.is_exit = snapshot.is_exit;
+        self.unhandled = None;
+        self.instret = snapshot.instret;
+        self.cycle = snapshot.cycle;
+        history.retired = snapshot.retired;
+        history.log.clear();
+        self.history = Some(history);
+
+        // the observers have seen these instructions already
+        let observers = mem::take(&mut self.observers);
+        self.quiet = true;
+        while self.history.as_ref().is_some_and(|h| h.retired < target) {
+            self.step();
+        }
+        self.quiet = false;
+        self.observers = observers;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use framebuffer::{Format, Framebuffer, FB_MEMORY};
+    use watch::{Watchpoint, Watchpoints};
+    use super::*;
+
+    // Counts up in x6 and stores every count to the first pixel word.
+    fn hart() -> State {
+        let program = vec![
+            0x2000_02b7, // lui t0, 0x20000
+            0x0000_0313, // li t1, 0
+            0x0013_0313, // loop: addi t1, t1, 1
+            0x0062_a023, // sw t1, 0(t0)
+            0xff9f_f06f, // j loop
+        ];
+        let state = State::init(program);
+        let fb = Framebuffer::new(4, 4, Format::Gray8, "frame".to_string());
+        state.bus.borrow_mut().framebuffer = Some(fb);
+        state
+    }
+
+    #[test]
+    fn replay_restores_pixels() {
+        let mut state = hart();
+        state.enable_history();
+        if let Some(ref mut history) = state.history {
+            history.interval = 10;
+            history.limit = 5;
+        }
+        for _ in 0..100 {
+            state.step();
+        }
+        if let Some(ref history) = state.history {
+            assert_eq!(history.snapshots.len(), 11);
+            for snapshot in history.snapshots.iter() {
+                assert!(snapshot.bus.framebuffer.as_ref().is_some_and(|fb| fb.pixels.is_empty()));
+            }
+        }
+        for &target in [93, 50, 37, 3].iter() {
+            while state.instret > target {
+                assert!(state.reverse_step());
+            }
+            let mut fresh = hart();
+            for _ in 0..target {
+                fresh.step();
+            }
+            assert_eq!(state.read_memory(FB_MEMORY, 4), fresh.read_memory(FB_MEMORY, 4));
+            assert_eq!(state.register(6), fresh.register(6));
+        }
+    }
+
+    #[test]
+    fn who_finds_the_last_writes() {
+        let mut state = hart();
+        state.enable_history();
+        for _ in 0..5 {
+            state.step();
+        }
+        let history = state.history.as_ref().unwrap();
+        // the addi in the loop, before the sw and the j
+        match history.last_register_write(6) {
+            Some((0x08, 3, Write::Reg { index: 6, old: 0, new: 1 })) => {}
+            other => panic!("{:?}", other),
+        }
+        match history.last_memory_write(FB_MEMORY + 2, 1) {
+            Some((0x0c, 2, Write::Mem { width: 4, old: 0, new: 1, .. })) => {}
+            other => panic!("{:?}", other),
+        }
+        assert!(history.last_register_write(7).is_none());
+        assert!(history.last_memory_write(FB_MEMORY + 4, 4).is_none());
+    }
+
+    #[test]
+    fn counter_writes_are_undone() {
+        let program = vec![
+            0x0640_0293, // li t0, 100
+            0xb022_9073, // csrw minstret, t0
+            0xb002_9073, // csrw mcycle, t0
+            0x0013_0313, // loop: addi t1, t1, 1
+            0xffdf_f06f, // j loop
+        ];
+        let mut state = State::init(program);
+        state.enable_history();
+        if let Some(ref mut history) = state.history {
+            history.interval = 4;
+            history.limit = 3;
+        }
+        let mut counters = vec![(state.instret, state.cycle)];
+        for _ in 0..20 {
+            state.step();
+            counters.push((state.instret, state.cycle));
+        }
+        assert_eq!(counters[3], (102, 101));
+        // through the log and then from snapshots, which are counted in
+        // instructions, not in instret
+        while let Some(expected) = counters.pop() {
+            assert_eq!((state.instret, state.cycle), expected);
+            assert_eq!(state.reverse_step(), !counters.is_empty());
+        }
+    }
+
+    #[test]
+    fn replay_keeps_watchpoints_added_later() {
+        let mut state = hart();
+        state.enable_history();
+        if let Some(ref mut history) = state.history {
+            history.interval = 10;
+            history.limit = 5;
+        }
+        for _ in 0..30 {
+            state.step();
+        }
+        // as the debugger's watch command does
+        let watch = Watchpoint::parse(&format!("{:#x}", FB_MEMORY)).unwrap();
+        state.observers.watch = Some(Watchpoints { list: vec![watch], hits: Vec::new() });
+        for _ in 0..20 {
+            assert!(state.reverse_step());
+        }
+        assert_eq!(state.instret, 10);
+        assert_eq!(state.register(6), 3);
+        // the replayed stores did not fire it, the next one does
+        assert!(state.watch_hits().is_empty());
+        while state.watch_hits().is_empty() {
+            state.step();
+        }
+        assert_eq!(state.read_memory(FB_MEMORY, 4), Some(4));
+    }
+}
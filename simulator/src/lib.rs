@@ -0,0 +1,69 @@
+//! ksim, a simulator for RV32IMA and RV64IMA with M, S and U privilege
+//! modes, Sv32 paging on RV32 and PMP, plus the custom print_int and exit
+//! instructions understood by kasm.  A machine can have several harts sharing one bus,
+//! see `Machine`.
+//!
+//! ```no_run
+//! extern crate ksim;
+//!
+//! let image = ksim::read_image("test.bin").unwrap();
+//! let mut state = ksim::State::init(image);
+//! state.capture_output();
+//! state.on_retire(|_, r| println!("{:#010x}", r.pc));
+//! state.run(Some(1_000_000));
+//! println!("a0 = {}", state.register(10));
+//! ```
+
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::fs::File;
+
+pub mod abi;
+pub mod bpred;
+mod block;
+pub mod bus;
+pub mod cache;
+pub mod checkpoint;
+pub mod costs;
+pub mod cosim;
+pub mod coverage;
+pub mod csr;
+pub mod debug;
+mod decode;
+pub mod disasm;
+pub mod disk;
+pub mod elf;
+pub mod framebuffer;
+mod history;
+mod machine;
+mod mmu;
+pub mod pipeline;
+mod pmp;
+pub mod profile;
+pub mod sanitize;
+mod state;
+pub mod stats;
+pub mod symbols;
+pub mod trap;
+pub mod vcd;
+pub mod watch;
+
+pub use machine::Machine;
+pub use state::{Limits, Observers, Retired, State, Stop};
+
+/// Read a program image in kasm's output format: one instruction per line,
+/// written as 32 binary digits.
+pub fn read_image(path: &str) -> io::Result<Vec<u32>> {
+    let f = BufReader::new(File::open(path)?);
+    let mut instructions = Vec::new();
+    for line in f.lines() {
+        let l = line?;
+        match u32::from_str_radix(l.trim(), 2) {
+            Ok(instr) => instructions.push(instr),
+            Err(_) => return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                                format!("not a binary instruction: {}", l))),
+        }
+    }
+    Ok(instructions)
+}
@@ -0,0 +1,277 @@
+// A machine with one or more harts sharing a bus.
+//
+// Every hart has its own registers, pc, CSRs and models (caches, branch
+// predictor and so on) and starts at address 0, or at the entry point of an
+// ELF program, with its hart ID in mhartid and a0.  The harts are RV32 unless
+// set_xlen() says otherwise or the ELF program is a 64-bit one.  The harts take turns
+// round-robin, each running `quantum` instructions at a time, so a run is
+// fully deterministic.
+//
+// The exit instruction ends the whole machine, like exit() in a
+// multithreaded process, and so does a store to tohost.  A hart that runs off the end of the program only
+// stops itself.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::Instant;
+
+use abi::AbiChecker;
+use bus::{Bus, RAM_SIZE};
+use disk::Disk;
+use elf::Elf;
+use framebuffer::Framebuffer;
+use sanitize::Sanitizer;
+use state::{Limits, Stop, TIME_CHECK};
+use symbols::Symbols;
+use State;
+
+pub struct Machine {
+    pub harts: Vec<State>,
+    // instructions a hart runs before the next one gets its turn
+    quantum: u64,
+}
+
+impl Machine {
+    /// A machine with `harts` harts all running the given program image.
+    pub fn new(instructions: Vec<u32>, harts: usize) -> Machine {
+        Machine::with_memory(instructions, harts, RAM_SIZE)
+    }
+
+    /// Like `new`, with `ram_size` bytes of data RAM instead of the default.
+    pub fn with_memory(instructions: Vec<u32>, harts: usize, ram_size: u32) -> Machine {
+        let bus = Bus::new(harts, ram_size);
+        Machine::with_bus(instructions, harts, bus, 0)
+    }
+
+    /// A machine running an ELF program.  Its segments are loaded into both
+    /// the program image and the data RAM, which start at the page of the
+    /// lowest segment, and the harts start at the entry point.  A store to
+    /// the program changes the code the storing hart fetches; other harts
+    /// see the change after a fence.i.  The RAM has at least `ram_size`
+    /// bytes, more if the segments need them.  A store to the program's
+    /// `tohost` symbol ends the run, see bus.rs.
+    pub fn from_elf(elf: &Elf, harts: usize, ram_size: u32) -> Machine {
+        let (start, end) = match elf.extent() {
+            Some(extent) => extent,
+            None => panic!("ELF file has nothing to load"),
+        };
+        let base = start & !0xfff;
+        let size = (end - base as u64 + 3) & !3;
+        if base as u64 + size > 1 << 32 {
+            panic!("ELF segments reach past the end of the address space");
+        }
+        let mut bytes = vec![0; size as usize];
+        for segment in elf.segments.iter() {
+            let offset = (segment.addr - base) as usize;
+            bytes[offset..offset + segment.data.len()].copy_from_slice(&segment.data);
+        }
+        let image: Vec<u32> = bytes.chunks(4)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]])).collect();
+
+        let mut bus = Bus::new(harts, ram_size.max(size as u32));
+        bus.ram_base = base;
+        bus.ram[..image.len()].copy_from_slice(&image);
+        bus.preloaded = size as u32;
+        bus.tohost = elf.symbols.address("tohost");
+        let mut machine = Machine::with_bus(image, harts, bus, base);
+        machine.set_xlen(elf.xlen);
+        for hart in machine.harts.iter_mut() {
+            hart.address = elf.entry;
+            hart.code_in_ram = true;
+        }
+        machine
+    }
+
+    fn with_bus(image: Vec<u32>, harts: usize, bus: Bus, image_base: u32) -> Machine {
+        let bus = Rc::new(RefCell::new(bus));
+        let harts = (0..harts).map(|i| {
+            let mut hart = State::with_bus(image.clone(), bus.clone(), i as u32);
+            hart.image_base = image_base;
+            if harts > 1 {
+                hart.label = format!("[hart {}] ", i);
+            }
+            hart
+        }).collect();
+        Machine { harts, quantum: 1 }
+    }
+
+    /// Make every hart an RV32 or an RV64 one, see State::set_xlen().
+    pub fn set_xlen(&mut self, xlen: u32) {
+        for hart in self.harts.iter_mut() {
+            hart.set_xlen(xlen);
+        }
+    }
+
+    /// True once a hart has executed exit or all of them have stopped.
+    pub fn is_exit(&self) -> bool {
+        self.harts.iter().any(|h| h.is_exit) || self.harts.iter().all(|h| h.is_exit())
+    }
+
+    /// Check every memory access of every hart, see sanitize.rs.  Reports
+    /// name the functions in `symbols`.
+    pub fn sanitize(&mut self, symbols: &Symbols) {
+        self.harts[0].bus.borrow_mut().track_writes();
+        for hart in self.harts.iter_mut() {
+            let mut sanitizer = Sanitizer::new(symbols.clone());
+            sanitizer.label = hart.label.clone();
+            hart.observers.sanitizer = Some(sanitizer);
+        }
+    }
+
+    /// Check the calling convention at every return of every hart, see
+    /// abi.rs.  Reports name the functions in `symbols`.
+    pub fn check_abi(&mut self, symbols: &Symbols) {
+        for hart in self.harts.iter_mut() {
+            let mut checker = AbiChecker::new(symbols.clone());
+            checker.label = hart.label.clone();
+            hart.observers.abi = Some(checker);
+        }
+    }
+
+    /// Set the number of instructions a hart runs before the next one gets
+    /// its turn, 1 by default.  It must be at least 1.
+    pub fn set_quantum(&mut self, quantum: u64) {
+        assert!(quantum > 0, "The quantum must be at least 1 instruction");
+        self.quantum = quantum;
+    }
+
+    /// Attach a framebuffer to the bus, see framebuffer.rs.  One restored
+    /// from a checkpoint keeps its pixels and frame count, and must have
+    /// the same size and format.
+    pub fn set_framebuffer(&mut self, mut framebuffer: Framebuffer) {
+        let mut bus = self.harts[0].bus.borrow_mut();
+        if let Some(old) = bus.framebuffer.take() {
+            if (old.width, old.height, old.format)
+                != (framebuffer.width, framebuffer.height, framebuffer.format) {
+                panic!("The framebuffer does not match the checkpoint's {}x{} {:?}",
+                       old.width, old.height, old.format);
+            }
+            framebuffer.pixels = old.pixels;
+            framebuffer.frames = old.frames;
+        }
+        bus.framebuffer = Some(framebuffer);
+    }
+
+    /// Attach a block device to the bus, see disk.rs.  One restored from a
+    /// checkpoint keeps its registers.
+    pub fn set_disk(&mut self, mut disk: Disk) {
+        let mut bus = self.harts[0].bus.borrow_mut();
+        if let Some(old) = bus.disk.take() {
+            disk.set_registers(old.registers());
+        }
+        bus.disk = Some(disk);
+    }
+
+    /// True if a sanitizer has reported a problem.
+    pub fn sanitizer_failed(&self) -> bool {
+        self.harts.iter()
+            .any(|h| h.observers.sanitizer.as_ref().is_some_and(|s| !s.reports.is_empty()))
+    }
+
+    /// True if the calling-convention checker has reported a problem.
+    pub fn abi_failed(&self) -> bool {
+        self.harts.iter().any(|h| h.observers.abi.as_ref().is_some_and(|a| !a.reports.is_empty()))
+    }
+
+    /// The code the program stored to tohost, 0 for success, if it did.
+    pub fn host_exit(&self) -> Option<u32> {
+        self.harts[0].bus.borrow().host_exit
+    }
+
+    /// Instructions retired by all harts together.
+    pub fn instret(&self) -> u64 {
+        self.harts.iter().map(|h| h.instret()).sum()
+    }
+
+    /// Run until the program exits, `limit` instructions have been executed
+    /// by all harts together or a watchpoint fires.
+    pub fn run(&mut self, limit: Option<u64>) -> Stop {
+        self.run_with(&Limits { instructions: limit, ..Limits::default() })
+    }
+
+    /// Run until the program exits, a watchpoint fires or one of the limits
+    /// is hit.  The instruction limit counts the instructions of all harts
+    /// together.  An infinite loop is only reported once every hart that has
+    /// not stopped is stuck in one.
+    pub fn run_with(&mut self, limits: &Limits) -> Stop {
+        let start = Instant::now();
+        let mut executed = 0;
+        let mut next_check = 0;
+        let mut last_loop = vec![None; self.harts.len()];
+        let mut stuck = vec![false; self.harts.len()];
+        let mut current = 0;
+        let mut turn = 0;
+        while !self.is_exit() {
+            if limits.instructions == Some(executed) {
+                return Stop::Limit;
+            }
+            // checking the clock on every instruction would be too slow
+            if let Some(timeout) = limits.timeout {
+                if executed >= next_check {
+                    if start.elapsed() >= timeout {
+                        return Stop::Timeout;
+                    }
+                    next_check = executed + TIME_CHECK;
+                }
+            }
+            if self.harts[current].is_exit() {
+                turn = self.quantum;
+            } else {
+                // the rest of this hart's turn, up to the next limit check;
+                // a single hart never has to give way
+                let mut n = if self.harts.len() == 1 { u64::MAX } else { self.quantum - turn };
+                if let Some(limit) = limits.instructions {
+                    n = n.min(limit - executed);
+                }
+                if limits.timeout.is_some() {
+                    n = n.min(next_check - executed);
+                }
+                let hart = &mut self.harts[current];
+                let (count, stop) = hart.run_some(n, limits.detect_loops, &mut last_loop[current]);
+                executed += count;
+                turn += count;
+                match stop {
+                    Some(Stop::InfiniteLoop(pc)) => {
+                        // nothing can get a hart out of such a loop, but the
+                        // others may still have work to do
+                        stuck[current] = true;
+                        if self.harts.iter().zip(&stuck).all(|(h, &s)| s || h.is_exit()) {
+                            return Stop::InfiniteLoop(pc);
+                        }
+                    }
+                    Some(stop) => return stop,
+                    None => {}
+                }
+            }
+            if turn >= self.quantum {
+                turn = 0;
+                current = (current + 1) % self.harts.len();
+            }
+        }
+        self.harts.iter().find_map(|h| h.stopped()).unwrap_or(Stop::Exit)
+    }
+
+    /// Print the cache, branch predictor, cost model and pipeline reports of
+    /// every hart.
+    pub fn report(&self) {
+        if self.harts.len() == 1 {
+            self.harts[0].report();
+            return;
+        }
+        for (i, hart) in self.harts.iter().enumerate() {
+            let o = &hart.observers;
+            if o.has_timing() || o.sanitizer.is_some() || o.abi.is_some() {
+                eprintln!("hart {}:", i);
+                hart.report();
+            }
+        }
+    }
+}
+
+impl From<State> for Machine {
+    /// A single-hart machine around an existing hart, e.g. a restored
+    /// checkpoint.
+    fn from(state: State) -> Machine {
+        Machine { harts: vec![state], quantum: 1 }
+    }
+}
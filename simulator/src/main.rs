@@ -1,155 +1,558 @@
-use std::io::prelude::*;
-use std::io::BufReader;
-use std::fs::File;
+extern crate ksim;
+
 use std::env;
+use std::fs::File;
+use std::io::prelude::*;
+use std::process;
+use std::time::Duration;
+
+use ksim::bpred::Predictor;
+use ksim::cache::{Cache, CacheConfig};
+use ksim::bus::RAM_SIZE;
+use ksim::checkpoint;
+use ksim::costs::CostModel;
+use ksim::cosim::Cosim;
+use ksim::coverage::{Coverage, LineMap};
+use ksim::debug;
+use ksim::disk::Disk;
+use ksim::elf::{self, Elf};
+use ksim::framebuffer::Framebuffer;
+use ksim::pipeline::Pipeline;
+use ksim::profile::Profiler;
+use ksim::stats::Stats;
+use ksim::symbols::Symbols;
+use ksim::vcd::Vcd;
+use ksim::watch::{Watchpoint, Watchpoints};
+use ksim::{Limits, Machine, Stop};
 
-struct State {
-    address: u32,
-    register: [u32; 32],
-    dmem: Vec<u32>,
-    imem: Vec<u32>,
-    is_exit: bool,
+// exit statuses when a run is cut short; 1 is used for usage errors
+const EXIT_INSTRUCTION_LIMIT: i32 = 2;
+const EXIT_TIMEOUT: i32 = 3;
+const EXIT_INFINITE_LOOP: i32 = 4;
+const EXIT_CHECK_FAILED: i32 = 5;
+const EXIT_DIVERGED: i32 = 6;
+const EXIT_EXCEPTION: i32 = 7;
+const EXIT_TEST_FAILED: i32 = 8;
+const EXIT_HOST_REQUEST: i32 = 9;
+
+fn usage(program: &str) -> ! {
+    eprintln!("Usage: {} [options] <filename>", program);
+    eprintln!("       {} [options] --restore <checkpoint>", program);
+    eprintln!("Options:");
+    eprintln!("  --xlen <32|64>   simulate RV32 or RV64 harts (default 32, or the class of");
+    eprintln!("                   an ELF program)");
+    eprintln!("  --harts <n>      simulate n harts sharing memory (default 1)");
+    eprintln!("  --quantum <n>    instructions a hart runs before the next one (default 1)");
+    eprintln!("  --memory <bytes> size of the data RAM (default 4096)");
+    eprintln!("  --blocks         execute a basic block at a time, for speed");
+    eprintln!("  --framebuffer <spec>  attach a framebuffer that is dumped to PPM files");
+    eprintln!("  --disk <file>    attach a block device backed by the image file");
+    eprintln!("  --icache <spec>  simulate an L1 instruction cache");
+    eprintln!("  --dcache <spec>  simulate an L1 data cache");
+    eprintln!("  --bpred <spec>   simulate a branch predictor");
+    eprintln!("  --costs <file>   charge cycles and energy per instruction class from a table");
+    eprintln!("  --pipeline <spec>  time the run on an in-order or out-of-order core model and");
+    eprintln!("                     report IPC and stall reasons (latencies from --costs)");
+    eprintln!("  --symbols <file> read a symbol table written by kasm --symbols");
+    eprintln!("  --profile <file> write per-symbol and per-PC instruction counts");
+    eprintln!("  --folded <file>  write folded call stacks for flamegraph tools");
+    eprintln!("  --stats          print instruction statistics at exit");
+    eprintln!("  --stats-json <file>  write instruction statistics as JSON");
+    eprintln!("  --line-map <file>    read a line map written by kasm --line-map");
+    eprintln!("  --coverage <file>    write line and branch coverage as an lcov tracefile");
+    eprintln!("  --coverage-listing <file>  write the source annotated with execution counts");
+    eprintln!("  --vcd <file>         write the pc, registers, CSRs and memory transactions of");
+    eprintln!("                       every retired instruction as a Value Change Dump");
+    eprintln!("  --cosim <trace>      compare every retired instruction with a reference");
+    eprintln!("                       commit trace and stop where they differ (exit status 6)");
+    eprintln!("  --signature <file>   write the words from begin_signature to end_signature");
+    eprintln!("                       of an ELF program to file when the run stops");
+    eprintln!("  --checkpoint <file>  save the machine state when the run stops");
+    eprintln!("  --checkpoint-at <n>  stop after n instructions (use with --checkpoint)");
+    eprintln!("  --restore <file>     resume from a checkpoint instead of a program");
+    eprintln!("  --sanitize       report reads of unwritten memory, accesses below sp and");
+    eprintln!("                   misaligned accesses (exit status 5 if there were any)");
+    eprintln!("  --check-abi      report functions that return with sp or s0-s11 changed or");
+    eprintln!("                   not to their call site (exit status 5 if there were any)");
+    eprintln!("  --debug          run under the interactive debugger");
+    eprintln!("  --watch <spec>   stop when a watchpoint fires; spec is a register name");
+    eprintln!("                   or <addr>[:<len>][:r|w|rw] (default 4 bytes, w)");
+    eprintln!("  --max-instructions <n>  give up after n instructions (exit status 2)");
+    eprintln!("  --timeout <seconds>     give up after this much wall-clock time (exit status 3)");
+    eprintln!("  --no-loop-detection     do not stop at a jump-to-self that changes nothing");
+    eprintln!("                          (otherwise such a loop ends the run with exit status 4)");
+    eprintln!("Cache spec: comma separated key=value pairs");
+    eprintln!("  size=<bytes>,assoc=<ways>,line=<bytes>,repl=lru|fifo|random,");
+    eprintln!("  write=wb|wt,alloc=1|0,latency=<miss cycles>");
+    eprintln!("Framebuffer spec: comma separated key=value pairs");
+    eprintln!("  width=<pixels>,height=<pixels>,format=gray8|rgb565|xrgb8888,prefix=<path>");
+    eprintln!("Branch predictor spec: nt|btfn|bimodal|gshare followed by");
+    eprintln!("  ,bits=<table index bits>,ras=<return stack entries>,penalty=<cycles>");
+    eprintln!("Pipeline spec: ooo|inorder followed by ,width=<issue width>,rob=<entries>,");
+    eprintln!("  rs=<reservation stations>,lsq=<entries>,regs=<physical registers>,");
+    eprintln!("  depth=<front-end stages>");
+    eprintln!("Cost table: lines of <class> <cycles> [<energy>], classes alu, mul, div, load,");
+    eprintln!("  store, branch-taken, branch-not-taken, jump, csr, atomic and other");
+    eprintln!("An exception with no trap handler to go to ends the run with exit status 7.");
+    eprintln!("A test that reports failure through tohost ends it with exit status 8,");
+    eprintln!("and any other request through tohost with exit status 9.");
+    process::exit(1);
 }
 
-impl State {
-    fn init(instructions: Vec<u32>) -> State {
-        State {
-            address: 0,
-            register: [0; 32],
-            dmem: vec![0; 1024],
-            imem: instructions,
-            is_exit: false,
-        }
+// Write the signature region of an architecture test, one word per line in
+// hex, the format the riscv-arch-test framework compares.
+fn write_signature(machine: &Machine, path: &str, begin: u32, end: u32) {
+    let mut text = String::new();
+    for addr in (begin..end).step_by(4) {
+        let word = match machine.harts[0].read_memory(addr, 4) {
+            Some(word) => word,
+            None => panic!("Signature address {:#010x} is not in memory", addr),
+        };
+        text.push_str(&format!("{:08x}\n", word));
     }
-    fn show_register(&self) {
-        for (i, r) in self.register.iter().enumerate() {
-            println!("reg{:02}: {:032b}", i, r);
-        }
+    let mut f = match File::create(path) {
+        Ok(file) => file,
+        Err(err) => panic!("File open error: {:?}", err),
+    };
+    f.write_all(text.as_bytes()).expect("File write error");
+}
+
+// The value following the option at args[i].
+fn option_value(args: &[String], i: usize) -> String {
+    if i + 1 >= args.len() {
+        usage(&args[0]);
     }
+    args[i + 1].clone()
+}
 
-    fn is_exit(&self) -> bool {
-        if self.is_exit == true{
-            return true;
-        }
-        let addr = (self.address / 4) as usize;
-        if addr >= self.imem.len() {
-            true
-        } else {
-            false
+fn main() {
+    let args: Vec<_> = env::args().collect();
+    let mut filename = None;
+    let mut icache = None;
+    let mut dcache = None;
+    let mut bpred = None;
+    let mut costs = None;
+    let mut pipeline = None;
+    let mut symbols = None;
+    let mut profile_file = None;
+    let mut folded_file = None;
+    let mut print_stats = false;
+    let mut stats_file = None;
+    let mut line_map_file = None;
+    let mut coverage_file = None;
+    let mut listing_file = None;
+    let mut vcd_file = None;
+    let mut trace_file = None;
+    let mut signature_file = None;
+    let mut checkpoint_file = None;
+    let mut checkpoint_at = None;
+    let mut restore_file = None;
+    let mut debug = false;
+    let mut sanitize = false;
+    let mut check_abi = false;
+    let mut watchpoints = Vec::new();
+    let mut max_instructions = None;
+    let mut timeout = None;
+    let mut detect_loops = true;
+    let mut xlen = None;
+    let mut harts = 1;
+    let mut quantum = 1;
+    let mut memory = None;
+    let mut blocks = false;
+    let mut framebuffer = None;
+    let mut disk = None;
+    let mut i = 1;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--xlen" => {
+                match option_value(&args, i).parse::<u32>() {
+                    Ok(n) if n == 32 || n == 64 => xlen = Some(n),
+                    _ => usage(&args[0]),
+                }
+                i += 1;
+            }
+            "--harts" => {
+                match option_value(&args, i).parse::<usize>() {
+                    Ok(n) if n > 0 => harts = n,
+                    _ => usage(&args[0]),
+                }
+                i += 1;
+            }
+            "--quantum" => {
+                match option_value(&args, i).parse::<u64>() {
+                    Ok(n) if n > 0 => quantum = n,
+                    _ => usage(&args[0]),
+                }
+                i += 1;
+            }
+            "--memory" => {
+                match option_value(&args, i).parse::<u32>() {
+                    Ok(n) if n > 0 && n.is_multiple_of(4) => memory = Some(n),
+                    _ => usage(&args[0]),
+                }
+                i += 1;
+            }
+            "--blocks" => blocks = true,
+            "--framebuffer" => {
+                framebuffer = Some(Framebuffer::parse(&option_value(&args, i)));
+                i += 1;
+            }
+            "--disk" => {
+                disk = Some(Disk::open(&option_value(&args, i)));
+                i += 1;
+            }
+            "--icache" => {
+                match CacheConfig::parse(&option_value(&args, i)) {
+                    Ok(config) => icache = Some(config),
+                    Err(message) => {
+                        eprintln!("{}", message);
+                        usage(&args[0]);
+                    }
+                }
+                i += 1;
+            }
+            "--dcache" => {
+                match CacheConfig::parse(&option_value(&args, i)) {
+                    Ok(config) => dcache = Some(config),
+                    Err(message) => {
+                        eprintln!("{}", message);
+                        usage(&args[0]);
+                    }
+                }
+                i += 1;
+            }
+            "--bpred" => {
+                bpred = Some(option_value(&args, i));
+                i += 1;
+            }
+            "--pipeline" => {
+                pipeline = Some(Pipeline::parse(&option_value(&args, i)));
+                i += 1;
+            }
+            "--costs" => {
+                costs = Some(CostModel::load(&option_value(&args, i)));
+                i += 1;
+            }
+            "--symbols" => {
+                symbols = Some(Symbols::load(&option_value(&args, i)));
+                i += 1;
+            }
+            "--profile" => {
+                profile_file = Some(option_value(&args, i));
+                i += 1;
+            }
+            "--folded" => {
+                folded_file = Some(option_value(&args, i));
+                i += 1;
+            }
+            "--stats" => print_stats = true,
+            "--line-map" => {
+                line_map_file = Some(option_value(&args, i));
+                i += 1;
+            }
+            "--cosim" => {
+                trace_file = Some(option_value(&args, i));
+                i += 1;
+            }
+            "--vcd" => {
+                vcd_file = Some(option_value(&args, i));
+                i += 1;
+            }
+            "--coverage" => {
+                coverage_file = Some(option_value(&args, i));
+                i += 1;
+            }
+            "--coverage-listing" => {
+                listing_file = Some(option_value(&args, i));
+                i += 1;
+            }
+            "--stats-json" => {
+                stats_file = Some(option_value(&args, i));
+                i += 1;
+            }
+            "--signature" => {
+                signature_file = Some(option_value(&args, i));
+                i += 1;
+            }
+            "--checkpoint" => {
+                checkpoint_file = Some(option_value(&args, i));
+                i += 1;
+            }
+            "--checkpoint-at" => {
+                match option_value(&args, i).parse::<u64>() {
+                    Ok(n) => checkpoint_at = Some(n),
+                    Err(_) => usage(&args[0]),
+                }
+                i += 1;
+            }
+            "--restore" => {
+                restore_file = Some(option_value(&args, i));
+                i += 1;
+            }
+            "--debug" => debug = true,
+            "--sanitize" => sanitize = true,
+            "--check-abi" => check_abi = true,
+            "--watch" => {
+                match Watchpoint::parse(&option_value(&args, i)) {
+                    Some(w) => watchpoints.push(w),
+                    None => usage(&args[0]),
+                }
+                i += 1;
+            }
+            "--max-instructions" => {
+                match option_value(&args, i).parse::<u64>() {
+                    Ok(n) => max_instructions = Some(n),
+                    Err(_) => usage(&args[0]),
+                }
+                i += 1;
+            }
+            "--timeout" => {
+                match option_value(&args, i).parse::<f64>() {
+                    Ok(t) => match Duration::try_from_secs_f64(t) {
+                        Ok(t) => timeout = Some(t),
+                        Err(_) => usage(&args[0]),
+                    },
+                    Err(_) => usage(&args[0]),
+                }
+                i += 1;
+            }
+            "--no-loop-detection" => detect_loops = false,
+            s if s.starts_with("--") => usage(&args[0]),
+            s => filename = Some(s.to_string()),
         }
+        i += 1;
     }
 
-    fn step(&mut self) {
-        let addr = (self.address / 4) as usize;
-        if addr >= self.imem.len() {
-            panic!("PC exceeded the length of instructions!");
+    if harts > 1 {
+        let single = [("--debug", debug), ("--checkpoint", checkpoint_file.is_some()),
+                      ("--checkpoint-at", checkpoint_at.is_some()),
+                      ("--restore", restore_file.is_some()),
+                      ("--profile", profile_file.is_some()), ("--folded", folded_file.is_some()),
+                      ("--stats", print_stats), ("--stats-json", stats_file.is_some()),
+                      ("--coverage", coverage_file.is_some()),
+                      ("--coverage-listing", listing_file.is_some()),
+                      ("--vcd", vcd_file.is_some()), ("--cosim", trace_file.is_some())];
+        for &(option, used) in single.iter() {
+            if used {
+                eprintln!("{} only works with a single hart", option);
+                process::exit(1);
+            }
         }
-        let instruction = self.imem[addr];
-        let opcode = instruction & 0x7f;
-        match opcode {
-            0b0000011 => self.exec_load(instruction),
-            0b0010011 => self.exec_op_imm(instruction),
-            0b0100011 => self.exec_store(instruction),
-            0b0110011 => self.exec_op(instruction),
-            0b0110111 => self.exec_lui(instruction),
-            0b1100011 => self.exec_branch(instruction),
-            0b1100111 => self.exec_jalr(instruction),
-            0b1101111 => self.exec_jal(instruction),
-            0b0001011 => self.exec_custom(instruction),
-            _         => panic!("Unsupported instruction"),
+    }
+
+    // the debugger steps backwards, and neither a reference trace nor a
+    // waveform can follow it
+    let replayed = [("--cosim", trace_file.is_some()), ("--vcd", vcd_file.is_some())];
+    for &(option, used) in replayed.iter() {
+        if debug && used {
+            eprintln!("{} does not work with --debug", option);
+            process::exit(1);
         }
     }
 
-    fn exec_custom(&mut self, instruction: u32) {
-        let funct3 = (instruction & 0x7000) >> 12;
-        let rd =     (instruction & 0xf80) >> 7;
-        match funct3 {
-            0b000 => {
-                self.is_exit = true;
-                println!("Exit.");
+    // the signature region of an ELF program
+    let mut signature = None;
+    let mut machine = match (restore_file, filename) {
+        (Some(path), _) => Machine::from(checkpoint::restore(&path)),
+        (None, Some(filename)) if elf::is_elf(&filename) => match Elf::read(&filename) {
+            Ok(program) => {
+                let found = &program.symbols;
+                if let (Some(begin), Some(end)) = (found.address("begin_signature"),
+                                                   found.address("end_signature")) {
+                    signature = Some((begin, end));
+                }
+                let machine = Machine::from_elf(&program, harts, memory.unwrap_or(RAM_SIZE));
+                // the program's own symbols, unless a table was given
+                if symbols.is_none() {
+                    symbols = Some(program.symbols);
+                }
+                machine
             }
-            0b001 => {
-                println!("print_int: {}", self.register[rd as usize]);
-                self.address += 4;
+            Err(err) => panic!("File open error: {:?}", err),
+        },
+        (None, Some(filename)) => match ksim::read_image(&filename) {
+            Ok(instructions) => {
+                let mut machine = match memory {
+                    Some(bytes) => Machine::with_memory(instructions, harts, bytes),
+                    None => Machine::new(instructions, harts),
+                };
+                if let Some(xlen) = xlen {
+                    machine.set_xlen(xlen);
+                }
+                machine
             }
-            _ => {
-                panic!("Unknown custom command!");
+            Err(err) => panic!("File open error: {:?}", err),
+        },
+        (None, None) => usage(&args[0]),
+    };
+    // ELF programs and checkpoints say what they are
+    if let Some(xlen) = xlen.filter(|&x| x != machine.harts[0].xlen()) {
+        eprintln!("--xlen {} does not match the program, which is RV{}", xlen, machine.harts[0].xlen());
+        process::exit(1);
+    }
+    let symbols = symbols.unwrap_or_default();
+    if signature_file.is_some() && signature.is_none() {
+        eprintln!("--signature needs an ELF program with begin_signature and end_signature symbols");
+        process::exit(1);
+    }
+    let line_map = line_map_file.map(|path| LineMap::load(&path));
+    if (coverage_file.is_some() || listing_file.is_some()) && line_map.is_none() {
+        eprintln!("--coverage and --coverage-listing need a --line-map from kasm");
+        process::exit(1);
+    }
+    machine.set_quantum(quantum);
+    if let Some(fb) = framebuffer {
+        machine.set_framebuffer(fb);
+    }
+    if let Some(disk) = disk {
+        machine.set_disk(disk);
+    }
+    if sanitize {
+        machine.sanitize(&symbols);
+    }
+    if check_abi {
+        machine.check_abi(&symbols);
+    }
+    for hart in machine.harts.iter_mut() {
+        hart.observers.icache = icache.clone().map(|config| Cache::new("L1I", config));
+        hart.observers.dcache = dcache.clone().map(|config| Cache::new("L1D", config));
+        hart.observers.bpred = bpred.as_ref().map(|spec| Predictor::parse(spec));
+        hart.observers.costs = costs.clone();
+        hart.observers.pipeline = pipeline.clone().map(|mut pipeline| {
+            if let Some(ref costs) = costs {
+                pipeline.set_latencies(costs.cycles());
             }
+            pipeline
+        });
+        hart.blocks = blocks;
+        if !watchpoints.is_empty() {
+            hart.observers.watch = Some(Watchpoints { list: watchpoints.clone(),
+                                                      hits: Vec::new() });
         }
     }
-
-
-    fn exec_load(&mut self, instruction: u32) {
-        self.address += 4;
-    }
-    fn exec_op_imm(&mut self, instruction: u32) {
-        let funct3 = (instruction & 0x7000) >> 12;
-        let rd =     (instruction & 0xf80) >> 7;
-        let rs1 =    (instruction & 0xf8000) >> 15;
-        let imm =    (instruction & 0xfff00000) >> 20;
-        let shamt =  (instruction & 0x700000) >> 20;
-        let funct7 = (instruction & 0xfe000000) >> 25;
-
-        // sign extention
-        let imm = imm | if instruction >> 31 == 1 {0xfffff000} else {0};
-        let rs1 = self.register[rs1 as usize];
-
-        self.register[rd as usize] = match funct3 {
-            0b000 => rs1 + imm,
-            0b010 => panic!("SLTI is not implemented"),
-            0b011 => panic!("SLTIU is not implemented"),
-            0b100 => rs1 ^ imm,
-            0b110 => rs1 | imm,
-            0b111 => rs1 & imm,
-            0b001 => panic!("SLLI is not implemented"),
-            0b101 => panic!("SRLI/SRLI is not implemented"),
-            _     => panic!("Unknown OP_IMM"),
+    let state = &mut machine.harts[0];
+    if profile_file.is_some() || folded_file.is_some() {
+        state.observers.profiler = Some(Profiler::new(state.pc()));
+    }
+    if print_stats || stats_file.is_some() {
+        state.observers.stats = Some(Stats::default());
+    }
+    if coverage_file.is_some() || listing_file.is_some() {
+        state.observers.coverage = Some(Coverage::default());
+    }
+    if let Some(ref path) = trace_file {
+        state.observers.cosim = Some(Cosim::open(path, state));
+    }
+    if let Some(ref path) = vcd_file {
+        state.observers.vcd = Some(Vcd::create(path, state));
+    }
+    let mut status = 0;
+    if debug {
+        debug::run(state, &symbols);
+    } else {
+        // both limits count from the start of the program, not of this run
+        let start = machine.instret();
+        let stop_at = match (checkpoint_at, max_instructions) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        let limits = Limits {
+            instructions: stop_at.map(|n| n.saturating_sub(start)),
+            timeout,
+            detect_loops,
         };
-        self.address += 4;
+        let stop = machine.run_with(&limits);
+        let pcs = machine.harts.iter().map(|h| format!("{:#010x}", h.pc())).collect::<Vec<_>>();
+        let at = if pcs.len() == 1 { "pc" } else { "pcs" };
+        match stop {
+            Stop::Exit => {
+                // test suites report the number of the failed test through
+                // tohost
+                if let Some(test) = machine.host_exit().filter(|&test| test != 0) {
+                    eprintln!("Test {} failed.", test);
+                    status = EXIT_TEST_FAILED;
+                }
+            }
+            Stop::Limit => {
+                if checkpoint_at != Some(machine.instret()) {
+                    eprintln!("Instruction limit of {} reached at {} {}.",
+                              machine.instret(), at, pcs.join(", "));
+                    status = EXIT_INSTRUCTION_LIMIT;
+                }
+            }
+            Stop::Timeout => {
+                eprintln!("Timed out after {} instructions at {} {}.",
+                          machine.instret(), at, pcs.join(", "));
+                status = EXIT_TIMEOUT;
+            }
+            Stop::InfiniteLoop(pc) => {
+                eprintln!("Infinite loop detected at pc {:#010x}: it jumps to itself without changing any state.", pc);
+                status = EXIT_INFINITE_LOOP;
+            }
+            Stop::Watchpoint(hits) => {
+                for hit in hits {
+                    eprintln!("{}", hit);
+                }
+            }
+            Stop::Divergence(divergence) => {
+                eprintln!("{}", divergence);
+                status = EXIT_DIVERGED;
+            }
+            Stop::Exception(exception, pc) => {
+                eprintln!("{} at pc {:#010x}", exception, pc);
+                status = EXIT_EXCEPTION;
+            }
+            Stop::HostRequest(request) => {
+                eprintln!("Unsupported tohost request {:#010x}.", request);
+                status = EXIT_HOST_REQUEST;
+            }
+        }
     }
-
-    fn exec_store(&mut self, instruction: u32) {
-        panic!("STORE is not implemented");
+    if let (Some(ref path), Some((begin, end))) = (signature_file, signature) {
+        write_signature(&machine, path, begin, end);
     }
-    fn exec_op(&mut self, instruction: u32) {
-        panic!("OP is not implemented");
+    if let Some(ref path) = checkpoint_file {
+        checkpoint::save(&machine.harts[0], path);
+        eprintln!("Checkpoint saved to {} after {} instructions.", path, machine.instret());
     }
-    fn exec_lui(&mut self, instruction: u32) {
-        panic!("LUI is not implemented");
+    machine.report();
+    let state = &mut machine.harts[0];
+    if let Some(ref mut profiler) = state.observers.profiler {
+        if let Some(ref path) = profile_file {
+            profiler.write_report(path, &symbols);
+        }
+        if let Some(ref path) = folded_file {
+            profiler.write_folded(path, &symbols);
+        }
     }
-    fn exec_branch(&mut self, instruction: u32) {
-        panic!("BRANCH is not implemented");
+    if let Some(ref stats) = state.observers.stats {
+        if print_stats {
+            stats.print(state.cycle());
+        }
+        if let Some(ref path) = stats_file {
+            stats.write_json(path, state.cycle());
+        }
     }
-    fn exec_jalr(&mut self, instruction: u32) {
-        panic!("JALR is not implemented");
+    if let Some(ref mut cosim) = state.observers.cosim {
+        if status == 0 && cosim.has_more() {
+            eprintln!("Co-simulation: the run stopped after {} instructions, before the end of the trace.",
+                      cosim.compared);
+            status = EXIT_DIVERGED;
+        }
     }
-    fn exec_jal(&mut self, instruction: u32) {
-        panic!("JAL is not implemented");
+    if let Some(ref mut vcd) = state.observers.vcd {
+        vcd.finish();
     }
-}
-
-fn main() {
-    let args: Vec<_> = env::args().collect();
-    if args.len() == 1 {
-        panic!("Usage: {} <filename>", args[0]);
-    } 
-
-    let f = match File::open(&args[1]) {
-        Ok(file) => BufReader::new(file),
-        Err(err) => panic!("File open error: {:?}", err),
-    };
-
-    let mut instructions = Vec::new();
-    for line in f.lines() {
-        let l = line.unwrap();
-        let instr = u32::from_str_radix(&l, 2).unwrap();
-        instructions.push(instr);
-    }
-    let mut state = State::init(instructions);
-    loop {
-        state.step();
-        if state.is_exit() {
-            break;
+    if let (Some(ref coverage), Some(ref map)) = (&state.observers.coverage, &line_map) {
+        if let Some(ref path) = coverage_file {
+            coverage.write_lcov(path, map, state);
         }
+        if let Some(ref path) = listing_file {
+            coverage.write_listing(path, map, state);
+        }
+    }
+    if status == 0 && (machine.sanitizer_failed() || machine.abi_failed()) {
+        status = EXIT_CHECK_FAILED;
     }
+    process::exit(status);
 }
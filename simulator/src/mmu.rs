@@ -0,0 +1,363 @@
+// Sv32 address translation.
+//
+// In S- and U-mode with satp.MODE set, every fetch, load and store goes
+// through the two-level page table walk, which also sets the A and D bits
+// of the leaf PTE.  Translations are cached in a small direct-mapped TLB
+// tagged with the ASID, so a kernel that forgets sfence.vma after changing
+// a page table sees stale mappings just as it would on hardware.
+//
+// Page tables live in RAM on the bus.  Instruction fetches index the
+// program image with the translated physical address.  The physical
+// address, and every page table access, is then checked by the PMP.
+
+use csr::*;
+use history::Write;
+use trap::Exception;
+use State;
+
+const PTE_V: u32 = 1 << 0;
+const PTE_R: u32 = 1 << 1;
+const PTE_W: u32 = 1 << 2;
+const PTE_X: u32 = 1 << 3;
+const PTE_U: u32 = 1 << 4;
+const PTE_G: u32 = 1 << 5;
+const PTE_A: u32 = 1 << 6;
+const PTE_D: u32 = 1 << 7;
+
+const TLB_ENTRIES: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    Fetch,
+    Load,
+    Store,
+}
+
+impl Access {
+    fn page_fault(self, addr: u64) -> Exception {
+        match self {
+            Access::Fetch => Exception::InstructionPageFault(addr),
+            Access::Load => Exception::LoadPageFault(addr),
+            Access::Store => Exception::StorePageFault(addr),
+        }
+    }
+
+    pub(crate) fn access_fault(self, addr: u64) -> Exception {
+        match self {
+            Access::Fetch => Exception::InstructionAccessFault(addr),
+            Access::Load => Exception::LoadAccessFault(addr),
+            Access::Store => Exception::StoreAccessFault(addr),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    vpn: u32,
+    asid: u32,
+    // the leaf PTE, for the permission checks
+    pte: u32,
+    // physical page number of this 4 KiB page, also within a megapage
+    ppn: u32,
+}
+
+#[derive(Clone)]
+pub struct Tlb {
+    entries: Vec<Option<Entry>>,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl Default for Tlb {
+    fn default() -> Tlb {
+        Tlb { entries: vec![None; TLB_ENTRIES], hits: 0, misses: 0 }
+    }
+}
+
+impl Tlb {
+    // sfence.vma: drop the entries for addr (or all addresses) in address
+    // space asid (or all of them).  Global mappings stay unless asid is None.
+    pub fn flush(&mut self, addr: Option<u32>, asid: Option<u32>) {
+        for slot in self.entries.iter_mut() {
+            if let Some(e) = *slot {
+                let addr_match = addr.is_none_or(|a| a >> 12 == e.vpn);
+                let asid_match = asid.is_none_or(|a| a == e.asid && e.pte & PTE_G == 0);
+                if addr_match && asid_match {
+                    *slot = None;
+                }
+            }
+        }
+    }
+
+    fn lookup(&self, vpn: u32, asid: u32) -> Option<Entry> {
+        match self.entries[vpn as usize % TLB_ENTRIES] {
+            Some(e) if e.vpn == vpn && (e.asid == asid || e.pte & PTE_G != 0) => Some(e),
+            _ => None,
+        }
+    }
+
+    fn insert(&mut self, entry: Entry) {
+        self.entries[entry.vpn as usize % TLB_ENTRIES] = Some(entry);
+    }
+}
+
+impl State {
+    // True if fetches are translated.
+    pub(crate) fn paging(&self) -> bool {
+        self.privilege < PRV_M && self.csr.satp & SATP_MODE != 0
+    }
+
+    // Physical address of a `width`-byte access at virtual address addr.
+    #[inline(always)]
+    pub(crate) fn translate(&mut self, addr: u32, width: u32, access: Access) -> Result<u32, Exception> {
+        // the common case, M-mode with nothing to check, has to be cheap
+        let mstatus = self.csr.mstatus;
+        if self.privilege == PRV_M && mstatus & MSTATUS_MPRV == 0 && !self.csr.pmp_locked_any() {
+            return Ok(addr);
+        }
+        self.translate_checked(addr, width, access)
+    }
+
+    fn translate_checked(&mut self, addr: u32, width: u32, access: Access) -> Result<u32, Exception> {
+        let mstatus = self.csr.mstatus;
+        // MPRV makes loads and stores in M-mode use the privilege in MPP
+        let privilege = if access != Access::Fetch && mstatus & MSTATUS_MPRV != 0 {
+            (mstatus & MSTATUS_MPP) >> 11
+        } else {
+            self.privilege
+        };
+        let paddr = if privilege == PRV_M || self.csr.satp & SATP_MODE == 0 {
+            addr
+        } else {
+            self.page(addr, width, access, privilege)?
+        };
+        if !self.pmp_allows(paddr, width, access, privilege) {
+            return Err(access.access_fault(addr as u64));
+        }
+        Ok(paddr)
+    }
+
+    // Sv32 translation of addr, through the TLB.
+    fn page(&mut self, addr: u32, width: u32, access: Access, privilege: u32) -> Result<u32, Exception> {
+        // an access that straddles two pages is not split up
+        if (addr & 0xfff) + width > 0x1000 {
+            return Err(match access {
+                Access::Fetch => Exception::InstructionAddressMisaligned(addr as u64),
+                Access::Load => Exception::LoadAddressMisaligned(addr as u64),
+                Access::Store => Exception::StoreAddressMisaligned(addr as u64),
+            });
+        }
+
+        let vpn = addr >> 12;
+        let asid = (self.csr.satp >> 22) & 0x1ff;
+        let entry = match self.tlb.lookup(vpn, asid) {
+            // a store through a clean page has to walk again to set D
+            Some(e) if access != Access::Store || e.pte & PTE_D != 0 => {
+                self.tlb.hits += 1;
+                if !self.permitted(e.pte, access, privilege) {
+                    return Err(access.page_fault(addr as u64));
+                }
+                e
+            }
+            _ => {
+                self.tlb.misses += 1;
+                let e = self.walk(addr, access, privilege)?;
+                self.tlb.insert(e);
+                e
+            }
+        };
+        Ok((entry.ppn << 12) | (addr & 0xfff))
+    }
+
+    // Physical address of the instruction at addr, for the debugger: no
+    // permission checks, no TLB and no A/D updates.
+    pub(crate) fn probe(&self, addr: u32) -> Option<u32> {
+        if !self.paging() {
+            return Some(addr);
+        }
+        let vpn = [(addr >> 12) & 0x3ff, addr >> 22];
+        let mut table = self.csr.satp & 0x3f_ffff;
+        for level in (0..2).rev() {
+            let pte = self.bus.borrow().read(table.checked_mul(4096)? + vpn[level] * 4, 4)?;
+            if pte & PTE_V == 0 {
+                return None;
+            }
+            if pte & (PTE_R | PTE_X) != 0 {
+                let ppn = if level == 1 { (pte >> 10) | vpn[0] } else { pte >> 10 };
+                return ppn.checked_mul(4096).map(|base| base | (addr & 0xfff));
+            }
+            table = pte >> 10;
+        }
+        None
+    }
+
+    fn permitted(&self, pte: u32, access: Access, privilege: u32) -> bool {
+        let mstatus = self.csr.mstatus;
+        let allowed = match access {
+            Access::Fetch => pte & PTE_X != 0,
+            Access::Load => pte & PTE_R != 0 || (pte & PTE_X != 0 && mstatus & MSTATUS_MXR != 0),
+            Access::Store => pte & PTE_W != 0,
+        };
+        let user_ok = if privilege == PRV_U {
+            pte & PTE_U != 0
+        } else {
+            // S-mode never executes user pages and touches them only with SUM
+            pte & PTE_U == 0 || (access != Access::Fetch && mstatus & MSTATUS_SUM != 0)
+        };
+        allowed && user_ok
+    }
+
+    // Walk the page table for addr.  If the access is permitted, set the A
+    // and D bits of the leaf PTE as needed.
+    fn walk(&mut self, addr: u32, access: Access, privilege: u32) -> Result<Entry, Exception> {
+        let vpn = [(addr >> 12) & 0x3ff, addr >> 22];
+        let mut table = self.csr.satp & 0x3f_ffff;
+        let mut level = 1;
+        loop {
+            // the table and the PTE must be within the 32-bit physical space
+            if table >> 20 != 0 {
+                return Err(access.access_fault(addr as u64));
+            }
+            let pte_addr = (table << 12) + vpn[level] * 4;
+            // the walk itself counts as an S-mode access
+            if !self.pmp_allows(pte_addr, 4, Access::Load, PRV_S) {
+                return Err(access.access_fault(addr as u64));
+            }
+            let pte = match self.bus.borrow().read(pte_addr, 4) {
+                Some(pte) => pte,
+                None => return Err(access.access_fault(addr as u64)),
+            };
+            if pte & PTE_V == 0 || (pte & PTE_R == 0 && pte & PTE_W != 0) {
+                return Err(access.page_fault(addr as u64));
+            }
+            let ppn = pte >> 10;
+            if pte & (PTE_R | PTE_X) == 0 {
+                // pointer to the next level
+                if level == 0 {
+                    return Err(access.page_fault(addr as u64));
+                }
+                level -= 1;
+                table = ppn;
+                continue;
+            }
+            // a megapage must be aligned
+            if (level == 1 && ppn & 0x3ff != 0) || !self.permitted(pte, access, privilege) {
+                return Err(access.page_fault(addr as u64));
+            }
+            let ppn = if level == 1 { ppn | vpn[0] } else { ppn };
+            if ppn >> 20 != 0 {
+                return Err(access.access_fault(addr as u64));
+            }
+            let mut new = pte | PTE_A;
+            if access == Access::Store {
+                new |= PTE_D;
+            }
+            if new != pte {
+                if !self.pmp_allows(pte_addr, 4, Access::Store, PRV_S) {
+                    return Err(access.access_fault(addr as u64));
+                }
+                self.bus.borrow_mut().write(pte_addr, new, 4);
+                if let Some(ref mut history) = self.history {
+                    history.record(Write::Mem { addr: pte_addr, width: 4, old: pte, new });
+                }
+            }
+            let asid = (self.csr.satp >> 22) & 0x1ff;
+            return Ok(Entry { vpn: addr >> 12, asid, pte: new, ppn });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use bus::Bus;
+    use super::*;
+
+    // An S-mode hart with 16 KiB of RAM, the root page table in page 1 and
+    // the PMP opened up.
+    fn hart() -> State {
+        let bus = Rc::new(RefCell::new(Bus::new(1, 0x4000)));
+        let mut state = State::with_bus(Vec::new(), bus, 0);
+        state.privilege = PRV_S;
+        state.csr.satp = SATP_MODE | 1;
+        state.csr.pmpaddr[0] = u32::MAX;
+        state.csr.pmpcfg[0] = 0x1f;
+        state
+    }
+
+    fn pte(state: &State, addr: u32) -> u32 {
+        state.bus.borrow().read(addr, 4).unwrap()
+    }
+
+    fn set_pte(state: &State, addr: u32, pte: u32) {
+        state.bus.borrow_mut().write(addr, pte, 4);
+    }
+
+    #[test]
+    fn walk_sets_accessed_and_dirty() {
+        let mut state = hart();
+        // 0x00400000 -> page 3, through the table in page 2
+        set_pte(&state, 0x1000 + 4, (2 << 10) | PTE_V);
+        set_pte(&state, 0x2000, (3 << 10) | PTE_R | PTE_W | PTE_V);
+        assert_eq!(state.translate(0x0040_0123, 4, Access::Load), Ok(0x3123));
+        assert_eq!(pte(&state, 0x2000) & (PTE_A | PTE_D), PTE_A);
+        // the pointer to the next level is left alone
+        assert_eq!(pte(&state, 0x1004), (2 << 10) | PTE_V);
+        // the TLB holds a clean entry now, so the store walks again for D
+        assert_eq!(state.translate(0x0040_0124, 4, Access::Store), Ok(0x3124));
+        assert_eq!(pte(&state, 0x2000) & (PTE_A | PTE_D), PTE_A | PTE_D);
+        let misses = state.tlb.misses;
+        assert_eq!(state.translate(0x0040_0128, 4, Access::Store), Ok(0x3128));
+        assert_eq!(state.tlb.misses, misses);
+    }
+
+    #[test]
+    fn denied_access_leaves_pte_alone() {
+        let mut state = hart();
+        set_pte(&state, 0x1000 + 4, (2 << 10) | PTE_V);
+        set_pte(&state, 0x2000, (3 << 10) | PTE_R | PTE_V);
+        assert_eq!(state.translate(0x0040_0000, 4, Access::Store),
+                   Err(Exception::StorePageFault(0x0040_0000)));
+        assert_eq!(pte(&state, 0x2000), (3 << 10) | PTE_R | PTE_V);
+        // S-mode does not touch user pages without SUM
+        set_pte(&state, 0x2000, (3 << 10) | PTE_R | PTE_U | PTE_V);
+        assert_eq!(state.translate(0x0040_0000, 4, Access::Load),
+                   Err(Exception::LoadPageFault(0x0040_0000)));
+        state.csr.mstatus |= MSTATUS_SUM;
+        assert_eq!(state.translate(0x0040_0000, 4, Access::Load), Ok(0x3000));
+    }
+
+    #[test]
+    fn megapages_must_be_aligned() {
+        let mut state = hart();
+        // 0x00800000 -> a misaligned megapage at page 1
+        set_pte(&state, 0x1000 + 8, (1 << 10) | PTE_R | PTE_V);
+        assert_eq!(state.translate(0x0080_0000, 4, Access::Load),
+                   Err(Exception::LoadPageFault(0x0080_0000)));
+        assert_eq!(pte(&state, 0x1008) & PTE_A, 0);
+        // 0x00c00000 -> the megapage at 0, which keeps the page offset
+        // within the megapage
+        set_pte(&state, 0x1000 + 12, PTE_R | PTE_W | PTE_X | PTE_V);
+        assert_eq!(state.translate(0x00c0_1234, 4, Access::Load), Ok(0x1234));
+        assert_eq!(state.translate(0x00c0_3ffc, 4, Access::Store), Ok(0x3ffc));
+        assert_eq!(pte(&state, 0x100c) & (PTE_A | PTE_D), PTE_A | PTE_D);
+    }
+
+    #[test]
+    fn invalid_and_reserved_ptes_fault() {
+        let mut state = hart();
+        assert_eq!(state.translate(0x0040_0000, 4, Access::Fetch),
+                   Err(Exception::InstructionPageFault(0x0040_0000)));
+        // W without R is reserved
+        set_pte(&state, 0x1000 + 4, (2 << 10) | PTE_W | PTE_V);
+        assert_eq!(state.translate(0x0040_0000, 4, Access::Load),
+                   Err(Exception::LoadPageFault(0x0040_0000)));
+        // a pointer at level 0
+        set_pte(&state, 0x1000 + 4, (2 << 10) | PTE_V);
+        set_pte(&state, 0x2000, (3 << 10) | PTE_V);
+        assert_eq!(state.translate(0x0040_0000, 4, Access::Load),
+                   Err(Exception::LoadPageFault(0x0040_0000)));
+    }
+}
@@ -0,0 +1,412 @@
+// In-order and out-of-order core timing models.
+//
+// The program runs as usual and every retired instruction then goes
+// through a model of a superscalar core, which works out the cycles it is
+// dispatched, issued, completed and committed in.  The model only affects
+// its own report: mcycle and the other timing options keep counting as
+// before.
+//
+// The front end delivers `width` instructions per cycle in program order
+// and is ideal apart from redirects.  Dispatch renames them and puts them
+// into the reorder buffer, a reservation station and, for loads, stores and
+// AMOs, the load/store queue, and stalls while any of these or the free
+// physical registers (`regs` less the 32 architectural ones) run out.  An
+// instruction issues once its operands are ready, at most `width` per
+// cycle, and completes after the latency of its class (see costs.rs; the
+// table given with --costs, or the defaults below) plus any data cache miss
+// penalty.  Loads take their value from the last older store to the same
+// word once that store has completed, so memory dependences are predicted
+// perfectly.  Divides use a single divider that is not pipelined.  Up to
+// `width` completed instructions commit per cycle, oldest first, freeing
+// their ROB and LSQ entries and the physical register of the previous
+// value of their destination.
+//
+// Branches mispredict where the branch predictor of --bpred does, and are
+// predicted perfectly without it; the front end then restarts `depth`
+// cycles after the branch completes.  CSR and other system instructions,
+// fences, exit and print_int wait for the ROB to drain and block dispatch
+// until they commit.  A trap restarts the front end `depth` cycles after
+// the instruction commits.
+//
+// The in-order model uses the same machine, but issues in program order and,
+// without renaming, does not let an instruction complete before an older
+// write to its destination register.
+
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, HashMap, VecDeque};
+
+use costs::{self, CLASSES};
+use decode::{decode, Op};
+use Retired;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Order {
+    InOrder,
+    OutOfOrder,
+}
+
+// latencies in cycles of the classes in costs::CLASSES
+const LATENCIES: [u64; CLASSES.len()] = [1, 3, 20, 2, 1, 1, 1, 1, 1, 2, 1];
+
+// Why instructions wait.  The dispatch stalls count cycles in which nothing
+// was dispatched; the issue delays count cycles each instruction waited in a
+// reservation station beyond the cycle after its dispatch.
+const ROB_FULL: usize = 0;
+const RS_FULL: usize = 1;
+const LSQ_FULL: usize = 2;
+const NO_REGISTERS: usize = 3;
+const SERIALIZE: usize = 4;
+const MISPREDICT: usize = 5;
+const TRAP: usize = 6;
+const OPERANDS: usize = 7;
+const OLDER_STORE: usize = 8;
+const ISSUE_WIDTH: usize = 9;
+const DIVIDER: usize = 10;
+const PROGRAM_ORDER: usize = 11;
+// the first issue delay
+const ISSUE_DELAYS: usize = OPERANDS;
+
+pub const REASONS: [&str; 12] = ["ROB full", "reservation stations full", "load/store queue full",
+                                 "no free physical register", "serializing instruction",
+                                 "branch misprediction", "trap", "operands not ready",
+                                 "older store", "issue width", "divider busy", "program order"];
+
+#[derive(Debug, Clone, Default)]
+pub struct PipelineStats {
+    pub instructions: u64,
+    pub cycles: u64,
+    // cycles or instruction-cycles for each of REASONS
+    pub stalls: [u64; REASONS.len()],
+}
+
+#[derive(Clone)]
+pub struct Pipeline {
+    pub order: Order,
+    pub width: u64,
+    pub rob: usize,
+    pub rs: usize,
+    pub lsq: usize,
+    pub regs: usize,
+    pub depth: u64,
+    latency: [u64; CLASSES.len()],
+    // earliest cycle the front end delivers the next instruction in, and
+    // the reason if that is a redirect
+    fetch: (u64, usize),
+    // the last dispatch and commit cycles, and how many instructions went
+    // in each of them
+    dispatch: (u64, u64),
+    commit: (u64, u64),
+    last_issue: u64,
+    // commit cycles of the youngest `rob` instructions, `lsq` memory
+    // instructions and `regs` - 32 instructions with a destination
+    rob_commits: VecDeque<u64>,
+    lsq_commits: VecDeque<u64>,
+    reg_commits: VecDeque<u64>,
+    // issue cycles of the instructions that may still hold a reservation
+    // station
+    waiting: BinaryHeap<Reverse<u64>>,
+    // number of instructions issued in each cycle from the last dispatch on
+    issued: BTreeMap<u64, u64>,
+    // cycle the value of each register is complete in
+    ready: [u64; 32],
+    divider: u64,
+    // completion cycle of the last store to each word
+    stores: HashMap<u32, u64>,
+    // data accesses of the current instruction: word address, whether it
+    // is a store and the miss penalty
+    accesses: Vec<(u32, bool, u64)>,
+    mispredicted: bool,
+    pub stats: PipelineStats,
+}
+
+// Which of rs1 and rs2 an instruction reads.
+fn sources(op: Op) -> (bool, bool) {
+    match op {
+        Op::Lui | Op::Auipc | Op::Jal | Op::Fence | Op::FenceI | Op::System | Op::Exit
+        | Op::PrintInt | Op::Illegal | Op::Undecoded => (false, false),
+        Op::Jalr | Op::Lb | Op::Lh | Op::Lw | Op::Lbu | Op::Lhu | Op::Lwu | Op::Ld | Op::Addi
+        | Op::Slti | Op::Sltiu | Op::Xori | Op::Ori | Op::Andi | Op::Slli | Op::Srli | Op::Srai
+        | Op::Addiw | Op::Slliw | Op::Srliw | Op::Sraiw => (true, false),
+        _ => (true, true),
+    }
+}
+
+// True for the instructions that write rd.
+fn writes_rd(op: Op) -> bool {
+    !matches!(op, Op::Beq | Op::Bne | Op::Blt | Op::Bge | Op::Bltu | Op::Bgeu | Op::Sb | Op::Sh
+                  | Op::Sw | Op::Sd | Op::Fence | Op::FenceI | Op::Exit | Op::PrintInt
+                  | Op::Illegal | Op::Undecoded)
+}
+
+fn is_memory(op: Op) -> bool {
+    matches!(op, Op::Lb | Op::Lh | Op::Lw | Op::Lbu | Op::Lhu | Op::Lwu | Op::Ld | Op::Sb
+                 | Op::Sh | Op::Sw | Op::Sd | Op::Amo)
+}
+
+fn is_serializing(op: Op) -> bool {
+    matches!(op, Op::System | Op::Fence | Op::FenceI | Op::Exit | Op::PrintInt)
+}
+
+// Raise a cycle to bound if that is later, charging the difference to a
+// reason.
+fn wait(cycle: &mut u64, bound: u64, stalls: &mut [u64], reason: usize) {
+    if bound > *cycle {
+        stalls[reason] += bound - *cycle;
+        *cycle = bound;
+    }
+}
+
+impl Pipeline {
+    // Parse a spec such as "ooo,width=4,rob=64,rs=32,lsq=16,regs=96,depth=5".
+    // The first item selects the model: ooo or inorder.
+    pub fn parse(spec: &str) -> Pipeline {
+        let mut items = spec.split(',').filter(|s| !s.is_empty());
+        let order = match items.next() {
+            Some("ooo") => Order::OutOfOrder,
+            Some("inorder") => Order::InOrder,
+            Some(m) => panic!("Unknown pipeline model: {}", m),
+            None => panic!("Pipeline spec is empty"),
+        };
+        let mut pipeline = Pipeline {
+            order,
+            width: 4,
+            rob: 64,
+            rs: 32,
+            lsq: 16,
+            regs: 96,
+            depth: 5,
+            latency: LATENCIES,
+            fetch: (0, 0),
+            dispatch: (0, 0),
+            commit: (0, 0),
+            last_issue: 0,
+            rob_commits: VecDeque::new(),
+            lsq_commits: VecDeque::new(),
+            reg_commits: VecDeque::new(),
+            waiting: BinaryHeap::new(),
+            issued: BTreeMap::new(),
+            ready: [0; 32],
+            divider: 0,
+            stores: HashMap::new(),
+            accesses: Vec::new(),
+            mispredicted: false,
+            stats: PipelineStats::default(),
+        };
+        for item in items {
+            let mut kv = item.splitn(2, '=');
+            let key = kv.next().unwrap();
+            let value = match kv.next().map(|v| v.parse::<u64>()) {
+                Some(Ok(n)) => n,
+                _ => panic!("Pipeline option {} needs a numeric value", key),
+            };
+            match key {
+                "width" => pipeline.width = value,
+                "rob" => pipeline.rob = value as usize,
+                "rs" => pipeline.rs = value as usize,
+                "lsq" => pipeline.lsq = value as usize,
+                "regs" => pipeline.regs = value as usize,
+                "depth" => pipeline.depth = value,
+                _ => panic!("Unknown pipeline option: {}", key),
+            }
+        }
+        if pipeline.width == 0 || pipeline.rob == 0 || pipeline.rs == 0 || pipeline.lsq == 0 {
+            panic!("Pipeline width, rob, rs and lsq must be at least 1");
+        }
+        if pipeline.regs <= 32 {
+            panic!("Pipeline needs more than 32 physical registers");
+        }
+        pipeline
+    }
+
+    /// Take the latencies from the cycles of a cost table.
+    pub fn set_latencies(&mut self, latency: [u64; CLASSES.len()]) {
+        self.latency = latency;
+    }
+
+    // Called for every data access of the current instruction.
+    pub(crate) fn access(&mut self, paddr: u32, is_store: bool, penalty: u64) {
+        self.accesses.push((paddr & !3, is_store, penalty));
+    }
+
+    // Called when the branch predictor gets the current instruction wrong.
+    pub(crate) fn mispredict(&mut self) {
+        self.mispredicted = true;
+    }
+
+    // Time an instruction that retired.
+    pub(crate) fn retire(&mut self, retired: &Retired, xlen: u32) {
+        let d = decode(retired.instruction, xlen);
+        let op = d.op;
+        let (rs1, rs2, rd) = (d.rs1 as usize, d.rs2 as usize, d.rd as usize);
+        let (reads1, reads2) = sources(op);
+        let writes = writes_rd(op) && rd != 0;
+        let memory = is_memory(op);
+        let in_order = self.order == Order::InOrder;
+        let stalls = &mut self.stats.stalls;
+
+        // dispatch
+        let mut dispatch = self.dispatch.0;
+        if self.dispatch.1 == self.width {
+            dispatch += 1;
+        }
+        wait(&mut dispatch, self.fetch.0, stalls, self.fetch.1);
+        if self.rob_commits.len() == self.rob {
+            wait(&mut dispatch, self.rob_commits[0] + 1, stalls, ROB_FULL);
+        }
+        if memory && self.lsq_commits.len() == self.lsq {
+            wait(&mut dispatch, self.lsq_commits[0] + 1, stalls, LSQ_FULL);
+        }
+        if writes && !in_order && self.reg_commits.len() == self.regs - 32 {
+            wait(&mut dispatch, self.reg_commits[0] + 1, stalls, NO_REGISTERS);
+        }
+        if is_serializing(op) && self.stats.instructions != 0 {
+            wait(&mut dispatch, self.commit.0 + 1, stalls, SERIALIZE);
+        }
+        // a reservation station is free again the cycle after its
+        // instruction issues
+        loop {
+            while self.waiting.peek().is_some_and(|r| r.0 < dispatch) {
+                self.waiting.pop();
+            }
+            if self.waiting.len() < self.rs {
+                break;
+            }
+            let Reverse(issue) = self.waiting.pop().unwrap();
+            wait(&mut dispatch, issue + 1, stalls, RS_FULL);
+        }
+        self.dispatch = if dispatch == self.dispatch.0 {
+            (dispatch, self.dispatch.1 + 1)
+        } else {
+            (dispatch, 1)
+        };
+
+        // issue
+        let class = costs::class(op, retired.taken);
+        let latency = self.latency[class];
+        let mut issue = dispatch + 1;
+        if reads1 {
+            wait(&mut issue, self.ready[rs1], stalls, OPERANDS);
+        }
+        if reads2 {
+            wait(&mut issue, self.ready[rs2], stalls, OPERANDS);
+        }
+        if in_order && writes {
+            wait(&mut issue, (self.ready[rd] + 1).saturating_sub(latency), stalls, OPERANDS);
+        }
+        for &(word, is_store, _) in self.accesses.iter() {
+            if !is_store {
+                if let Some(&done) = self.stores.get(&word) {
+                    wait(&mut issue, done, stalls, OLDER_STORE);
+                }
+            }
+        }
+        if class == costs::DIV {
+            wait(&mut issue, self.divider, stalls, DIVIDER);
+        }
+        if in_order {
+            wait(&mut issue, self.last_issue, stalls, PROGRAM_ORDER);
+        }
+        let width = self.width;
+        while self.issued.get(&issue).is_some_and(|&n| n >= width) {
+            issue += 1;
+            stalls[ISSUE_WIDTH] += 1;
+        }
+        *self.issued.entry(issue).or_insert(0) += 1;
+        self.issued = self.issued.split_off(&dispatch);
+        self.waiting.push(Reverse(issue));
+        self.last_issue = issue;
+
+        // complete
+        let penalty: u64 = self.accesses.iter().filter(|a| !a.1).map(|a| a.2).sum();
+        let done = issue + latency + penalty;
+        if class == costs::DIV {
+            self.divider = done;
+        }
+        if writes {
+            self.ready[rd] = done;
+        }
+        for &(word, is_store, _) in self.accesses.iter() {
+            if is_store {
+                self.stores.insert(word, done);
+            }
+        }
+        if self.stores.len() > 4 * self.lsq + 64 {
+            self.stores.retain(|_, done| *done > dispatch);
+        }
+        self.accesses.clear();
+
+        // commit
+        let mut commit = done.max(self.commit.0);
+        if commit == self.commit.0 && self.commit.1 == self.width {
+            commit += 1;
+        }
+        self.commit = if commit == self.commit.0 {
+            (commit, self.commit.1 + 1)
+        } else {
+            (commit, 1)
+        };
+        push(&mut self.rob_commits, commit, self.rob);
+        if memory {
+            push(&mut self.lsq_commits, commit, self.lsq);
+        }
+        if writes {
+            push(&mut self.reg_commits, commit, self.regs - 32);
+        }
+
+        // redirects of the front end
+        if retired.trapped {
+            self.fetch = (commit + self.depth, TRAP);
+        } else if is_serializing(op) {
+            self.fetch = (commit + 1, SERIALIZE);
+        } else if self.mispredicted {
+            self.fetch = (done + self.depth, MISPREDICT);
+        }
+        self.mispredicted = false;
+        self.stats.instructions += 1;
+        self.stats.cycles = commit + 1;
+    }
+
+    pub fn ipc(&self) -> f64 {
+        if self.stats.cycles == 0 {
+            0.0
+        } else {
+            self.stats.instructions as f64 / self.stats.cycles as f64
+        }
+    }
+
+    pub fn report(&self) {
+        let s = &self.stats;
+        let order = match self.order {
+            Order::OutOfOrder => "out-of-order",
+            Order::InOrder => "in-order",
+        };
+        eprintln!("pipeline: {}, {}-wide, {} ROB entries, {} reservation stations, {} LSQ entries,",
+                  order, self.width, self.rob, self.rs, self.lsq);
+        if self.order == Order::OutOfOrder {
+            eprintln!("  {} physical registers, {} front-end stages", self.regs, self.depth);
+        } else {
+            eprintln!("  {} front-end stages", self.depth);
+        }
+        eprintln!("  instructions: {}", s.instructions);
+        eprintln!("  cycles:       {}", s.cycles);
+        eprintln!("  IPC:          {:.3}", self.ipc());
+        eprintln!("  dispatch stalls (cycles):");
+        for (name, cycles) in REASONS.iter().zip(s.stalls.iter()).take(ISSUE_DELAYS) {
+            eprintln!("    {:<28}{:>12}", name, cycles);
+        }
+        eprintln!("  issue delays (instruction-cycles):");
+        for (reason, (name, cycles)) in REASONS.iter().zip(s.stalls.iter()).enumerate().skip(ISSUE_DELAYS) {
+            if reason != PROGRAM_ORDER || self.order == Order::InOrder {
+                eprintln!("    {:<28}{:>12}", name, cycles);
+            }
+        }
+    }
+}
+
+// Append to a queue that keeps the last `size` values.
+fn push(queue: &mut VecDeque<u64>, value: u64, size: usize) {
+    queue.push_back(value);
+    if queue.len() > size {
+        queue.pop_front();
+    }
+}
@@ -0,0 +1,234 @@
+// Physical memory protection.
+//
+// Sixteen entries, each a pmpaddr register and a byte of pmpcfg.  An access
+// is checked against the entries in order, and the first entry that covers
+// any of its bytes decides: the access must lie entirely within that entry,
+// and outside M-mode the entry's R, W or X bit must allow it.  M-mode is
+// only restricted by locked entries.  An S- or U-mode access that matches no
+// entry fails, so a program has to program the PMP before it leaves M-mode.
+//
+// A locked entry cannot be changed until reset, and locking a TOR entry
+// also locks the pmpaddr below it, which holds its lower bound.
+
+use csr::*;
+use mmu::Access;
+use State;
+
+pub const PMP_ENTRIES: usize = 16;
+
+const PMP_R: u32 = 1 << 0;
+const PMP_W: u32 = 1 << 1;
+const PMP_X: u32 = 1 << 2;
+const PMP_A: u32 = 3 << 3;
+const PMP_L: u32 = 1 << 7;
+
+// address matching modes in the A field
+const PMP_OFF: u32 = 0;
+const PMP_TOR: u32 = 1;
+const PMP_NA4: u32 = 2;
+
+impl Csr {
+    fn pmp_cfg(&self, i: usize) -> u32 {
+        (self.pmpcfg[i / 4] >> (8 * (i % 4))) & 0xff
+    }
+
+    fn pmp_locked(&self, i: usize) -> bool {
+        self.pmp_cfg(i) & PMP_L != 0
+    }
+
+    // True if some entry is locked, so that it also applies to M-mode.
+    #[inline]
+    pub(crate) fn pmp_locked_any(&self) -> bool {
+        self.pmpcfg.iter().fold(0, |a, &c| a | c) & 0x8080_8080 != 0
+    }
+
+    // Byte range [start, end) covered by entry i, or None if it is off.
+    fn pmp_range(&self, i: usize) -> Option<(u64, u64)> {
+        let addr = self.pmpaddr[i] as u64;
+        match (self.pmp_cfg(i) & PMP_A) >> 3 {
+            PMP_OFF => None,
+            PMP_TOR => {
+                let start = if i == 0 { 0 } else { (self.pmpaddr[i - 1] as u64) << 2 };
+                Some((start, addr << 2))
+            }
+            PMP_NA4 => Some((addr << 2, (addr << 2) + 4)),
+            _ => {
+                // NAPOT: the trailing ones give the size, 8 bytes and up
+                let ones = self.pmpaddr[i].trailing_ones();
+                let start = (addr & !((1 << ones) - 1)) << 2;
+                Some((start, start + (8 << ones)))
+            }
+        }
+    }
+
+    // New pmpcfg value after a write: locked entries keep their byte, and
+    // the reserved combination W without R reads back as neither.
+    pub(crate) fn pmp_write_cfg(&self, index: usize, value: u32) -> u32 {
+        let mut result = 0;
+        for byte in 0..4 {
+            let i = 4 * index + byte;
+            let mut cfg = if self.pmp_locked(i) {
+                self.pmp_cfg(i)
+            } else {
+                (value >> (8 * byte)) & (PMP_L | PMP_A | PMP_X | PMP_W | PMP_R)
+            };
+            if cfg & (PMP_R | PMP_W) == PMP_W {
+                cfg &= !PMP_W;
+            }
+            result |= cfg << (8 * byte);
+        }
+        result
+    }
+
+    // True if pmpaddr i can be written.
+    pub(crate) fn pmp_addr_writable(&self, i: usize) -> bool {
+        let next_is_locked_tor = i + 1 < PMP_ENTRIES && self.pmp_locked(i + 1)
+            && (self.pmp_cfg(i + 1) & PMP_A) >> 3 == PMP_TOR;
+        !self.pmp_locked(i) && !next_is_locked_tor
+    }
+}
+
+impl State {
+    // Whether a width-byte access at physical address paddr is allowed in
+    // the given privilege mode.
+    pub(crate) fn pmp_allows(&self, paddr: u32, width: u32, access: Access, privilege: u32) -> bool {
+        let csr = &self.csr;
+        // the common case: M-mode and no entry is locked
+        if privilege == PRV_M && !csr.pmp_locked_any() {
+            return true;
+        }
+        let (first, last) = (paddr as u64, paddr as u64 + width as u64);
+        for i in 0..PMP_ENTRIES {
+            let (start, end) = match csr.pmp_range(i) {
+                Some(range) => range,
+                None => continue,
+            };
+            if last <= start || first >= end {
+                continue;
+            }
+            if first < start || last > end {
+                return false;
+            }
+            if privilege == PRV_M && !csr.pmp_locked(i) {
+                return true;
+            }
+            let cfg = csr.pmp_cfg(i);
+            return match access {
+                Access::Fetch => cfg & PMP_X != 0,
+                Access::Load => cfg & PMP_R != 0,
+                Access::Store => cfg & PMP_W != 0,
+            };
+        }
+        privilege == PRV_M
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const U: u32 = PRV_U;
+    const M: u32 = PRV_M;
+
+    fn set(state: &mut State, i: usize, addr: u32, cfg: u32) {
+        state.csr.pmpaddr[i] = addr;
+        state.csr.pmpcfg[i / 4] |= cfg << (8 * (i % 4));
+    }
+
+    fn allows(state: &State, addr: u32, width: u32, access: Access, privilege: u32) -> bool {
+        state.pmp_allows(addr, width, access, privilege)
+    }
+
+    #[test]
+    fn unmatched_accesses() {
+        let mut state = State::init(Vec::new());
+        // with every entry off only M-mode gets anywhere
+        assert!(allows(&state, 0x100, 4, Access::Load, M));
+        assert!(!allows(&state, 0x100, 4, Access::Load, U));
+        assert!(!allows(&state, 0x100, 4, Access::Fetch, PRV_S));
+        set(&mut state, 0, 0x1000 >> 2, PMP_NA4 << 3 | PMP_R);
+        assert!(!allows(&state, 0x100, 4, Access::Load, U));
+        assert!(allows(&state, 0x100, 4, Access::Store, M));
+    }
+
+    #[test]
+    fn tor() {
+        let mut state = State::init(Vec::new());
+        set(&mut state, 0, 0x1000 >> 2, 0);
+        set(&mut state, 1, 0x2000 >> 2, PMP_TOR << 3 | PMP_R);
+        assert!(allows(&state, 0x1000, 4, Access::Load, U));
+        assert!(allows(&state, 0x1ffc, 4, Access::Load, U));
+        assert!(!allows(&state, 0x1800, 4, Access::Store, U));
+        assert!(!allows(&state, 0x2000, 4, Access::Load, U));
+        assert!(!allows(&state, 0xffc, 4, Access::Load, U));
+        // an access that is only partly inside fails
+        assert!(!allows(&state, 0x1ffe, 4, Access::Load, U));
+        // entry 0 starts at address 0
+        let mut state = State::init(Vec::new());
+        set(&mut state, 0, 0x100 >> 2, PMP_TOR << 3 | PMP_X);
+        assert!(allows(&state, 0, 4, Access::Fetch, U));
+        assert!(!allows(&state, 0x100, 4, Access::Fetch, U));
+    }
+
+    #[test]
+    fn na4_and_napot() {
+        let mut state = State::init(Vec::new());
+        set(&mut state, 0, 0x3000 >> 2, PMP_NA4 << 3 | PMP_R | PMP_W);
+        // 4 KiB at 0x4000: nine trailing ones
+        set(&mut state, 1, (0x4000 >> 2) | 0x1ff, 3 << 3 | PMP_R);
+        // 8 bytes at 0x5008: no trailing ones
+        set(&mut state, 2, 0x5008 >> 2, 3 << 3 | PMP_W);
+        assert!(allows(&state, 0x3000, 4, Access::Store, U));
+        assert!(!allows(&state, 0x3004, 4, Access::Store, U));
+        assert!(!allows(&state, 0x3002, 4, Access::Load, U));
+        assert!(allows(&state, 0x4000, 4, Access::Load, U));
+        assert!(allows(&state, 0x4ffc, 4, Access::Load, U));
+        assert!(!allows(&state, 0x4ffc, 4, Access::Store, U));
+        assert!(!allows(&state, 0x5000, 4, Access::Load, U));
+        assert!(allows(&state, 0x500c, 4, Access::Store, U));
+        assert!(!allows(&state, 0x5010, 4, Access::Store, U));
+    }
+
+    #[test]
+    fn first_match_decides() {
+        let mut state = State::init(Vec::new());
+        set(&mut state, 0, 0x1000 >> 2, PMP_NA4 << 3);
+        set(&mut state, 1, (0x1000 >> 2) | 0x1ff, 3 << 3 | PMP_R);
+        assert!(!allows(&state, 0x1000, 4, Access::Load, U));
+        assert!(allows(&state, 0x1004, 4, Access::Load, U));
+        // an access that covers the first entry only in part fails
+        assert!(!allows(&state, 0xffe, 4, Access::Load, U));
+    }
+
+    #[test]
+    fn locked_entries_bind_m_mode() {
+        let mut state = State::init(Vec::new());
+        set(&mut state, 0, 0x1000 >> 2, PMP_NA4 << 3 | PMP_R);
+        assert!(allows(&state, 0x1000, 4, Access::Store, M));
+        set(&mut state, 0, 0x1000 >> 2, PMP_L);
+        assert!(state.csr.pmp_locked_any());
+        assert!(allows(&state, 0x1000, 4, Access::Load, M));
+        assert!(!allows(&state, 0x1000, 4, Access::Store, M));
+        // M-mode accesses that no entry covers still succeed
+        assert!(allows(&state, 0x2000, 4, Access::Store, M));
+    }
+
+    #[test]
+    fn lock_rules() {
+        let mut state = State::init(Vec::new());
+        set(&mut state, 1, 0x2000 >> 2, PMP_L | PMP_TOR << 3 | PMP_R);
+        let csr = &state.csr;
+        // a locked entry keeps its configuration, the others can change
+        assert_eq!(csr.pmp_write_cfg(0, 0x0000_1f1f), 0x0000_891f);
+        // the locked TOR entry and the pmpaddr holding its lower bound
+        assert!(!csr.pmp_addr_writable(1));
+        assert!(!csr.pmp_addr_writable(0));
+        assert!(csr.pmp_addr_writable(2));
+        // W without R is reserved and reads back as neither
+        assert_eq!(csr.pmp_write_cfg(1, PMP_W | PMP_X), PMP_X);
+        // a locked entry that is not TOR leaves the pmpaddr below it alone
+        let mut state = State::init(Vec::new());
+        set(&mut state, 1, 0x2000 >> 2, PMP_L | PMP_NA4 << 3);
+        assert!(state.csr.pmp_addr_writable(0));
+    }
+}
@@ -0,0 +1,130 @@
+// Execution profiler.
+// Counts retired instructions per PC and keeps a shadow call stack built
+// from JAL/JALR call and return pairs, so that counts can be attributed to
+// whole call chains for flamegraph tools.
+
+use std::io::prelude::*;
+use std::collections::HashMap;
+use std::fs::File;
+
+use symbols::Symbols;
+
+fn is_link(r: u32) -> bool {
+    r == 1 || r == 5
+}
+
+// A JAL or JALR that writes a link register (x1 or x5) is a call.
+pub fn is_call(instruction: u32) -> bool {
+    let opcode = instruction & 0x7f;
+    let rd = (instruction & 0xf80) >> 7;
+    (opcode == 0b1101111 || opcode == 0b1100111) && is_link(rd)
+}
+
+// A JALR through a link register that discards the result is a return.
+pub fn is_return(instruction: u32) -> bool {
+    let opcode = instruction & 0x7f;
+    let rd = (instruction & 0xf80) >> 7;
+    let rs1 = (instruction & 0xf8000) >> 15;
+    opcode == 0b1100111 && rd == 0 && is_link(rs1)
+}
+
+pub struct Profiler {
+    counts: HashMap<u32, u64>,
+    total: u64,
+    // entry addresses of the active functions, outermost first
+    stack: Vec<u32>,
+    // instructions retired since the stack last changed
+    pending: u64,
+    stacks: HashMap<Vec<u32>, u64>,
+}
+
+impl Profiler {
+    pub fn new(entry: u32) -> Profiler {
+        Profiler {
+            counts: HashMap::new(),
+            total: 0,
+            stack: vec![entry],
+            pending: 0,
+            stacks: HashMap::new(),
+        }
+    }
+
+    pub fn retire(&mut self, pc: u32, instruction: u32, next_pc: u32) {
+        *self.counts.entry(pc).or_insert(0) += 1;
+        self.total += 1;
+        self.pending += 1;
+        if is_call(instruction) {
+            self.flush();
+            self.stack.push(next_pc);
+        } else if is_return(instruction) {
+            self.flush();
+            // keep the entry frame even if the program returns from it
+            if self.stack.len() > 1 {
+                self.stack.pop();
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        if self.pending > 0 {
+            *self.stacks.entry(self.stack.clone()).or_insert(0) += self.pending;
+            self.pending = 0;
+        }
+    }
+
+    fn percent(&self, count: u64) -> f64 {
+        if self.total == 0 {
+            0.0
+        } else {
+            count as f64 * 100.0 / self.total as f64
+        }
+    }
+
+    // Text report: instruction counts per symbol and per PC, hottest first.
+    pub fn write_report(&self, path: &str, symbols: &Symbols) {
+        let mut per_symbol: HashMap<String, u64> = HashMap::new();
+        for (&pc, &count) in &self.counts {
+            *per_symbol.entry(symbols.function(pc)).or_insert(0) += count;
+        }
+        let mut per_symbol: Vec<_> = per_symbol.into_iter().collect();
+        per_symbol.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        let mut per_pc: Vec<_> = self.counts.iter().map(|(&pc, &count)| (pc, count)).collect();
+        per_pc.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+
+        let mut f = match File::create(path) {
+            Ok(file) => file,
+            Err(err) => panic!("File open error: {:?}", err),
+        };
+        writeln!(f, "total instructions: {}", self.total).unwrap();
+        writeln!(f).unwrap();
+        writeln!(f, "{:>12}  {:>7}  symbol", "count", "percent").unwrap();
+        for (name, count) in per_symbol {
+            writeln!(f, "{:>12}  {:>6.2}%  {}", count, self.percent(count), name).unwrap();
+        }
+        writeln!(f).unwrap();
+        writeln!(f, "{:>12}  {:>7}  {:>10}  location", "count", "percent", "pc").unwrap();
+        for (pc, count) in per_pc {
+            writeln!(f, "{:>12}  {:>6.2}%  {:#010x}  {}",
+                     count, self.percent(count), pc, symbols.describe(pc)).unwrap();
+        }
+    }
+
+    // Folded stacks ("main;foo;bar 42" per line) for flamegraph.pl and friends.
+    pub fn write_folded(&mut self, path: &str, symbols: &Symbols) {
+        self.flush();
+        let mut merged: HashMap<String, u64> = HashMap::new();
+        for (stack, &count) in &self.stacks {
+            let frames: Vec<_> = stack.iter().map(|&addr| symbols.function(addr)).collect();
+            *merged.entry(frames.join(";")).or_insert(0) += count;
+        }
+        let mut lines: Vec<_> = merged.into_iter().collect();
+        lines.sort();
+        let mut f = match File::create(path) {
+            Ok(file) => file,
+            Err(err) => panic!("File open error: {:?}", err),
+        };
+        for (frames, count) in lines {
+            writeln!(f, "{} {}", frames, count).unwrap();
+        }
+    }
+}
@@ -0,0 +1,114 @@
+// Memory-safety checks.
+//
+// With a sanitizer attached, every load and store of a hart is checked for
+// three kinds of problems:
+//   - reading RAM that nothing has written since the machine started.  The
+//     bus keeps a shadow bit per byte of RAM, set by every write from any
+//     hart or the debugger; the segments of an ELF program and the memory
+//     of a restored checkpoint count as written.
+//   - accessing the stack below sp, i.e. a frame that has been popped.  The
+//     stack is taken to reach from sp down to the lowest sp seen so far, so
+//     a program that switches between stacks may get false reports.
+//   - an address that is not a multiple of the access width.
+// Each problem is printed the first time it happens at a pc and counted
+// after that, so a loop does not flood the output.
+
+use std::fmt;
+
+use symbols::Symbols;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Problem {
+    Uninitialized,
+    BelowStack,
+    Misaligned,
+}
+
+#[derive(Debug, Clone)]
+pub struct Report {
+    pub pc: u32,
+    pub problem: Problem,
+    // the first access with this problem at pc
+    pub addr: u32,
+    pub width: u32,
+    pub is_write: bool,
+    // sp at that access
+    pub sp: u32,
+    pub count: u64,
+}
+
+pub struct Sanitizer {
+    symbols: Symbols,
+    pub reports: Vec<Report>,
+    // lowest sp seen at an access, 0 until sp is set
+    stack_low: u32,
+    // put in front of reports, to tell harts apart
+    pub(crate) label: String,
+}
+
+impl Sanitizer {
+    pub fn new(symbols: Symbols) -> Sanitizer {
+        Sanitizer { symbols, reports: Vec::new(), stack_low: 0, label: String::new() }
+    }
+
+    // Check an access at virtual address addr; initialized tells whether
+    // every byte of it has been written before.
+    pub(crate) fn access(&mut self, pc: u32, addr: u32, width: u32, is_write: bool, sp: u32,
+                         initialized: bool) {
+        if sp != 0 && (self.stack_low == 0 || sp < self.stack_low) {
+            self.stack_low = sp;
+        }
+        let problems = [
+            (Problem::Uninitialized, !is_write && !initialized),
+            (Problem::BelowStack, sp != 0 && addr >= self.stack_low && addr < sp),
+            (Problem::Misaligned, !addr.is_multiple_of(width)),
+        ];
+        for &(problem, found) in problems.iter() {
+            if !found {
+                continue;
+            }
+            match self.reports.iter_mut().find(|r| r.pc == pc && r.problem == problem) {
+                Some(report) => report.count += 1,
+                None => {
+                    let report = Report { pc, problem, addr, width, is_write, sp, count: 1 };
+                    eprintln!("{}Sanitizer: pc {}: {}", self.label, self.location(pc), report);
+                    self.reports.push(report);
+                }
+            }
+        }
+    }
+
+    // Print how often each problem happened.
+    pub fn report(&self) {
+        if self.reports.is_empty() {
+            return;
+        }
+        eprintln!("Sanitizer summary:");
+        for r in self.reports.iter() {
+            eprintln!("  {:>8}x  {}  {}", r.count, self.location(r.pc), r);
+        }
+    }
+
+    // "0x00000010 <main+0x8>", or just the address without a symbol.
+    fn location(&self, pc: u32) -> String {
+        match self.symbols.lookup(pc) {
+            Some(_) => format!("{:#010x} <{}>", pc, self.symbols.describe(pc)),
+            None => format!("{:#010x}", pc),
+        }
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let access = if self.is_write { "write" } else { "read" };
+        match self.problem {
+            Problem::Uninitialized =>
+                write!(f, "{}-byte read of uninitialized memory at {:#010x}", self.width, self.addr),
+            Problem::BelowStack =>
+                write!(f, "{}-byte {} at {:#010x}, below sp {:#010x}", self.width, access,
+                       self.addr, self.sp),
+            Problem::Misaligned =>
+                write!(f, "misaligned {}-byte {} at {:#010x}", self.width, access, self.addr),
+        }
+    }
+}
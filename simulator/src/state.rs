@@ -0,0 +1,1057 @@
+use std::cell::RefCell;
+use std::mem;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use abi::AbiChecker;
+use bpred::Predictor;
+use bus::{Bus, RAM_SIZE};
+use cache::Cache;
+use costs::CostModel;
+use cosim::{Cosim, Divergence};
+use coverage::Coverage;
+use csr::{Csr, PRV_M};
+use decode::{decode, Decoded, Op, UNDECODED};
+use history::{History, Write};
+use mmu::{Access, Tlb};
+use pipeline::Pipeline;
+use profile::Profiler;
+use stats::Stats;
+use trap::Exception;
+use vcd::Vcd;
+use sanitize::Sanitizer;
+use watch::{Hit, Watchpoints};
+
+/// What step() reports to retire hooks about an instruction.
+#[derive(Debug, Clone, Copy)]
+pub struct Retired {
+    pub pc: u32,
+    pub instruction: u32,
+    pub next_pc: u32,
+    /// True if it raised an exception instead of completing.
+    pub trapped: bool,
+    /// True if it was a conditional branch and taken.
+    pub taken: bool,
+}
+
+/// Why run() returned.
+#[derive(Debug)]
+pub enum Stop {
+    /// The program executed exit or ran off the end of its image.
+    Exit,
+    /// The instruction limit was reached.
+    Limit,
+    /// The wall-clock timeout expired.
+    Timeout,
+    /// The instruction at this pc jumps to itself without changing any state.
+    InfiniteLoop(u32),
+    /// One or more watchpoints fired on the last instruction.
+    Watchpoint(Vec<Hit>),
+    /// The last instruction does not match the reference trace.
+    Divergence(Divergence),
+    /// The instruction at this pc raised an exception with no trap handler
+    /// to go to, see trap.rs.
+    Exception(Exception, u32),
+    /// The program stored a request to tohost that is not an exit code,
+    /// see bus.rs.
+    HostRequest(u32),
+}
+
+/// Limits for run_with().
+#[derive(Debug, Clone, Default)]
+pub struct Limits {
+    /// Maximum number of instructions to execute.
+    pub instructions: Option<u64>,
+    /// Maximum wall-clock time to run for.
+    pub timeout: Option<Duration>,
+    /// Stop at a jump-to-self that leaves every register unchanged.
+    pub detect_loops: bool,
+}
+
+type Hook = Box<dyn FnMut(&State, &Retired)>;
+
+// run_with() looks at the clock every this many instructions
+pub(crate) const TIME_CHECK: u64 = 4096;
+
+/// A single RV32IMA or RV64IMA hart with M, S and U modes, its instruction
+/// memory and the bus it shares with the other harts of the machine.
+pub struct State {
+    pub(crate) address: u32,
+    // on RV32 the values are kept sign-extended from bit 31
+    pub(crate) register: [u64; 32],
+    // 32 or 64
+    pub(crate) xlen: u32,
+    pub(crate) privilege: u32,
+    pub(crate) csr: Csr,
+    pub(crate) tlb: Tlb,
+    pub(crate) bus: Rc<RefCell<Bus>>,
+    pub(crate) imem: Vec<u32>,
+    // physical address of imem[0], page aligned
+    pub(crate) image_base: u32,
+    // the image is a copy of the program in RAM, as for ELF programs, so
+    // stores to it change the code
+    pub(crate) code_in_ram: bool,
+    // imem decoded on first use, see decode.rs
+    pub(crate) decoded: Vec<Decoded>,
+    // length of the basic block starting at each word, 0 until it is found
+    pub(crate) block_lens: Vec<u8>,
+    pub(crate) is_exit: bool,
+    pub(crate) instret: u64,
+    pub(crate) cycle: u64,
+    // whether the conditional branch executing now is taken
+    pub(crate) taken: bool,
+    pub observers: Observers,
+    // set by step() when the cosim finds a difference
+    divergence: Option<Divergence>,
+    // the exception that stopped the hart, see trap.rs
+    pub(crate) unhandled: Option<Exception>,
+    pub(crate) history: Option<History>,
+    /// Run a basic block at a time when nothing watches single
+    /// instructions, see block.rs.
+    pub blocks: bool,
+    pub(crate) quiet: bool,
+    // put in front of every output line, to tell harts apart
+    pub(crate) label: String,
+    output: Option<Vec<String>>,
+}
+
+/// The models and tools that watch a hart execute.  They are not part of
+/// the architectural state, and reverse execution detaches all of them
+/// while it replays history, see history.rs.
+#[derive(Default)]
+pub struct Observers {
+    pub icache: Option<Cache>,
+    pub dcache: Option<Cache>,
+    pub bpred: Option<Predictor>,
+    pub costs: Option<CostModel>,
+    pub pipeline: Option<Pipeline>,
+    pub profiler: Option<Profiler>,
+    pub stats: Option<Stats>,
+    pub coverage: Option<Coverage>,
+    pub vcd: Option<Vcd>,
+    pub cosim: Option<Cosim>,
+    pub watch: Option<Watchpoints>,
+    pub sanitizer: Option<Sanitizer>,
+    pub abi: Option<AbiChecker>,
+    hooks: Vec<Hook>,
+}
+
+impl Observers {
+    // True if a timing model is attached, which report() prints.
+    pub(crate) fn has_timing(&self) -> bool {
+        self.icache.is_some() || self.dcache.is_some() || self.bpred.is_some()
+            || self.costs.is_some() || self.pipeline.is_some()
+    }
+}
+
+impl State {
+    /// A machine with the given program image, starting at address 0.
+    pub fn init(instructions: Vec<u32>) -> State {
+        State::with_bus(instructions, Rc::new(RefCell::new(Bus::new(1, RAM_SIZE))), 0)
+    }
+
+    /// Hart `hartid` of a machine, attached to a shared bus.  It starts at
+    /// address 0 with its hart ID in a0.
+    pub(crate) fn with_bus(instructions: Vec<u32>, bus: Rc<RefCell<Bus>>, hartid: u32) -> State {
+        let mut register = [0; 32];
+        register[10] = hartid as u64;
+        let decoded = vec![UNDECODED; instructions.len()];
+        let block_lens = vec![0; instructions.len()];
+        State {
+            address: 0,
+            register,
+            xlen: 32,
+            privilege: PRV_M,
+            csr: Csr::new(hartid),
+            tlb: Tlb::default(),
+            bus,
+            imem: instructions,
+            image_base: 0,
+            code_in_ram: false,
+            decoded,
+            block_lens,
+            is_exit: false,
+            instret: 0,
+            cycle: 0,
+            taken: false,
+            observers: Observers::default(),
+            divergence: None,
+            unhandled: None,
+            history: None,
+            blocks: false,
+            quiet: false,
+            label: String::new(),
+            output: None,
+        }
+    }
+
+    /// Replace the program image and restart execution at address 0.
+    /// Registers and data memory are left as they are.
+    pub fn load_image(&mut self, instructions: Vec<u32>) {
+        self.set_image(instructions);
+        self.address = 0;
+        self.is_exit = false;
+    }
+
+    pub(crate) fn set_image(&mut self, instructions: Vec<u32>) {
+        self.decoded = vec![UNDECODED; instructions.len()];
+        self.block_lens = vec![0; instructions.len()];
+        self.imem = instructions;
+    }
+
+    /// Replace the instruction at physical address addr in the program
+    /// image.  Does nothing outside the image.
+    pub fn write_instruction(&mut self, addr: u32, instruction: u32) {
+        let index = (addr.wrapping_sub(self.image_base) / 4) as usize;
+        if index < self.imem.len() {
+            self.imem[index] = instruction;
+            self.decoded[index] = UNDECODED;
+            self.invalidate_blocks(index);
+        }
+    }
+
+    // Put a word that a disk read wrote to RAM into the program image too,
+    // growing the image up to it if it lies past the end.
+    fn load_instruction(&mut self, addr: u32, word: u32) {
+        if addr < self.image_base {
+            return;
+        }
+        let index = ((addr - self.image_base) / 4) as usize;
+        if index >= self.imem.len() {
+            self.imem.resize(index + 1, 0);
+            self.decoded.resize(index + 1, UNDECODED);
+            self.block_lens.resize(index + 1, 0);
+        }
+        self.write_instruction(addr, word);
+    }
+
+    // Copy the words a store to RAM changed into the program image, if the
+    // image lives in RAM.
+    fn store_instructions(&mut self, paddr: u32, width: u32) {
+        if !self.code_in_ram {
+            return;
+        }
+        // a misaligned store can reach into a second word
+        let (first, last) = (paddr & !3, paddr.wrapping_add(width - 1) & !3);
+        self.refresh_instruction(first);
+        if last != first {
+            self.refresh_instruction(last);
+        }
+    }
+
+    // Bring the program image word at addr up to date with RAM.
+    fn refresh_instruction(&mut self, addr: u32) {
+        let old = match self.image_word(addr) {
+            Some(old) => old,
+            None => return,
+        };
+        let new = match self.bus.borrow().read(addr, 4) {
+            Some(new) => new,
+            None => return,
+        };
+        if new != old {
+            if let Some(ref mut history) = self.history {
+                history.record(Write::Image { addr, old, new });
+            }
+            self.write_instruction(addr, new);
+        }
+    }
+
+    // Forget all decoded instructions.
+    fn flush_decoded(&mut self) {
+        for d in self.decoded.iter_mut() {
+            *d = UNDECODED;
+        }
+        for b in self.block_lens.iter_mut() {
+            *b = 0;
+        }
+    }
+
+    // The word of the program image at physical address addr.
+    pub(crate) fn image_word(&self, addr: u32) -> Option<u32> {
+        self.imem.get((addr.wrapping_sub(self.image_base) / 4) as usize).cloned()
+    }
+
+    pub fn pc(&self) -> u32 {
+        self.address
+    }
+
+    pub fn set_pc(&mut self, pc: u32) {
+        self.address = pc;
+    }
+
+    /// Width of the integer registers in bits, 32 or 64.
+    pub fn xlen(&self) -> u32 {
+        self.xlen
+    }
+
+    /// Make this an RV32 or an RV64 hart.  The registers keep their values,
+    /// cut down to 32 bits for RV32.
+    pub fn set_xlen(&mut self, xlen: u32) {
+        if xlen != 32 && xlen != 64 {
+            panic!("XLEN must be 32 or 64, not {}", xlen);
+        }
+        self.xlen = xlen;
+        for i in 0..32 {
+            self.register[i] = self.sext_xlen(self.register[i]);
+        }
+        self.flush_decoded();
+    }
+
+    /// Value of integer register x`index`, XLEN bits wide.
+    pub fn register(&self, index: usize) -> u64 {
+        self.zext_xlen(self.register[index])
+    }
+
+    /// Set integer register x`index`; RV32 uses the low 32 bits of value.
+    /// Writes to x0 are ignored.
+    pub fn set_register(&mut self, index: usize, value: u64) {
+        if index != 0 {
+            self.register[index] = self.sext_xlen(value);
+        }
+    }
+
+    // A result cut down to XLEN bits, the way registers hold it.
+    #[inline(always)]
+    fn sext_xlen(&self, value: u64) -> u64 {
+        if self.xlen == 32 { value as i32 as u64 } else { value }
+    }
+
+    // The XLEN bits of a register value, for the unsigned operations.
+    #[inline(always)]
+    fn zext_xlen(&self, value: u64) -> u64 {
+        if self.xlen == 32 { value as u32 as u64 } else { value }
+    }
+
+    /// Read 1, 2 or 4 bytes from the bus, bypassing caches and watchpoints.
+    /// None if nothing answers at addr.
+    pub fn read_memory(&self, addr: u32, width: u32) -> Option<u32> {
+        self.bus.borrow().read(addr, width)
+    }
+
+    /// Write 1, 2 or 4 bytes to the bus, bypassing caches and watchpoints.
+    /// None if nothing answers at addr.
+    pub fn write_memory(&mut self, addr: u32, value: u32, width: u32) -> Option<()> {
+        self.bus.borrow_mut().write(addr, value, width)
+    }
+
+    /// Number of retired instructions.
+    pub fn instret(&self) -> u64 {
+        self.instret
+    }
+
+    /// Cycles according to the timing model: one per instruction, or the
+    /// cost model's cycles, plus any cache miss and branch misprediction
+    /// penalties.
+    pub fn cycle(&self) -> u64 {
+        self.cycle
+    }
+
+    /// Call hook after every retired instruction.
+    pub fn on_retire<F>(&mut self, hook: F) where F: FnMut(&State, &Retired) + 'static {
+        self.observers.hooks.push(Box::new(hook));
+    }
+
+    /// Collect the program's output lines instead of printing them.
+    pub fn capture_output(&mut self) {
+        self.output = Some(Vec::new());
+    }
+
+    /// Output collected since the last call, see capture_output().
+    pub fn take_output(&mut self) -> Vec<String> {
+        match self.output {
+            Some(ref mut out) => mem::take(out),
+            None => Vec::new(),
+        }
+    }
+
+    fn emit(&mut self, line: String) {
+        if self.quiet {
+            return;
+        }
+        let line = format!("{}{}", self.label, line);
+        match self.output {
+            Some(ref mut out) => out.push(line),
+            None => println!("{}", line),
+        }
+    }
+
+    /// Run until the program exits, `limit` instructions have been executed
+    /// or a watchpoint fires.
+    pub fn run(&mut self, limit: Option<u64>) -> Stop {
+        self.run_with(&Limits { instructions: limit, ..Limits::default() })
+    }
+
+    /// Run until the program exits, a watchpoint fires or one of the limits
+    /// is hit.
+    pub fn run_with(&mut self, limits: &Limits) -> Stop {
+        let start = Instant::now();
+        let mut executed = 0;
+        // registers after the last jump-to-self, if the last instruction was one
+        let mut last_loop: Option<[u64; 32]> = None;
+        while !self.is_exit() {
+            if limits.instructions == Some(executed) {
+                return Stop::Limit;
+            }
+            // checking the clock on every instruction would be too slow
+            if let Some(timeout) = limits.timeout {
+                if start.elapsed() >= timeout {
+                    return Stop::Timeout;
+                }
+            }
+            let n = limits.instructions.map_or(TIME_CHECK, |l| (l - executed).min(TIME_CHECK));
+            let (count, stop) = self.run_some(n, limits.detect_loops, &mut last_loop);
+            executed += count;
+            if let Some(stop) = stop {
+                return stop;
+            }
+        }
+        self.stopped().unwrap_or(Stop::Exit)
+    }
+
+    // Execute up to n instructions for run_with(), stopping early if the
+    // program exits or a watchpoint fires or an infinite loop is detected.
+    // Returns the number executed.  `last_loop` holds the registers after the
+    // last jump-to-self, if the last instruction was one.
+    pub(crate) fn run_some(&mut self, n: u64, detect_loops: bool,
+                           last_loop: &mut Option<[u64; 32]>) -> (u64, Option<Stop>) {
+        // without anything watching single instructions, skip step()'s
+        // bookkeeping
+        let o = &self.observers;
+        let observed = self.history.is_some() || o.watch.is_some() || !o.hooks.is_empty()
+            || o.profiler.is_some() || o.stats.is_some() || o.coverage.is_some() || o.abi.is_some()
+            || o.vcd.is_some() || o.cosim.is_some() || o.pipeline.is_some();
+        let mut executed = 0;
+        while executed < n && !self.is_exit() {
+            // the number of instructions executed and the pc of the last one
+            let (count, pc) = if observed {
+                let pc = self.address;
+                self.step();
+                let hits = self.watch_hits();
+                if !hits.is_empty() {
+                    return (executed + 1, Some(Stop::Watchpoint(hits)));
+                }
+                if let Some(divergence) = self.divergence.take() {
+                    return (executed + 1, Some(Stop::Divergence(divergence)));
+                }
+                (1, pc)
+            } else if self.blocks {
+                self.run_block(n - executed)
+            } else {
+                (1, self.execute_next().pc)
+            };
+            executed += count;
+            if detect_loops {
+                // only the last instruction of a block can be a jump
+                if count > 1 {
+                    *last_loop = None;
+                }
+                // Only branches and jumps can land on their own pc, and they
+                // never write memory, so an unchanged register file means the
+                // next iteration will do exactly the same again, unless an
+                // interrupt gets in the way.
+                if self.address == pc && !self.is_exit && !self.interrupts_enabled() {
+                    if *last_loop == Some(self.register) {
+                        return (executed, Some(Stop::InfiniteLoop(pc)));
+                    }
+                    *last_loop = Some(self.register);
+                } else {
+                    *last_loop = None;
+                }
+            }
+        }
+        (executed, self.stopped())
+    }
+
+    // Why the hart stopped, if it was not a plain exit.
+    pub(crate) fn stopped(&self) -> Option<Stop> {
+        if let Some(exception) = self.unhandled {
+            return Some(Stop::Exception(exception, self.address));
+        }
+        self.bus.borrow().bad_request.map(Stop::HostRequest)
+    }
+
+    pub fn show_register(&self) {
+        for i in 0..32 {
+            println!("reg{:02}: {:0width$b}", i, self.register(i), width = self.xlen as usize);
+        }
+    }
+
+    /// True once the program has exited, or stopped on an exception it has
+    /// no trap handler for.
+    pub fn is_exit(&self) -> bool {
+        // with paging, a pc outside the image is a fault, not the end
+        let index = (self.address.wrapping_sub(self.image_base) / 4) as usize;
+        self.is_exit || self.unhandled.is_some() || (index >= self.imem.len() && !self.paging())
+    }
+
+    /// Execute one instruction, or the first instruction of the trap
+    /// handler if an interrupt is taken.
+    pub fn step(&mut self) {
+        self.begin_record();
+        let retired = self.execute_next();
+        let (pc, instruction) = (retired.pc, retired.instruction);
+        if let Some(ref mut profiler) = self.observers.profiler {
+            profiler.retire(pc, instruction, self.address);
+        }
+        if let Some(ref mut stats) = self.observers.stats {
+            stats.retire(&retired, self.register[2] as u32);
+        }
+        if let Some(ref mut coverage) = self.observers.coverage {
+            coverage.retire(&retired);
+        }
+        if let Some(ref mut pipeline) = self.observers.pipeline {
+            pipeline.retire(&retired, self.xlen);
+        }
+        if self.observers.abi.is_some() {
+            let register = self.register.map(|r| self.zext_xlen(r));
+            if let Some(ref mut abi) = self.observers.abi {
+                abi.retire(pc, instruction, self.address, &register);
+            }
+        }
+        if let Some(mut cosim) = self.observers.cosim.take() {
+            self.divergence = cosim.retire(self, &retired);
+            self.observers.cosim = Some(cosim);
+        }
+        if let Some(mut vcd) = self.observers.vcd.take() {
+            vcd.retire(self, &retired);
+            self.observers.vcd = Some(vcd);
+        }
+        self.end_record();
+        if !self.observers.hooks.is_empty() {
+            let mut hooks = mem::take(&mut self.observers.hooks);
+            for hook in hooks.iter_mut() {
+                hook(self, &retired);
+            }
+            self.observers.hooks = hooks;
+        }
+    }
+
+    // The core of step(): take a pending interrupt, then fetch, execute and
+    // retire one instruction.  The instruction is 0 if the fetch failed.
+    #[inline(always)]
+    fn execute_next(&mut self) -> Retired {
+        if self.csr.mie != 0 {
+            self.check_interrupts();
+        }
+        let pc = self.address;
+        let mut trapped = false;
+        self.taken = false;
+        let instruction = match self.fetch(pc) {
+            Ok(d) => {
+                if let Err(exception) = self.execute(&d) {
+                    self.raise(exception);
+                    trapped = true;
+                }
+                self.cycle += self.cost(d.op);
+                d.raw
+            }
+            Err(exception) => {
+                self.raise(exception);
+                trapped = true;
+                self.cycle += 1;
+                0
+            }
+        };
+        self.instret += 1;
+        self.bus.borrow_mut().tick();
+        Retired { pc, instruction, next_pc: self.address, trapped, taken: self.taken }
+    }
+
+    // Cycles of the instruction that just executed: one, or what the cost
+    // model charges for it.
+    #[inline(always)]
+    pub(crate) fn cost(&mut self, op: Op) -> u64 {
+        match self.observers.costs {
+            Some(ref mut costs) => costs.charge(op, self.taken),
+            None => 1,
+        }
+    }
+
+    // Index in the program image and physical address of the instruction
+    // at pc.
+    #[inline(always)]
+    pub(crate) fn fetch_index(&mut self, pc: u32) -> Result<(usize, u32), Exception> {
+        let addr = self.translate(pc, 4, Access::Fetch)?;
+        let index = (addr.wrapping_sub(self.image_base) / 4) as usize;
+        if index >= self.imem.len() {
+            return Err(Exception::InstructionAccessFault(pc as u64));
+        }
+        Ok((index, addr))
+    }
+
+    #[inline(always)]
+    fn fetch(&mut self, pc: u32) -> Result<Decoded, Exception> {
+        let (index, addr) = self.fetch_index(pc)?;
+        if let Some(ref mut cache) = self.observers.icache {
+            self.cycle += cache.access(addr, false);
+        }
+        let mut d = self.decoded[index];
+        if d.op == Op::Undecoded {
+            d = decode(self.imem[index], self.xlen);
+            self.decoded[index] = d;
+        }
+        Ok(d)
+    }
+
+    #[inline(always)]
+    pub(crate) fn execute(&mut self, d: &Decoded) -> Result<(), Exception> {
+        let rs1 = self.register[d.rs1 as usize];
+        let rs2 = self.register[d.rs2 as usize];
+        let rd = d.rd as u32;
+        let imm = d.imm as i32 as u64;
+        // the bits of rs2 that shift amounts use
+        let shift = rs2 & (self.xlen as u64 - 1);
+        let value = match d.op {
+            Op::Lui => imm,
+            Op::Auipc => (self.address as u64).wrapping_add(imm),
+            Op::Jal => return self.jump(rd, (self.address as u64).wrapping_add(imm), None),
+            Op::Jalr => return self.jump(rd, rs1.wrapping_add(imm) & !1, Some(d.rs1 as u32)),
+            Op::Beq => return self.branch(rs1 == rs2, imm),
+            Op::Bne => return self.branch(rs1 != rs2, imm),
+            Op::Blt => return self.branch((rs1 as i64) < (rs2 as i64), imm),
+            Op::Bge => return self.branch((rs1 as i64) >= (rs2 as i64), imm),
+            Op::Bltu => return self.branch(rs1 < rs2, imm),
+            Op::Bgeu => return self.branch(rs1 >= rs2, imm),
+            Op::Lb | Op::Lh | Op::Lw | Op::Lbu | Op::Lhu | Op::Lwu | Op::Ld => {
+                let addr = self.data_address(rs1.wrapping_add(imm), Access::Load)?;
+                match d.op {
+                    Op::Lb => self.load(addr, 1)? as i8 as u64,
+                    Op::Lh => self.load(addr, 2)? as i16 as u64,
+                    Op::Lw => self.load(addr, 4)? as i32 as u64,
+                    Op::Lbu => self.load(addr, 1)? as u64,
+                    Op::Lhu => self.load(addr, 2)? as u64,
+                    Op::Lwu => self.load(addr, 4)? as u64,
+                    _ => {
+                        let paddr = self.translate(addr, 8, Access::Load)?;
+                        self.load_wide(addr, paddr, 8)?
+                    }
+                }
+            }
+            Op::Sb | Op::Sh | Op::Sw | Op::Sd => {
+                let addr = self.data_address(rs1.wrapping_add(imm), Access::Store)?;
+                match d.op {
+                    Op::Sb => self.store(addr, rs2 as u32, 1)?,
+                    Op::Sh => self.store(addr, rs2 as u32, 2)?,
+                    Op::Sw => self.store(addr, rs2 as u32, 4)?,
+                    _ => {
+                        let paddr = self.translate(addr, 8, Access::Store)?;
+                        self.store_at(addr, paddr, rs2, 8)?;
+                    }
+                }
+                self.address += 4;
+                return Ok(());
+            }
+            Op::Addi => rs1.wrapping_add(imm),
+            Op::Slti => ((rs1 as i64) < (imm as i64)) as u64,
+            Op::Sltiu => (rs1 < imm) as u64,
+            Op::Xori => rs1 ^ imm,
+            Op::Ori => rs1 | imm,
+            Op::Andi => rs1 & imm,
+            Op::Slli => rs1 << d.imm,
+            Op::Srli => self.zext_xlen(rs1) >> d.imm,
+            Op::Srai => ((rs1 as i64) >> d.imm) as u64,
+            Op::Add => rs1.wrapping_add(rs2),
+            Op::Sub => rs1.wrapping_sub(rs2),
+            Op::Sll => rs1 << shift,
+            Op::Slt => ((rs1 as i64) < (rs2 as i64)) as u64,
+            Op::Sltu => (rs1 < rs2) as u64,
+            Op::Xor => rs1 ^ rs2,
+            Op::Srl => self.zext_xlen(rs1) >> shift,
+            Op::Sra => ((rs1 as i64) >> shift) as u64,
+            Op::Or => rs1 | rs2,
+            Op::And => rs1 & rs2,
+            Op::Mul => rs1.wrapping_mul(rs2),
+            // the high halves come from the full product, so RV32 shifts
+            // it by 32
+            Op::Mulh => ((rs1 as i64 as i128 * rs2 as i64 as i128) >> self.xlen) as u64,
+            Op::Mulhsu => ((rs1 as i64 as i128 * self.zext_xlen(rs2) as i128) >> self.xlen) as u64,
+            Op::Mulhu => ((self.zext_xlen(rs1) as u128 * self.zext_xlen(rs2) as u128) >> self.xlen) as u64,
+            // division by zero gives all ones and the dividend as the
+            // remainder; the signed overflow wraps
+            Op::Div if rs2 == 0 => u64::MAX,
+            Op::Div => (rs1 as i64).wrapping_div(rs2 as i64) as u64,
+            Op::Rem if rs2 == 0 => rs1,
+            Op::Rem => (rs1 as i64).wrapping_rem(rs2 as i64) as u64,
+            Op::Divu | Op::Remu => {
+                let (a, b) = (self.zext_xlen(rs1), self.zext_xlen(rs2));
+                match (d.op, b) {
+                    (Op::Divu, 0) => u64::MAX,
+                    (Op::Divu, _) => a / b,
+                    (_, 0) => a,
+                    _ => a % b,
+                }
+            }
+            Op::Addiw => rs1.wrapping_add(imm) as i32 as u64,
+            Op::Slliw => ((rs1 as u32) << d.imm) as i32 as u64,
+            Op::Srliw => ((rs1 as u32) >> d.imm) as i32 as u64,
+            Op::Sraiw => ((rs1 as i32) >> d.imm) as u64,
+            Op::Addw => rs1.wrapping_add(rs2) as i32 as u64,
+            Op::Subw => rs1.wrapping_sub(rs2) as i32 as u64,
+            Op::Sllw => ((rs1 as u32) << (rs2 & 0x1f)) as i32 as u64,
+            Op::Srlw => ((rs1 as u32) >> (rs2 & 0x1f)) as i32 as u64,
+            Op::Sraw => ((rs1 as i32) >> (rs2 & 0x1f)) as u64,
+            Op::Mulw => rs1.wrapping_mul(rs2) as i32 as u64,
+            Op::Divw | Op::Remw => {
+                let (a, b) = (rs1 as i32, rs2 as i32);
+                match (d.op, b) {
+                    (Op::Divw, 0) => u64::MAX,
+                    (Op::Divw, _) => a.wrapping_div(b) as u64,
+                    (_, 0) => a as u64,
+                    _ => a.wrapping_rem(b) as u64,
+                }
+            }
+            Op::Divuw | Op::Remuw => {
+                let (a, b) = (rs1 as u32, rs2 as u32);
+                match (d.op, b) {
+                    (Op::Divuw, 0) => u64::MAX,
+                    (Op::Divuw, _) => (a / b) as i32 as u64,
+                    (_, 0) => a as i32 as u64,
+                    _ => (a % b) as i32 as u64,
+                }
+            }
+            // memory is sequentially consistent, so fence has nothing to do
+            Op::Fence | Op::FenceI => {
+                if d.op == Op::FenceI {
+                    // other harts may have changed the code in RAM
+                    if self.code_in_ram {
+                        for i in 0..self.imem.len() {
+                            self.refresh_instruction(self.image_base + 4 * i as u32);
+                        }
+                    }
+                    self.flush_decoded();
+                }
+                self.address += 4;
+                return Ok(());
+            }
+            Op::System => return self.exec_system(d.raw),
+            Op::Amo => return self.exec_amo(d.raw),
+            Op::Exit => {
+                self.is_exit = true;
+                self.emit("Exit.".to_string());
+                return Ok(());
+            }
+            Op::PrintInt => {
+                let value = self.register(rd as usize);
+                self.emit(format!("print_int: {}", value));
+                self.address += 4;
+                return Ok(());
+            }
+            Op::Illegal | Op::Undecoded => return Err(Exception::IllegalInstruction(d.raw)),
+        };
+        self.write_register(rd, value);
+        self.address += 4;
+        Ok(())
+    }
+
+    // The pc a jump or taken branch goes to.  Addresses wrap around on RV32;
+    // on RV64 the ones past the 32-bit physical address space fault, as
+    // there is no Sv39, so the trap reports the jump's own pc.
+    fn code_address(&self, target: u64) -> Result<u32, Exception> {
+        if self.xlen == 32 || target >> 32 == 0 {
+            Ok(target as u32)
+        } else {
+            Err(Exception::InstructionAccessFault(target))
+        }
+    }
+
+    // The virtual address of a load or store, likewise.
+    fn data_address(&self, addr: u64, access: Access) -> Result<u32, Exception> {
+        if self.xlen == 32 || addr >> 32 == 0 {
+            Ok(addr as u32)
+        } else {
+            Err(access.access_fault(addr))
+        }
+    }
+
+    fn branch(&mut self, taken: bool, offset: u64) -> Result<(), Exception> {
+        self.taken = taken;
+        let target = (self.address as u64).wrapping_add(offset);
+        let target = if taken { self.code_address(target)? } else { target as u32 };
+        if taken && !target.is_multiple_of(4) {
+            return Err(Exception::InstructionAddressMisaligned(target as u64));
+        }
+        if let Some(ref mut bpred) = self.observers.bpred {
+            if !bpred.branch(self.address, target, taken) {
+                self.cycle += bpred.penalty;
+                if let Some(ref mut pipeline) = self.observers.pipeline {
+                    pipeline.mispredict();
+                }
+            }
+        }
+        self.address = if taken { target } else { self.address + 4 };
+        Ok(())
+    }
+
+    // jal (rs1 is None) and jalr
+    fn jump(&mut self, rd: u32, target: u64, rs1: Option<u32>) -> Result<(), Exception> {
+        let target = self.code_address(target)?;
+        if !target.is_multiple_of(4) {
+            return Err(Exception::InstructionAddressMisaligned(target as u64));
+        }
+        if let Some(ref mut bpred) = self.observers.bpred {
+            // the target of jal is known at decode, so only jalr can mispredict
+            if !bpred.jump(self.address, target, rd, rs1) && rs1.is_some() {
+                self.cycle += bpred.penalty;
+                if let Some(ref mut pipeline) = self.observers.pipeline {
+                    pipeline.mispredict();
+                }
+            }
+        }
+        let link = self.address as u64 + 4;
+        self.write_register(rd, link);
+        self.address = target;
+        Ok(())
+    }
+
+    /// Watchpoint hits of the last instruction, if any fired.
+    pub fn watch_hits(&mut self) -> Vec<Hit> {
+        match self.observers.watch {
+            Some(ref mut watch) => mem::take(&mut watch.hits),
+            None => Vec::new(),
+        }
+    }
+
+    pub(crate) fn write_register(&mut self, rd: u32, value: u64) {
+        if rd != 0 {
+            let value = self.sext_xlen(value);
+            let old = self.register[rd as usize];
+            if let Some(ref mut history) = self.history {
+                history.record(Write::Reg { index: rd, old, new: value });
+            }
+            if let Some(ref mut watch) = self.observers.watch {
+                let mask = u64::MAX >> (64 - self.xlen);
+                watch.register(self.address, rd, old & mask, value & mask);
+            }
+            self.register[rd as usize] = value;
+        }
+    }
+
+    // little endian access of 1, 2 or 4 bytes at virtual address addr
+    fn load(&mut self, addr: u32, width: u32) -> Result<u32, Exception> {
+        let paddr = self.translate(addr, width, Access::Load)?;
+        self.load_at(addr, paddr, width).map(|value| value as u32)
+    }
+
+    fn store(&mut self, addr: u32, value: u32, width: u32) -> Result<(), Exception> {
+        let paddr = self.translate(addr, width, Access::Store)?;
+        self.store_at(addr, paddr, value as u64, width)
+    }
+
+    // The physical side of a load of 1, 2, 4 or 8 bytes: caches and
+    // watchpoints see the physical and the virtual address respectively.
+    fn load_at(&mut self, addr: u32, paddr: u32, width: u32) -> Result<u64, Exception> {
+        if self.observers.sanitizer.is_some() {
+            self.sanitize(addr, paddr, width, false);
+        }
+        let value = match self.bus.borrow().read_wide(paddr, width) {
+            Some(value) => value,
+            None => return Err(Exception::LoadAccessFault(addr as u64)),
+        };
+        let penalty = match self.observers.dcache {
+            Some(ref mut cache) => cache.access(paddr, false),
+            None => 0,
+        };
+        self.cycle += penalty;
+        if let Some(ref mut pipeline) = self.observers.pipeline {
+            pipeline.access(paddr, false, penalty);
+        }
+        if let Some(ref mut watch) = self.observers.watch {
+            watch.memory(self.address, addr, width, value, value, false);
+        }
+        if let Some(ref mut vcd) = self.observers.vcd {
+            vcd.load(paddr, value);
+        }
+        Ok(value)
+    }
+
+    fn store_at(&mut self, addr: u32, paddr: u32, value: u64, width: u32) -> Result<(), Exception> {
+        if self.observers.sanitizer.is_some() {
+            self.sanitize(addr, paddr, width, true);
+        }
+        let value = if width == 8 { value } else { value & ((1 << (8 * width)) - 1) };
+        // a doubleword goes to the bus as two words, once both answer
+        if width == 8 || self.observers.watch.is_some() {
+            let old = match self.bus.borrow().read_wide(paddr, width) {
+                Some(old) => old,
+                None => return Err(Exception::StoreAccessFault(addr as u64)),
+            };
+            if let Some(ref mut watch) = self.observers.watch {
+                watch.memory(self.address, addr, width, old, value, true);
+            }
+        }
+        let parts = [(paddr, value as u32, width.min(4)),
+                     (paddr.wrapping_add(4), (value >> 32) as u32, 4)];
+        for &(paddr, part, width) in parts[..(width / 8 + 1) as usize].iter() {
+            if self.store_part(paddr, part, width).is_none() {
+                return Err(Exception::StoreAccessFault(addr as u64));
+            }
+        }
+        let penalty = match self.observers.dcache {
+            Some(ref mut cache) => cache.access(paddr, true),
+            None => 0,
+        };
+        self.cycle += penalty;
+        if let Some(ref mut pipeline) = self.observers.pipeline {
+            pipeline.access(paddr, true, penalty);
+        }
+        if let Some(ref mut vcd) = self.observers.vcd {
+            vcd.store(paddr, value, width);
+        }
+        Ok(())
+    }
+
+    // Store 1, 2 or 4 bytes to the bus, recording history and keeping the
+    // program image up to date.
+    fn store_part(&mut self, paddr: u32, value: u32, width: u32) -> Option<()> {
+        if let Some(ref mut history) = self.history {
+            let mut bus = self.bus.borrow_mut();
+            let old = bus.read(paddr, width)?;
+            if bus.has_effects(paddr, width) {
+                history.record(Write::Devices(Box::new(bus.save_devices(paddr, value, width))));
+                bus.journal = Some(Vec::new());
+            }
+            history.record(Write::Mem { addr: paddr, width, old, new: value });
+        }
+        let loaded = {
+            let mut bus = self.bus.borrow_mut();
+            bus.write(paddr, value, width)?;
+            // the program reported its result, or asked for something
+            // else, through tohost
+            if bus.host_exit.is_some() || bus.bad_request.is_some() {
+                self.is_exit = true;
+            }
+            if let (Some(history), Some(journal)) = (self.history.as_mut(), bus.journal.take()) {
+                for (addr, old, new) in journal {
+                    history.record(Write::Mem { addr, width: 4, old, new });
+                }
+            }
+            mem::take(&mut bus.loaded)
+        };
+        self.store_instructions(paddr, width);
+        for (addr, word) in loaded {
+            let old = self.image_word(addr).unwrap_or(0);
+            if let Some(ref mut history) = self.history {
+                history.record(Write::Image { addr, old, new: word });
+            }
+            self.load_instruction(addr, word);
+        }
+        Some(())
+    }
+
+    // A word, sign-extended, or a doubleword at a translated address.
+    fn load_wide(&mut self, addr: u32, paddr: u32, width: u32) -> Result<u64, Exception> {
+        let value = self.load_at(addr, paddr, width)?;
+        Ok(if width == 4 { value as i32 as u64 } else { value })
+    }
+
+    fn sanitize(&mut self, addr: u32, paddr: u32, width: u32, is_write: bool) {
+        let initialized = self.bus.borrow().is_written(paddr, width);
+        let (pc, sp) = (self.address, self.register[2] as u32);
+        if let Some(ref mut sanitizer) = self.observers.sanitizer {
+            sanitizer.access(pc, addr, width, is_write, sp, initialized);
+        }
+    }
+
+    fn exec_amo(&mut self, instruction: u32) -> Result<(), Exception> {
+        let funct3 = (instruction & 0x7000) >> 12;
+        let rd =     (instruction & 0xf80) >> 7;
+        let rs1 =    (instruction & 0xf8000) >> 15;
+        let rs2 =    (instruction & 0x1f00000) >> 20;
+        let funct5 = (instruction & 0xf8000000) >> 27;
+
+        // the .w forms, and the .d forms on RV64
+        let width = match funct3 {
+            0b010 => 4,
+            0b011 if self.xlen == 64 => 8,
+            _ => return Err(Exception::IllegalInstruction(instruction)),
+        };
+        let base = self.register[rs1 as usize];
+        let src = self.register[rs2 as usize];
+        let hart = self.csr.mhartid as usize;
+        // the .w forms work on sign-extended words, which keeps the order of
+        // both the signed and the unsigned comparisons
+        let src = if width == 4 { src as i32 as u64 } else { src };
+        let op: fn(u64, u64) -> u64 = match funct5 {
+            0b00010 => {
+                // lr
+                let addr = self.data_address(base, Access::Load)?;
+                if !addr.is_multiple_of(width) {
+                    return Err(Exception::LoadAddressMisaligned(addr as u64));
+                }
+                let paddr = self.translate(addr, width, Access::Load)?;
+                let value = self.load_wide(addr, paddr, width)?;
+                self.bus.borrow_mut().reservations[hart] = Some((paddr, width));
+                self.write_register(rd, value);
+                self.address += 4;
+                return Ok(());
+            }
+            0b00011 => {
+                // sc
+                let addr = self.data_address(base, Access::Store)?;
+                if !addr.is_multiple_of(width) {
+                    return Err(Exception::StoreAddressMisaligned(addr as u64));
+                }
+                let paddr = self.translate(addr, width, Access::Store)?;
+                let reserved = self.bus.borrow_mut().reservations[hart].take() == Some((paddr, width));
+                if reserved {
+                    self.store_at(addr, paddr, src, width)?;
+                }
+                self.write_register(rd, !reserved as u64);
+                self.address += 4;
+                return Ok(());
+            }
+            0b00001 => |_, b| b,
+            0b00000 => |a, b| a.wrapping_add(b),
+            0b00100 => |a, b| a ^ b,
+            0b01100 => |a, b| a & b,
+            0b01000 => |a, b| a | b,
+            0b10000 => |a, b| (a as i64).min(b as i64) as u64,
+            0b10100 => |a, b| (a as i64).max(b as i64) as u64,
+            0b11000 => |a, b| a.min(b),
+            0b11100 => |a, b| a.max(b),
+            _       => return Err(Exception::IllegalInstruction(instruction)),
+        };
+        let addr = self.data_address(base, Access::Store)?;
+        if !addr.is_multiple_of(width) {
+            return Err(Exception::StoreAddressMisaligned(addr as u64));
+        }
+        // an AMO faults as a store even when its read fails
+        let paddr = self.translate(addr, width, Access::Store)?;
+        let old = self.load_wide(addr, paddr, width).map_err(|_| Exception::StoreAccessFault(addr as u64))?;
+        self.store_at(addr, paddr, op(old, src), width)?;
+        self.write_register(rd, old);
+        self.address += 4;
+        Ok(())
+    }
+
+    /// Print the cache and branch predictor reports, if those are enabled.
+    pub fn report(&self) {
+        if let Some(ref sanitizer) = self.observers.sanitizer {
+            sanitizer.report();
+        }
+        if let Some(ref abi) = self.observers.abi {
+            abi.report();
+        }
+        if !self.observers.has_timing() {
+            return;
+        }
+        eprintln!("instructions: {}", self.instret);
+        eprintln!("cycles:       {}", self.cycle);
+        if let Some(ref costs) = self.observers.costs {
+            costs.report();
+        }
+        if let Some(ref pipeline) = self.observers.pipeline {
+            pipeline.report();
+        }
+        if let Some(ref cache) = self.observers.icache {
+            cache.report();
+        }
+        if let Some(ref cache) = self.observers.dcache {
+            cache.report();
+        }
+        if let Some(ref bpred) = self.observers.bpred {
+            bpred.report();
+        }
+    }
+}
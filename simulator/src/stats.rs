@@ -0,0 +1,128 @@
+// Dynamic instruction statistics, printed at exit with --stats or written
+// as JSON with --stats-json.
+
+use std::io::prelude::*;
+use std::collections::BTreeMap;
+use std::fs::File;
+
+use disasm;
+use profile;
+use Retired;
+
+#[derive(Default)]
+pub struct Stats {
+    instructions: u64,
+    classes: BTreeMap<&'static str, u64>,
+    mnemonics: BTreeMap<&'static str, u64>,
+    taken: u64,
+    not_taken: u64,
+    // indexed by log2 of the access width: byte, half, word, double
+    loads: [u64; 4],
+    stores: [u64; 4],
+    call_depth: u64,
+    max_call_depth: u64,
+    // sp when the program first set it, taken as the base of the stack
+    stack_base: Option<u32>,
+    max_stack_bytes: u32,
+}
+
+const WIDTHS: [&str; 4] = ["byte", "half", "word", "double"];
+
+impl Stats {
+    pub fn retire(&mut self, retired: &Retired, sp: u32) {
+        self.instructions += 1;
+        // an instruction that trapped, or failed to fetch, did nothing
+        if retired.trapped {
+            return;
+        }
+        let instruction = retired.instruction;
+        *self.classes.entry(disasm::class(instruction)).or_insert(0) += 1;
+        *self.mnemonics.entry(disasm::mnemonic(instruction)).or_insert(0) += 1;
+
+        let funct3 = ((instruction & 0x7000) >> 12) as usize;
+        match instruction & 0x7f {
+            0b1100011 => {
+                if retired.taken {
+                    self.taken += 1;
+                } else {
+                    self.not_taken += 1;
+                }
+            }
+            0b0000011 => self.loads[funct3 & 0b011] += 1,
+            0b0100011 => self.stores[funct3 & 0b011] += 1,
+            _ => {}
+        }
+
+        if profile::is_call(instruction) {
+            self.call_depth += 1;
+            self.max_call_depth = self.max_call_depth.max(self.call_depth);
+        } else if profile::is_return(instruction) && self.call_depth > 0 {
+            self.call_depth -= 1;
+        }
+
+        if self.stack_base.is_none() && sp != 0 {
+            self.stack_base = Some(sp);
+        }
+        if let Some(base) = self.stack_base {
+            if sp <= base {
+                self.max_stack_bytes = self.max_stack_bytes.max(base - sp);
+            }
+        }
+    }
+
+    pub fn print(&self, cycles: u64) {
+        eprintln!("==== statistics ====");
+        eprintln!("instructions:    {}", self.instructions);
+        eprintln!("cycles:          {}", cycles);
+        eprintln!("branches:        {} taken, {} not taken", self.taken, self.not_taken);
+        for (i, width) in WIDTHS.iter().enumerate() {
+            eprintln!("{:<16} {} loads, {} stores", format!("{}:", width), self.loads[i], self.stores[i]);
+        }
+        eprintln!("max call depth:  {}", self.max_call_depth);
+        eprintln!("max stack bytes: {}", self.max_stack_bytes);
+        eprintln!("instruction classes:");
+        for (class, count) in &self.classes {
+            eprintln!("  {:<10} {:>12}  {:>6.2}%", class, count, self.percent(*count));
+        }
+        eprintln!("mnemonics:");
+        for (mnemonic, count) in &self.mnemonics {
+            eprintln!("  {:<10} {:>12}  {:>6.2}%", mnemonic, count, self.percent(*count));
+        }
+    }
+
+    fn percent(&self, count: u64) -> f64 {
+        if self.instructions == 0 {
+            0.0
+        } else {
+            count as f64 * 100.0 / self.instructions as f64
+        }
+    }
+
+    pub fn write_json(&self, path: &str, cycles: u64) {
+        let map = |m: &BTreeMap<&str, u64>| {
+            let items: Vec<_> = m.iter().map(|(k, v)| format!("\"{}\": {}", k, v)).collect();
+            format!("{{{}}}", items.join(", "))
+        };
+        let widths = |a: &[u64; 4]| {
+            let items: Vec<_> = WIDTHS.iter().zip(a.iter())
+                .map(|(k, v)| format!("\"{}\": {}", k, v)).collect();
+            format!("{{{}}}", items.join(", "))
+        };
+        let mut f = match File::create(path) {
+            Ok(file) => file,
+            Err(err) => panic!("File open error: {:?}", err),
+        };
+        writeln!(f, "{{").unwrap();
+        writeln!(f, "  \"instructions\": {},", self.instructions).unwrap();
+        writeln!(f, "  \"cycles\": {},", cycles).unwrap();
+        writeln!(f, "  \"classes\": {},", map(&self.classes)).unwrap();
+        writeln!(f, "  \"mnemonics\": {},", map(&self.mnemonics)).unwrap();
+        writeln!(f, "  \"branches\": {{\"taken\": {}, \"not_taken\": {}}},",
+                 self.taken, self.not_taken).unwrap();
+        writeln!(f, "  \"loads\": {},", widths(&self.loads)).unwrap();
+        writeln!(f, "  \"stores\": {},", widths(&self.stores)).unwrap();
+        writeln!(f, "  \"max_call_depth\": {},", self.max_call_depth).unwrap();
+        writeln!(f, "  \"max_stack_bytes\": {}", self.max_stack_bytes).unwrap();
+        writeln!(f, "}}").unwrap();
+    }
+}
@@ -0,0 +1,73 @@
+// Symbol table written by `kasm --symbols`, or read from an ELF file.
+// Each line is "<address in hex> <name>".
+
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::fs::File;
+
+#[derive(Default, Clone)]
+pub struct Symbols {
+    // sorted by address
+    entries: Vec<(u32, String)>,
+}
+
+impl Symbols {
+    pub fn load(path: &str) -> Symbols {
+        let f = match File::open(path) {
+            Ok(file) => BufReader::new(file),
+            Err(err) => panic!("File open error: {:?}", err),
+        };
+        let mut entries = Vec::new();
+        for line in f.lines() {
+            let l = line.unwrap();
+            let mut fields = l.split_whitespace();
+            let (addr, name) = match (fields.next(), fields.next()) {
+                (Some(addr), Some(name)) => (addr, name),
+                (None, _) => continue,
+                _ => panic!("Malformed symbol line: {}", l),
+            };
+            let addr = match u32::from_str_radix(addr, 16) {
+                Ok(a) => a,
+                Err(_) => panic!("Malformed symbol address: {}", l),
+            };
+            entries.push((addr, name.to_string()));
+        }
+        Symbols::new(entries)
+    }
+
+    pub fn new(mut entries: Vec<(u32, String)>) -> Symbols {
+        entries.sort();
+        Symbols { entries }
+    }
+
+    // The symbol covering addr, i.e. the one with the greatest address <= addr.
+    pub fn lookup(&self, addr: u32) -> Option<(u32, &str)> {
+        match self.entries.binary_search_by_key(&addr, |e| e.0) {
+            Ok(i) => Some((self.entries[i].0, &self.entries[i].1)),
+            Err(0) => None,
+            Err(i) => Some((self.entries[i - 1].0, &self.entries[i - 1].1)),
+        }
+    }
+
+    // Address of the symbol called name.
+    pub fn address(&self, name: &str) -> Option<u32> {
+        self.entries.iter().find(|e| e.1 == name).map(|e| e.0)
+    }
+
+    // Human readable location such as "main+0x8".
+    pub fn describe(&self, addr: u32) -> String {
+        match self.lookup(addr) {
+            Some((base, name)) if base == addr => name.to_string(),
+            Some((base, name)) => format!("{}+{:#x}", name, addr - base),
+            None => format!("{:#010x}", addr),
+        }
+    }
+
+    // Name of the function containing addr, or its hex address if unknown.
+    pub fn function(&self, addr: u32) -> String {
+        match self.lookup(addr) {
+            Some((_, name)) => name.to_string(),
+            None => format!("{:#010x}", addr),
+        }
+    }
+}
@@ -0,0 +1,210 @@
+// Exceptions and interrupts.
+//
+// A trap goes to M-mode unless it happens in S- or U-mode and medeleg or
+// mideleg delegate it to S-mode.  It saves the pc in xepc, the cause in
+// xcause and the faulting address or instruction in xtval, then continues
+// at xtvec with interrupts of that mode disabled.  As long as no trap vector
+// is installed for the target mode (xtvec is 0), an exception stops the hart
+// at the faulting instruction instead, and run() returns Stop::Exception, as
+// the simulator stopped before traps existed.
+
+use std::fmt;
+
+use csr::*;
+use State;
+
+// The faulting addresses are XLEN bits wide.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Exception {
+    InstructionAddressMisaligned(u64),
+    InstructionAccessFault(u64),
+    IllegalInstruction(u32),
+    Breakpoint,
+    LoadAddressMisaligned(u64),
+    LoadAccessFault(u64),
+    StoreAddressMisaligned(u64),
+    StoreAccessFault(u64),
+    // from the given privilege mode
+    EnvironmentCall(u32),
+    InstructionPageFault(u64),
+    LoadPageFault(u64),
+    StorePageFault(u64),
+}
+
+impl Exception {
+    pub fn cause(&self) -> u32 {
+        match *self {
+            Exception::InstructionAddressMisaligned(_) => 0,
+            Exception::InstructionAccessFault(_) => 1,
+            Exception::IllegalInstruction(_) => 2,
+            Exception::Breakpoint => 3,
+            Exception::LoadAddressMisaligned(_) => 4,
+            Exception::LoadAccessFault(_) => 5,
+            Exception::StoreAddressMisaligned(_) => 6,
+            Exception::StoreAccessFault(_) => 7,
+            Exception::EnvironmentCall(privilege) => 8 + privilege,
+            Exception::InstructionPageFault(_) => 12,
+            Exception::LoadPageFault(_) => 13,
+            Exception::StorePageFault(_) => 15,
+        }
+    }
+
+    // xtval: the faulting address or instruction, if there is one.
+    fn value(&self) -> Option<u64> {
+        match *self {
+            Exception::IllegalInstruction(i) => Some(i as u64),
+            Exception::InstructionAddressMisaligned(v)
+            | Exception::InstructionAccessFault(v)
+            | Exception::LoadAddressMisaligned(v)
+            | Exception::LoadAccessFault(v)
+            | Exception::StoreAddressMisaligned(v)
+            | Exception::StoreAccessFault(v)
+            | Exception::InstructionPageFault(v)
+            | Exception::LoadPageFault(v)
+            | Exception::StorePageFault(v) => Some(v),
+            Exception::Breakpoint | Exception::EnvironmentCall(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for Exception {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Exception::InstructionAddressMisaligned(a) =>
+                write!(f, "Misaligned jump target {:#010x}", a),
+            Exception::InstructionAccessFault(a) =>
+                write!(f, "Instruction access fault at {:#010x}", a),
+            Exception::IllegalInstruction(i) => write!(f, "Illegal instruction {:#010x}", i),
+            Exception::Breakpoint => write!(f, "Breakpoint"),
+            Exception::LoadAddressMisaligned(a) => write!(f, "Misaligned load from {:#010x}", a),
+            Exception::LoadAccessFault(a) => write!(f, "Load access fault at {:#010x}", a),
+            Exception::StoreAddressMisaligned(a) => write!(f, "Misaligned store to {:#010x}", a),
+            Exception::StoreAccessFault(a) => write!(f, "Store access fault at {:#010x}", a),
+            Exception::EnvironmentCall(privilege) =>
+                write!(f, "Environment call from {}-mode", mode_name(privilege)),
+            Exception::InstructionPageFault(a) =>
+                write!(f, "Instruction page fault at {:#010x}", a),
+            Exception::LoadPageFault(a) => write!(f, "Load page fault at {:#010x}", a),
+            Exception::StorePageFault(a) => write!(f, "Store page fault at {:#010x}", a),
+        }
+    }
+}
+
+pub fn mode_name(privilege: u32) -> &'static str {
+    match privilege {
+        PRV_U => "U",
+        PRV_S => "S",
+        _ => "M",
+    }
+}
+
+impl State {
+    // Trap on an exception raised by the instruction at the current pc.
+    pub(crate) fn raise(&mut self, exception: Exception) {
+        let cause = exception.cause();
+        let vector = if self.delegated(cause) { self.csr.stvec } else { self.csr.mtvec };
+        if vector == 0 {
+            self.unhandled = Some(exception);
+            return;
+        }
+        // ebreak reports its own address
+        let tval = exception.value().unwrap_or(if exception == Exception::Breakpoint {
+            self.address as u64
+        } else {
+            0
+        });
+        self.trap(cause, tval);
+    }
+
+    // Whether a trap with this cause goes to S-mode.
+    fn delegated(&self, cause: u32) -> bool {
+        let deleg = if cause >> 31 == 1 { self.csr.mideleg } else { self.csr.medeleg };
+        self.privilege <= PRV_S && (deleg >> (cause & 0x1f)) & 1 != 0
+    }
+
+    fn trap(&mut self, cause: u32, tval: u64) {
+        let pc = self.address;
+        let mstatus = self.csr.mstatus;
+        let tvec = if self.delegated(cause) {
+            self.set_wide_csr(SEPC, pc as u64);
+            self.set_csr(SCAUSE, cause);
+            self.set_wide_csr(STVAL, tval);
+            let spie = if mstatus & MSTATUS_SIE != 0 { MSTATUS_SPIE } else { 0 };
+            let spp = if self.privilege == PRV_S { MSTATUS_SPP } else { 0 };
+            self.set_csr(MSTATUS, (mstatus & !(MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP)) | spie | spp);
+            self.privilege = PRV_S;
+            self.csr.stvec
+        } else {
+            self.set_wide_csr(MEPC, pc as u64);
+            self.set_csr(MCAUSE, cause);
+            self.set_wide_csr(MTVAL, tval);
+            let mpie = if mstatus & MSTATUS_MIE != 0 { MSTATUS_MPIE } else { 0 };
+            let mpp = self.privilege << 11;
+            self.set_csr(MSTATUS, (mstatus & !(MSTATUS_MIE | MSTATUS_MPIE | MSTATUS_MPP)) | mpie | mpp);
+            self.privilege = PRV_M;
+            self.csr.mtvec
+        };
+        let base = tvec & !3;
+        let vectored = tvec & 1 != 0 && cause >> 31 == 1;
+        self.address = if vectored { base + 4 * (cause & 0x7fff_ffff) } else { base };
+    }
+
+    pub(crate) fn mret(&mut self) {
+        let mstatus = self.csr.mstatus;
+        let mpp = (mstatus & MSTATUS_MPP) >> 11;
+        let mie = if mstatus & MSTATUS_MPIE != 0 { MSTATUS_MIE } else { 0 };
+        // MPP goes back to U, and leaving M-mode clears MPRV
+        let mprv = if mpp == PRV_M { mstatus & MSTATUS_MPRV } else { 0 };
+        let kept = mstatus & !(MSTATUS_MIE | MSTATUS_MPP | MSTATUS_MPRV);
+        self.set_csr(MSTATUS, kept | mie | MSTATUS_MPIE | mprv);
+        self.privilege = mpp;
+        self.address = self.csr.mepc as u32;
+    }
+
+    pub(crate) fn sret(&mut self) {
+        let mstatus = self.csr.mstatus;
+        let spp = if mstatus & MSTATUS_SPP != 0 { PRV_S } else { PRV_U };
+        let sie = if mstatus & MSTATUS_SPIE != 0 { MSTATUS_SIE } else { 0 };
+        let kept = mstatus & !(MSTATUS_SIE | MSTATUS_SPP | MSTATUS_MPRV);
+        self.set_csr(MSTATUS, kept | sie | MSTATUS_SPIE);
+        self.privilege = spp;
+        self.address = self.csr.sepc as u32;
+    }
+
+    // Pending interrupts as seen in mip.
+    pub(crate) fn mip(&self) -> u32 {
+        self.csr.mip | self.bus.borrow().interrupts(self.csr.mhartid as usize)
+    }
+
+    // True if some interrupt could still be taken.
+    pub(crate) fn interrupts_enabled(&self) -> bool {
+        self.csr.mie != 0 && (self.privilege < PRV_M || self.csr.mstatus & MSTATUS_MIE != 0)
+    }
+
+    // Take the highest priority pending and enabled interrupt, if any.
+    // Called before every instruction.
+    pub(crate) fn check_interrupts(&mut self) {
+        let pending = self.mip() & self.csr.mie;
+        if pending == 0 {
+            return;
+        }
+        let mstatus = self.csr.mstatus;
+        let m_enabled = self.privilege < PRV_M || mstatus & MSTATUS_MIE != 0;
+        let s_enabled = self.privilege < PRV_S
+                     || (self.privilege == PRV_S && mstatus & MSTATUS_SIE != 0);
+        let mut enabled = 0;
+        if m_enabled {
+            enabled |= pending & !self.csr.mideleg;
+        }
+        if s_enabled {
+            enabled |= pending & self.csr.mideleg;
+        }
+        // M-level interrupts first, each level in the order MEI, MSI, MTI
+        for &code in [11, 3, 7, 9, 1, 5].iter() {
+            if enabled & (1 << code) != 0 {
+                self.trap(0x8000_0000 | code, 0);
+                return;
+            }
+        }
+    }
+}
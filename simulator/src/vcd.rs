@@ -0,0 +1,191 @@
+// Value Change Dump output, for looking at a run in a waveform viewer next
+// to RTL simulations.
+//
+// Every retired instruction is one time step, at the hart's instret after
+// it, and shows the state once it has retired.  Signals, in scope ksim:
+//   pc, insn      the retired instruction and its address
+//   priv          the privilege mode
+//   regs.<name>   x1-x31 under their ABI names, XLEN bits wide
+//   mem.re, mem.raddr, mem.rdata
+//                 a load by the instruction: re is 1 for the instructions
+//                 that load, the address is physical and the data XLEN bits
+//                 wide
+//   mem.we, mem.waddr, mem.wdata, mem.wsize
+//                 the same for stores, with the size in bytes
+//   csr.<name>    the CSRs saved in checkpoints, XLEN bits wide
+// Only changes are written.  Page table walks do not show up as memory
+// transactions.
+
+use std::io::prelude::*;
+use std::io::BufWriter;
+use std::fs::File;
+
+use csr;
+use disasm;
+use {Retired, State};
+
+const PC: usize = 0;
+const INSN: usize = 1;
+const PRIV: usize = 2;
+const RE: usize = 3;
+const RADDR: usize = 4;
+const RDATA: usize = 5;
+const WE: usize = 6;
+const WADDR: usize = 7;
+const WDATA: usize = 8;
+const WSIZE: usize = 9;
+// x1 is signal REGS
+const REGS: usize = 10;
+// the first CSR, after x1-x31
+const CSRS: usize = REGS + 31;
+
+pub struct Vcd {
+    out: BufWriter<File>,
+    // identifier code and width in bits of each signal
+    signals: Vec<(String, u32)>,
+    // the value last written for each signal
+    last: Vec<Option<u64>>,
+    csrs: Vec<u32>,
+    // (address, value) of the current instruction's load, and (address,
+    // value, width) of its store
+    load: Option<(u32, u64)>,
+    store: Option<(u32, u64, u32)>,
+}
+
+// Identifier codes are strings of the printable characters ! to ~.
+fn identifier(mut index: usize) -> String {
+    let mut id = String::new();
+    loop {
+        id.push((b'!' + (index % 94) as u8) as char);
+        index /= 94;
+        if index == 0 {
+            return id;
+        }
+        index -= 1;
+    }
+}
+
+impl Vcd {
+    /// Start a dump of state at path with the definitions and the initial
+    /// values.
+    pub fn create(path: &str, state: &State) -> Vcd {
+        let out = match File::create(path) {
+            Ok(file) => BufWriter::new(file),
+            Err(err) => panic!("File open error: {:?}", err),
+        };
+        let csrs = csr::plain();
+        let mut vcd = Vcd {
+            out,
+            signals: Vec::new(),
+            last: Vec::new(),
+            csrs: csrs.clone(),
+            load: None,
+            store: None,
+        };
+        vcd.line("$version ksim $end");
+        vcd.line("$timescale 1ns $end");
+        vcd.line("$scope module ksim $end");
+        vcd.define("pc", 32);
+        vcd.define("insn", 32);
+        vcd.define("priv", 2);
+        vcd.line("$scope module mem $end");
+        let xlen = state.xlen();
+        for &(name, width) in [("re", 1), ("raddr", 32), ("rdata", xlen), ("we", 1),
+                               ("waddr", 32), ("wdata", xlen), ("wsize", 4)].iter() {
+            vcd.define(name, width);
+        }
+        vcd.line("$upscope $end");
+        vcd.line("$scope module regs $end");
+        for i in 1..32 {
+            vcd.define(disasm::register_name(i), xlen);
+        }
+        vcd.line("$upscope $end");
+        vcd.line("$scope module csr $end");
+        for &number in csrs.iter() {
+            vcd.define(&disasm::csr_name(number), xlen);
+        }
+        vcd.line("$upscope $end");
+        vcd.line("$upscope $end");
+        vcd.line("$enddefinitions $end");
+
+        vcd.line(&format!("#{}", state.instret()));
+        vcd.line("$dumpvars");
+        vcd.set(PC, state.pc() as u64);
+        vcd.set(INSN, 0);
+        for signal in RE..=WSIZE {
+            vcd.set(signal, 0);
+        }
+        vcd.sample(state);
+        vcd.line("$end");
+        vcd
+    }
+
+    fn line(&mut self, text: &str) {
+        writeln!(self.out, "{}", text).expect("File write error");
+    }
+
+    fn define(&mut self, name: &str, width: u32) {
+        let id = identifier(self.signals.len());
+        self.line(&format!("$var wire {} {} {} $end", width, id, name));
+        self.signals.push((id, width));
+        self.last.push(None);
+    }
+
+    // Write the value of a signal if it changed.
+    fn set(&mut self, signal: usize, value: u64) {
+        if self.last[signal] == Some(value) {
+            return;
+        }
+        self.last[signal] = Some(value);
+        let (ref id, width) = self.signals[signal];
+        if width == 1 {
+            writeln!(self.out, "{}{}", value, id).expect("File write error");
+        } else {
+            writeln!(self.out, "b{:b} {}", value, id).expect("File write error");
+        }
+    }
+
+    // The privilege mode, registers and CSRs.
+    fn sample(&mut self, state: &State) {
+        self.set(PRIV, state.privilege as u64);
+        for i in 1..32 {
+            self.set(REGS + i - 1, state.register(i));
+        }
+        for i in 0..self.csrs.len() {
+            let value = state.read_csr(self.csrs[i]).unwrap_or(0);
+            self.set(CSRS + i, value);
+        }
+    }
+
+    pub(crate) fn load(&mut self, addr: u32, value: u64) {
+        self.load = Some((addr, value));
+    }
+
+    pub(crate) fn store(&mut self, addr: u32, value: u64, width: u32) {
+        self.store = Some((addr, value, width));
+    }
+
+    // Called by step() after every retired instruction.
+    pub fn retire(&mut self, state: &State, retired: &Retired) {
+        writeln!(self.out, "#{}", state.instret()).expect("File write error");
+        self.set(PC, retired.pc as u64);
+        self.set(INSN, retired.instruction as u64);
+        self.set(RE, self.load.is_some() as u64);
+        if let Some((addr, value)) = self.load.take() {
+            self.set(RADDR, addr as u64);
+            self.set(RDATA, value);
+        }
+        self.set(WE, self.store.is_some() as u64);
+        if let Some((addr, value, width)) = self.store.take() {
+            self.set(WADDR, addr as u64);
+            self.set(WDATA, value);
+            self.set(WSIZE, width as u64);
+        }
+        self.sample(state);
+    }
+
+    /// Write out everything buffered.
+    pub fn finish(&mut self) {
+        self.out.flush().expect("File write error");
+    }
+}
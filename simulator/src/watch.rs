@@ -0,0 +1,177 @@
+// Memory and register watchpoints.
+// A watchpoint fires on every matching access, not only when the value
+// changes.  Hits are collected while an instruction executes and the run
+// loop or the debugger stops once it has retired.
+
+use std::fmt;
+
+use disasm;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Watchpoint {
+    Memory { addr: u32, len: u32, access: Access },
+    Register(u32),
+}
+
+impl Watchpoint {
+    // Parse "<reg>" or "<addr>[:<len>][:r|w|rw]".  The address is hex with
+    // a 0x prefix or decimal; len defaults to 4 and the access to w.
+    pub fn parse(spec: &str) -> Option<Watchpoint> {
+        if let Some(index) = disasm::register_index(spec) {
+            return Some(Watchpoint::Register(index));
+        }
+        let mut fields = spec.split(':');
+        let addr = fields.next().and_then(parse_number)?;
+        let mut len = 4;
+        let mut access = Access::Write;
+        for field in fields {
+            match field {
+                "r" => access = Access::Read,
+                "w" => access = Access::Write,
+                "rw" => access = Access::ReadWrite,
+                _ => len = parse_number(field).filter(|&n| n > 0)?,
+            }
+        }
+        Some(Watchpoint::Memory { addr, len, access })
+    }
+
+    fn covers(&self, addr: u32, width: u32, is_write: bool) -> bool {
+        match *self {
+            Watchpoint::Memory { addr: a, len, access } => {
+                let overlaps = addr.wrapping_sub(a) < len || a.wrapping_sub(addr) < width;
+                let kind = match access {
+                    Access::Read => !is_write,
+                    Access::Write => is_write,
+                    Access::ReadWrite => true,
+                };
+                overlaps && kind
+            }
+            Watchpoint::Register(_) => false,
+        }
+    }
+}
+
+fn parse_number(s: &str) -> Option<u32> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => s.parse::<u32>().ok(),
+    }
+}
+
+impl fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Watchpoint::Memory { addr, len, access } => {
+                let access = match access {
+                    Access::Read => "read",
+                    Access::Write => "write",
+                    Access::ReadWrite => "access",
+                };
+                write!(f, "{} of {:#010x} ({} bytes)", access, addr, len)
+            }
+            Watchpoint::Register(index) => write!(f, "write of {}", disasm::register_name(index)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Hit {
+    pub pc: u32,
+    pub watchpoint: Watchpoint,
+    // the accessed location, e.g. "mem[0x000003f8]" or "s0"
+    pub location: String,
+    pub old: u64,
+    pub new: u64,
+    pub is_write: bool,
+}
+
+impl fmt::Display for Hit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_write {
+            write!(f, "Watchpoint ({}) at pc {:#010x}: {} {:#x} -> {:#x}",
+                   self.watchpoint, self.pc, self.location, self.old, self.new)
+        } else {
+            write!(f, "Watchpoint ({}) at pc {:#010x}: {} read {:#x}",
+                   self.watchpoint, self.pc, self.location, self.new)
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Watchpoints {
+    pub list: Vec<Watchpoint>,
+    pub hits: Vec<Hit>,
+}
+
+impl Watchpoints {
+    pub fn memory(&mut self, pc: u32, addr: u32, width: u32, old: u64, new: u64, is_write: bool) {
+        for &w in &self.list {
+            if w.covers(addr, width, is_write) {
+                self.hits.push(Hit {
+                    pc,
+                    watchpoint: w,
+                    location: format!("mem[{:#010x}]", addr),
+                    old,
+                    new,
+                    is_write,
+                });
+            }
+        }
+    }
+
+    pub fn register(&mut self, pc: u32, index: u32, old: u64, new: u64) {
+        for &w in &self.list {
+            if w == Watchpoint::Register(index) {
+                self.hits.push(Hit {
+                    pc,
+                    watchpoint: w,
+                    location: disasm::register_name(index).to_string(),
+                    old,
+                    new,
+                    is_write: true,
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_specs() {
+        let memory = |addr, len, access| Some(Watchpoint::Memory { addr, len, access });
+        assert_eq!(Watchpoint::parse("0x100"), memory(0x100, 4, Access::Write));
+        assert_eq!(Watchpoint::parse("256:2"), memory(0x100, 2, Access::Write));
+        assert_eq!(Watchpoint::parse("0x100:r"), memory(0x100, 4, Access::Read));
+        assert_eq!(Watchpoint::parse("0x100:0x10:rw"), memory(0x100, 16, Access::ReadWrite));
+        assert_eq!(Watchpoint::parse("sp"), Some(Watchpoint::Register(2)));
+        assert_eq!(Watchpoint::parse("x31"), Some(Watchpoint::Register(31)));
+        assert_eq!(Watchpoint::parse("fp"), Some(Watchpoint::Register(8)));
+        assert_eq!(Watchpoint::parse("0x100:0"), None);
+        assert_eq!(Watchpoint::parse("0x100:x"), None);
+        assert_eq!(Watchpoint::parse("main"), None);
+        assert_eq!(Watchpoint::parse(""), None);
+    }
+
+    #[test]
+    fn overlapping_accesses() {
+        let w = Watchpoint::parse("0x100:4:rw").unwrap();
+        assert!(w.covers(0x100, 1, false));
+        assert!(w.covers(0x103, 1, true));
+        assert!(w.covers(0xfe, 4, false));
+        assert!(!w.covers(0x104, 4, true));
+        assert!(!w.covers(0xfc, 4, true));
+        let w = Watchpoint::parse("0x100:r").unwrap();
+        assert!(w.covers(0x100, 4, false));
+        assert!(!w.covers(0x100, 4, true));
+    }
+}
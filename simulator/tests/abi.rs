@@ -0,0 +1,66 @@
+// The calling-convention checker's callee-saved register and return
+// address reports.
+
+extern crate ksim;
+
+mod common;
+
+use common::{addi, i_type, jal};
+use ksim::abi::Problem;
+use ksim::symbols::Symbols;
+use ksim::Machine;
+
+fn ret(link: u32) -> u32 {
+    i_type(0x67, 0, 0, link, 0)
+}
+
+#[test]
+fn saved_registers_and_return_addresses() {
+    let program = vec![
+        addi(8, 0, 5),      // 0x00  s0 = 5
+        jal(1, 0x1c),       // 0x04  call good
+        jal(1, 0x20),       // 0x08  call clobber
+        jal(1, 0x1c),       // 0x0c  call clobber again
+        jal(1, 0x20),       // 0x10  call wrong_return
+        common::EXIT,       // 0x14  never reached
+        jal(5, 0x20),       // 0x18  millicode is not checked
+        common::EXIT,       // 0x1c
+        addi(10, 10, 1),    // 0x20  good
+        ret(1),             // 0x24
+        addi(8, 8, 1),      // 0x28  clobber
+        ret(1),             // 0x2c
+        addi(1, 1, 4),      // 0x30  wrong_return
+        ret(1),             // 0x34
+        addi(8, 8, 1),      // 0x38  millicode
+        ret(5),             // 0x3c
+    ];
+    let symbols = Symbols::new(vec![(0x00, "main".to_string()), (0x20, "good".to_string()),
+                                    (0x28, "clobber".to_string()),
+                                    (0x30, "wrong_return".to_string()),
+                                    (0x38, "millicode".to_string())]);
+    let mut machine = Machine::new(program, 1);
+    machine.check_abi(&symbols);
+    machine.run(None);
+    assert!(machine.abi_failed());
+    let state = &machine.harts[0];
+    assert_eq!(state.register(8), 8);
+
+    let reports = &state.observers.abi.as_ref().unwrap().reports;
+    let found: Vec<_> = reports.iter()
+        .map(|r| (r.function, r.pc, r.problem, r.expected, r.actual, r.count)).collect();
+    assert_eq!(found, vec![(0x28, 0x2c, Problem::Register(8), 5, 6, 2),
+                           (0x30, 0x34, Problem::ReturnAddress, 0x14, 0x18, 1)]);
+    assert_eq!(reports[0].to_string(),
+               "clobber returns at pc 0x0000002c with s0 = 0x6 instead of 0x5");
+    assert_eq!(reports[1].to_string(),
+               "wrong_return returns at pc 0x00000034 to 0x00000018 instead of 0x00000014");
+}
+
+#[test]
+fn clean_program() {
+    let mut machine = Machine::new(common::sum_program(10), 1);
+    machine.check_abi(&Symbols::default());
+    machine.run(None);
+    assert_eq!(machine.harts[0].register(10), 55);
+    assert!(!machine.abi_failed());
+}
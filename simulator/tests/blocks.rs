@@ -0,0 +1,97 @@
+// Basic-block execution has to give the same results and timing as
+// executing an instruction at a time.
+
+extern crate ksim;
+
+mod common;
+
+use std::env;
+use std::fs;
+
+use common::{addi, lui, sw, EXIT};
+use ksim::bpred::Predictor;
+use ksim::cache::{Cache, CacheConfig};
+use ksim::costs::CostModel;
+use ksim::disk::Disk;
+use ksim::{Machine, State, Stop};
+
+fn run(blocks: bool, timing: bool) -> State {
+    let mut state = State::init(common::sum_program(200));
+    state.blocks = blocks;
+    if timing {
+        let config = "size=256,assoc=2,line=16,latency=7";
+        state.observers.icache = Some(Cache::new("icache", CacheConfig::parse(config).unwrap()));
+        state.observers.dcache = Some(Cache::new("dcache", CacheConfig::parse(config).unwrap()));
+        state.observers.bpred = Some(Predictor::parse("gshare,bits=6,penalty=3"));
+        state.observers.costs = Some(CostModel::parse("load 3\nstore 2\njump 2\n").unwrap());
+    }
+    state.capture_output();
+    assert!(matches!(state.run(None), Stop::Exit));
+    state
+}
+
+fn assert_same(step: &State, block: &State) {
+    assert_eq!(block.instret(), step.instret());
+    assert_eq!(block.cycle(), step.cycle());
+    assert_eq!(block.pc(), step.pc());
+    for i in 0..32 {
+        assert_eq!(block.register(i), step.register(i), "x{}", i);
+    }
+}
+
+#[test]
+fn blocks_match_steps() {
+    let (step, block) = (run(false, false), run(true, false));
+    assert_eq!(step.register(10), 20100);
+    assert_eq!(step.cycle(), step.instret());
+    assert_same(&step, &block);
+}
+
+#[test]
+fn blocks_match_steps_with_timing_models() {
+    let (step, block) = (run(false, true), run(true, true));
+    assert!(step.cycle() > step.instret());
+    assert_same(&step, &block);
+    let (s, b) = (&step.observers, &block.observers);
+    let (si, bi) = (&s.icache.as_ref().unwrap().stats, &b.icache.as_ref().unwrap().stats);
+    assert_eq!((bi.reads, bi.read_misses), (si.reads, si.read_misses));
+    let (sd, bd) = (&s.dcache.as_ref().unwrap().stats, &b.dcache.as_ref().unwrap().stats);
+    assert_eq!((bd.reads, bd.writes, bd.read_misses), (sd.reads, sd.writes, sd.read_misses));
+}
+
+// A disk read that overwrites the rest of the running block with a program
+// that sets a0 to 77 and exits.
+fn run_disk_read(blocks: bool) -> State {
+    let path = env::temp_dir().join(format!("ksim-test-{}-{}.img", std::process::id(), blocks));
+    let path = path.to_str().unwrap();
+    let mut image = vec![0; 512];
+    image[..4].copy_from_slice(&addi(10, 0, 77).to_le_bytes());
+    image[4..8].copy_from_slice(&EXIT.to_le_bytes());
+    fs::write(path, &image).unwrap();
+    let program = vec![
+        lui(5, 0x10001),     // disk registers
+        sw(5, 0, 0),         // sector 0
+        addi(6, 0, 0x20),
+        sw(5, 6, 4),         // to 0x20
+        addi(7, 0, 1),
+        sw(5, 7, 8),         // one sector
+        sw(5, 7, 12),        // read
+        addi(0, 0, 0),
+        addi(10, 0, 1),      // 0x20: replaced by the read
+    ];
+    let mut machine = Machine::new(program, 1);
+    machine.set_disk(Disk::open(path));
+    let mut state = machine.harts.remove(0);
+    fs::remove_file(path).unwrap();
+    state.blocks = blocks;
+    state.capture_output();
+    assert!(matches!(state.run(None), Stop::Exit));
+    state
+}
+
+#[test]
+fn disk_read_into_running_block() {
+    let (step, block) = (run_disk_read(false), run_disk_read(true));
+    assert_eq!(step.register(10), 77);
+    assert_same(&step, &block);
+}
@@ -0,0 +1,107 @@
+// Saving a checkpoint in the middle of a run and restoring it.
+
+extern crate ksim;
+
+mod common;
+
+use std::env;
+use std::fs;
+use std::panic;
+
+use ksim::disk::{Disk, DISK_BASE};
+use ksim::framebuffer::{Format, Framebuffer, FB_MEMORY};
+use ksim::{checkpoint, Machine, State, Stop};
+
+#[test]
+fn restored_run_matches_uninterrupted_run() {
+    let program = common::sum_program(100);
+    let mut whole = State::init(program.clone());
+    whole.capture_output();
+    assert!(matches!(whole.run(None), Stop::Exit));
+
+    let mut first = State::init(program);
+    first.set_register(20, 0x1234_5678);
+    first.run(Some(321));
+    let path = env::temp_dir().join(format!("ksim-test-{}.ckpt", std::process::id()));
+    let path = path.to_str().unwrap();
+    checkpoint::save(&first, path);
+    let mut second = checkpoint::restore(path);
+    fs::remove_file(path).unwrap();
+
+    assert_eq!(second.pc(), first.pc());
+    assert_eq!(second.instret(), 321);
+    assert_eq!(second.cycle(), first.cycle());
+    for i in 0..32 {
+        assert_eq!(second.register(i), first.register(i), "x{}", i);
+    }
+    for addr in (0x100..0x200).step_by(4) {
+        assert_eq!(second.read_memory(addr, 4), first.read_memory(addr, 4));
+    }
+
+    second.capture_output();
+    assert!(matches!(second.run(None), Stop::Exit));
+    assert_eq!(second.instret(), whole.instret());
+    assert_eq!(second.cycle(), whole.cycle());
+    assert_eq!(second.register(10), 5050);
+    assert_eq!(second.register(20), 0x1234_5678);
+    assert_eq!(second.read_memory(0x100 + 4 * 99, 4), Some(5050));
+}
+
+#[test]
+fn devices_are_restored() {
+    let dir = env::temp_dir();
+    let image = dir.join(format!("ksim-test-{}.img", std::process::id()));
+    let image = image.to_str().unwrap();
+    fs::write(image, vec![0; 1024]).unwrap();
+    let prefix = dir.join("ksim-test-frame").to_str().unwrap().to_string();
+    let mut machine = Machine::new(vec![common::EXIT], 1);
+    machine.set_framebuffer(Framebuffer::new(4, 4, Format::Gray8, prefix.clone()));
+    machine.set_disk(Disk::open(image));
+    let state = &mut machine.harts[0];
+    state.write_memory(FB_MEMORY + 4, 0x1122_3344, 4).unwrap();
+    state.write_memory(DISK_BASE, 1, 4).unwrap();
+    state.write_memory(DISK_BASE + 4, 0x40, 4).unwrap();
+
+    let path = dir.join(format!("ksim-test-devices-{}.ckpt", std::process::id()));
+    let path = path.to_str().unwrap();
+    checkpoint::save(state, path);
+    let restored = checkpoint::restore(path);
+    fs::remove_file(path).unwrap();
+    assert_eq!(restored.read_memory(FB_MEMORY + 4, 4), Some(0x1122_3344));
+    assert_eq!(restored.read_memory(DISK_BASE, 4), Some(1));
+    assert_eq!(restored.read_memory(DISK_BASE + 0x10, 4), Some(2));
+
+    // devices given again on the command line keep the restored state
+    let mut machine = Machine::from(restored);
+    machine.set_framebuffer(Framebuffer::new(4, 4, Format::Gray8, prefix));
+    machine.set_disk(Disk::open(image));
+    fs::remove_file(image).unwrap();
+    let state = &machine.harts[0];
+    assert_eq!(state.read_memory(FB_MEMORY + 4, 4), Some(0x1122_3344));
+    assert_eq!(state.read_memory(DISK_BASE + 4, 4), Some(0x40));
+}
+
+#[test]
+fn changed_disk_image_is_refused() {
+    let dir = env::temp_dir();
+    let image = dir.join(format!("ksim-test-changed-{}.img", std::process::id()));
+    let image = image.to_str().unwrap();
+    fs::write(image, vec![0; 512]).unwrap();
+    let mut machine = Machine::new(vec![common::EXIT], 1);
+    machine.set_disk(Disk::open(image));
+    let path = dir.join(format!("ksim-test-changed-{}.ckpt", std::process::id()));
+    let path = path.to_str().unwrap();
+    checkpoint::save(&machine.harts[0], path);
+    // the same contents written again are fine
+    fs::write(image, vec![0; 512]).unwrap();
+    checkpoint::restore(path);
+
+    let mut data = vec![0; 512];
+    data[100] = 1;
+    fs::write(image, data).unwrap();
+    let result = panic::catch_unwind(|| checkpoint::restore(path));
+    fs::remove_file(path).unwrap();
+    fs::remove_file(image).unwrap();
+    let message = result.err().and_then(|e| e.downcast_ref::<String>().cloned()).unwrap();
+    assert_eq!(message, format!("Disk image {} changed after the checkpoint was saved", image));
+}
@@ -0,0 +1,76 @@
+// Instruction encoders for the test programs.
+
+#![allow(dead_code)]
+
+pub const EXIT: u32 = 0x0000_000b;
+
+pub fn r_type(opcode: u32, funct3: u32, funct7: u32, rd: u32, rs1: u32, rs2: u32) -> u32 {
+    (funct7 << 25) | (rs2 << 20) | (rs1 << 15) | (funct3 << 12) | (rd << 7) | opcode
+}
+
+pub fn i_type(opcode: u32, funct3: u32, rd: u32, rs1: u32, imm: i32) -> u32 {
+    (((imm as u32) & 0xfff) << 20) | (rs1 << 15) | (funct3 << 12) | (rd << 7) | opcode
+}
+
+pub fn s_type(funct3: u32, rs1: u32, rs2: u32, imm: i32) -> u32 {
+    let imm = imm as u32;
+    (((imm >> 5) & 0x7f) << 25) | (rs2 << 20) | (rs1 << 15) | (funct3 << 12)
+        | ((imm & 0x1f) << 7) | 0x23
+}
+
+pub fn b_type(funct3: u32, rs1: u32, rs2: u32, offset: i32) -> u32 {
+    let imm = offset as u32;
+    (((imm >> 12) & 1) << 31) | (((imm >> 5) & 0x3f) << 25) | (rs2 << 20) | (rs1 << 15)
+        | (funct3 << 12) | (((imm >> 1) & 0xf) << 8) | (((imm >> 11) & 1) << 7) | 0x63
+}
+
+pub fn jal(rd: u32, offset: i32) -> u32 {
+    let imm = offset as u32;
+    (((imm >> 20) & 1) << 31) | (((imm >> 1) & 0x3ff) << 21) | (((imm >> 11) & 1) << 20)
+        | (((imm >> 12) & 0xff) << 12) | (rd << 7) | 0x6f
+}
+
+pub fn lui(rd: u32, imm20: u32) -> u32 {
+    (imm20 << 12) | (rd << 7) | 0x37
+}
+
+pub fn addi(rd: u32, rs1: u32, imm: i32) -> u32 {
+    i_type(0x13, 0, rd, rs1, imm)
+}
+
+pub fn add(rd: u32, rs1: u32, rs2: u32) -> u32 {
+    r_type(0x33, 0, 0, rd, rs1, rs2)
+}
+
+pub fn lw(rd: u32, rs1: u32, imm: i32) -> u32 {
+    i_type(0x03, 2, rd, rs1, imm)
+}
+
+pub fn sw(rs1: u32, rs2: u32, imm: i32) -> u32 {
+    s_type(2, rs1, rs2, imm)
+}
+
+pub fn bne(rs1: u32, rs2: u32, offset: i32) -> u32 {
+    b_type(1, rs1, rs2, offset)
+}
+
+// Sums the words 1..=n into a0 (x10), keeping the running sums in an array
+// at 0x100 and calling a leaf function for every element, then exits.
+pub fn sum_program(n: i32) -> Vec<u32> {
+    vec![
+        addi(10, 0, 0),           // 0x00  a0 = 0
+        addi(11, 0, 1),           // 0x04  a1 = 1
+        addi(12, 0, n + 1),       // 0x08  a2 = n + 1
+        addi(13, 0, 0x100),       // 0x0c  a3 = 0x100
+        add(10, 10, 11),          // 0x10  loop: a0 += a1
+        sw(13, 10, 0),            // 0x14  *a3 = a0
+        lw(14, 13, 0),            // 0x18  a4 = *a3
+        addi(13, 13, 4),          // 0x1c  a3 += 4
+        0x0100_00ef,              // 0x20  jal ra, 0x30
+        addi(11, 11, 1),          // 0x24  a1 += 1
+        bne(11, 12, -0x18),       // 0x28  loop while a1 != a2
+        EXIT,                     // 0x2c
+        addi(15, 14, 0),          // 0x30  leaf: a5 = a4
+        i_type(0x67, 0, 0, 1, 0), // 0x34  ret
+    ]
+}
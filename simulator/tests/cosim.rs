@@ -0,0 +1,85 @@
+// Co-simulation against Spike-format and plain commit traces, and the
+// report of where they part ways.
+
+extern crate ksim;
+
+mod common;
+
+use std::env;
+use std::fs;
+
+use common::{add, addi, sw};
+use ksim::cosim::Cosim;
+use ksim::{State, Stop};
+
+fn program() -> Vec<u32> {
+    vec![addi(10, 0, 5), addi(11, 10, 2), add(12, 10, 11), sw(0, 12, 0x100), common::EXIT]
+}
+
+// Run the program against a trace, returning the state and how it stopped.
+fn cosim(name: &str, trace: &str) -> (State, Stop) {
+    let path = env::temp_dir().join(format!("ksim-test-{}-{}.trace", std::process::id(), name));
+    fs::write(&path, trace).unwrap();
+    let mut state = State::init(program());
+    state.observers.cosim = Some(Cosim::open(path.to_str().unwrap(), &state));
+    let stop = state.run(None);
+    fs::remove_file(&path).unwrap();
+    (state, stop)
+}
+
+#[test]
+fn spike_trace_matches() {
+    let p = program();
+    let trace = format!(
+        "# from spike --log-commits\n\
+         core   0: 3 0x0000000000000000 ({:#010x}) x10 0x0000000000000005\n\
+         core   0: 3 0x0000000000000004 ({:#010x}) x11 0x0000000000000007 c768_mstatus 0x0\n\
+         core   0: 3 0x0000000000000008 ({:#010x}) x12 0x000000000000000c\n\
+         \n\
+         core   0: 3 0x000000000000000c ({:#010x}) mem 0x0000000000000100 0x0000000c\n\
+         core   0: 3 0x0000000000000010 ({:#010x})\n",
+        p[0], p[1], p[2], p[3], p[4]);
+    let (mut state, stop) = cosim("spike", &trace);
+    assert!(matches!(stop, Stop::Exit));
+    let cosim = state.observers.cosim.as_mut().unwrap();
+    assert_eq!(cosim.compared, 5);
+    assert!(!cosim.has_more());
+}
+
+#[test]
+fn divergence() {
+    let p = program();
+    let trace = format!("0 {:x} a0=5\n4 {:x} x11=7\n# ksim writes 0xc\n8 {:x} a2=0xd\n",
+                        p[0], p[1], p[2]);
+    let (state, stop) = cosim("diverged", &trace);
+    let divergence = match stop {
+        Stop::Divergence(divergence) => divergence,
+        other => panic!("expected a divergence, got {:?}", other),
+    };
+    assert_eq!(state.instret(), 3);
+    assert_eq!((divergence.instret, divergence.line), (3, Some(4)));
+    assert_eq!(divergence.to_string(), format!(
+        "Co-simulation diverged at instruction 3 (trace line 4):\n\
+         \x20         ksim                reference\n\
+         \x20 pc      0x00000008          0x00000008\n\
+         \x20 insn    {:#010x}          {:#010x}\n\
+         \x20 a2      0x0000000c          0x0000000d", p[2], p[2]));
+}
+
+#[test]
+fn end_of_the_trace() {
+    let p = program();
+    let (_, stop) = cosim("short", &format!("0x0 0x{:08x} x10=5\n", p[0]));
+    let divergence = match stop {
+        Stop::Divergence(divergence) => divergence,
+        other => panic!("expected a divergence, got {:?}", other),
+    };
+    assert_eq!((divergence.instret, divergence.line), (2, None));
+    assert!(divergence.reference.is_none());
+    assert_eq!(divergence.to_string(), format!(
+        "Co-simulation diverged at instruction 2 (end of the trace):\n\
+         \x20         ksim                reference\n\
+         \x20 pc      0x00000004          -\n\
+         \x20 insn    {:#010x}          -\n\
+         \x20 a1      0x00000007          -", p[1]));
+}
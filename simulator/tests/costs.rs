@@ -0,0 +1,58 @@
+// The cost model: cycles and energy per class, with branches charged by
+// their outcome.
+
+extern crate ksim;
+
+mod common;
+
+use common::{addi, b_type, bne};
+use ksim::costs::{CostModel, CLASSES};
+use ksim::State;
+
+const TABLE: &str = "\
+alu 2 0.5
+branch-taken 3 2   # a bubble
+branch-not-taken 1 1
+";
+
+fn run(blocks: bool) -> State {
+    let program = vec![
+        addi(1, 0, 1),      // 0x00
+        bne(1, 0, 4),       // 0x04  taken, to the next instruction
+        b_type(0, 1, 0, 8), // 0x08  beq, not taken
+        addi(2, 0, 2),      // 0x0c
+        common::EXIT,       // 0x10
+    ];
+    let mut state = State::init(program);
+    state.blocks = blocks;
+    state.observers.costs = Some(CostModel::parse(TABLE).unwrap());
+    state.run(None);
+    state
+}
+
+#[test]
+fn branches_are_charged_by_outcome() {
+    for &blocks in [false, true].iter() {
+        let state = run(blocks);
+        assert_eq!(state.instret(), 5);
+        // alu 2 + 2, taken 3, not taken 1, and exit is "other" at 1
+        assert_eq!(state.cycle(), 9);
+        let costs = state.observers.costs.as_ref().unwrap();
+        assert_eq!(costs.energy(), 0.5 + 2.0 + 1.0 + 0.5);
+        let class = |name: &str| costs.stats[CLASSES.iter().position(|&c| c == name).unwrap()];
+        let counts = |name: &str| (class(name).instructions, class(name).cycles);
+        assert_eq!(counts("alu"), (2, 4));
+        assert_eq!(counts("branch-taken"), (1, 3));
+        assert_eq!(counts("branch-not-taken"), (1, 1));
+        assert_eq!(counts("other"), (1, 1));
+    }
+}
+
+#[test]
+fn malformed_tables() {
+    assert!(CostModel::parse("alu").is_err());
+    assert!(CostModel::parse("alu 1 -2").is_err());
+    assert!(CostModel::parse("fpu 3").is_err());
+    assert!(CostModel::parse("alu 1 2 3").is_err());
+    assert_eq!(CostModel::parse("# nothing\n\n").unwrap().cycles(), [1; CLASSES.len()]);
+}
@@ -0,0 +1,74 @@
+// Line and branch coverage, as an lcov tracefile and as an annotated
+// listing.
+
+extern crate ksim;
+
+mod common;
+
+use std::env;
+use std::fs;
+
+use common::{addi, b_type, bne};
+use ksim::coverage::{Coverage, LineMap};
+use ksim::State;
+
+const SOURCE: &str = "    addi x1, x0, 1
+# the bne goes to the next instruction
+    bne x1, x0, next
+next: beq x1, x0, end
+    addi x2, x0, 2
+    exit
+end: addi x3, x0, 3
+    exit
+";
+
+#[test]
+fn lcov_and_listing() {
+    let program = vec![addi(1, 0, 1), bne(1, 0, 4), b_type(0, 1, 0, 12), addi(2, 0, 2),
+                       common::EXIT, addi(3, 0, 3), common::EXIT];
+    let dir = env::temp_dir();
+    let file = |name: &str| {
+        let path = dir.join(format!("ksim-test-{}-{}", std::process::id(), name));
+        path.to_str().unwrap().to_string()
+    };
+    let (source, map_file, lcov, listing) = (file("cov.asm"), file("cov.map"),
+                                             file("cov.info"), file("cov.gcov"));
+    fs::write(&source, SOURCE).unwrap();
+    let lines = [1, 3, 4, 5, 6, 7, 8];
+    let text: String = lines.iter().enumerate()
+        .map(|(i, line)| format!("{:x} {}\n", 4 * i, line)).collect();
+    fs::write(&map_file, format!("source {}\n{}", source, text)).unwrap();
+
+    let mut state = State::init(program);
+    state.observers.coverage = Some(Coverage::default());
+    state.run(None);
+    let map = LineMap::load(&map_file);
+    let coverage = state.observers.coverage.as_ref().unwrap();
+    coverage.write_lcov(&lcov, &map, &state);
+    coverage.write_listing(&listing, &map, &state);
+    let lcov_text = fs::read_to_string(&lcov).unwrap();
+    let listing_text = fs::read_to_string(&listing).unwrap();
+    for path in [&source, &map_file, &lcov, &listing].iter() {
+        fs::remove_file(path).unwrap();
+    }
+
+    assert_eq!(lcov_text, format!("TN:\nSF:{}\n\
+                                   BRDA:3,0,0,1\nBRDA:3,0,1,0\nBRDA:4,0,0,0\nBRDA:4,0,1,1\n\
+                                   BRF:4\nBRH:2\n\
+                                   DA:1,1\nDA:3,1\nDA:4,1\nDA:5,1\nDA:6,1\nDA:7,0\nDA:8,0\n\
+                                   LF:7\nLH:5\nend_of_record\n", source));
+    let expected = [
+        format!("        -:    0:Source:{}", source),
+        "        -:    0:Lines executed:71.43% of 7".to_string(),
+        "        -:    0:Branch directions taken:50.00% of 4".to_string(),
+        "        1:    1:    addi x1, x0, 1".to_string(),
+        "        -:    2:# the bne goes to the next instruction".to_string(),
+        "        1:    3:    bne x1, x0, next  [taken 1, not taken 0]".to_string(),
+        "        1:    4:next: beq x1, x0, end  [taken 0, not taken 1]".to_string(),
+        "        1:    5:    addi x2, x0, 2".to_string(),
+        "        1:    6:    exit".to_string(),
+        "    #####:    7:end: addi x3, x0, 3".to_string(),
+        "    #####:    8:    exit".to_string(),
+    ];
+    assert_eq!(listing_text.lines().collect::<Vec<_>>(), expected);
+}
@@ -0,0 +1,95 @@
+// Frames a program draws into the framebuffer, dumped as PPM and PGM
+// files, and the control registers.
+
+extern crate ksim;
+
+mod common;
+
+use std::env;
+use std::fs;
+
+use common::{addi, lui, lw, s_type, sw};
+use ksim::framebuffer::{Format, Framebuffer};
+use ksim::Machine;
+
+const SB: u32 = 0;
+const SH: u32 = 1;
+const SW: u32 = 2;
+
+// Load a 32-bit constant into rd.
+fn li(rd: u32, value: u32) -> Vec<u32> {
+    let low = ((value & 0xfff) as i32) << 20 >> 20;
+    vec![lui(rd, value.wrapping_sub(low as u32) >> 12), addi(rd, rd, low)]
+}
+
+// Store the pixels with stores of `funct3`, dump a frame, clear the first
+// pixel and dump another.  Returns both files and the registers read back.
+fn draw(name: &str, width: u32, height: u32, format: Format, funct3: u32, pixels: &[u32])
+        -> (Vec<Vec<u8>>, Vec<u64>) {
+    let mut program = vec![lui(5, 0x20000), lui(6, 0x10000), addi(28, 0, 1)];
+    let bytes = 1 << funct3;
+    for (i, &pixel) in pixels.iter().enumerate() {
+        program.extend(li(7, pixel));
+        program.push(s_type(funct3, 5, 7, bytes * i as i32));
+    }
+    program.extend(vec![sw(6, 28, 0), s_type(funct3, 5, 0, 0), sw(6, 28, 0)]);
+    for (i, rd) in (10..14).enumerate() {
+        program.push(lw(rd, 6, 4 * i as i32));
+    }
+    program.push(common::EXIT);
+
+    let prefix = env::temp_dir().join(format!("ksim-test-{}-{}-", std::process::id(), name));
+    let fb = Framebuffer::new(width, height, format, prefix.to_str().unwrap().to_string());
+    let paths = [fb.frame_path(0), fb.frame_path(1)];
+    let mut machine = Machine::new(program, 1);
+    machine.set_framebuffer(fb);
+    machine.run(None);
+    let frames = paths.iter().map(|path| {
+        let data = fs::read(path).unwrap();
+        fs::remove_file(path).unwrap();
+        data
+    }).collect();
+    let registers = (10..14).map(|i| machine.harts[0].register(i)).collect();
+    (frames, registers)
+}
+
+fn image(header: &str, pixels: &[u8]) -> Vec<u8> {
+    let mut data = header.as_bytes().to_vec();
+    data.extend_from_slice(pixels);
+    data
+}
+
+#[test]
+fn xrgb8888() {
+    let (frames, registers) = draw("xrgb", 2, 1, Format::Xrgb8888, SW, &[0xff8000, 0x123456]);
+    assert_eq!(registers, vec![2, 2, 1, 32]);
+    assert_eq!(frames[0], image("P6\n2 1\n255\n", &[0xff, 0x80, 0x00, 0x12, 0x34, 0x56]));
+    assert_eq!(frames[1], image("P6\n2 1\n255\n", &[0x00, 0x00, 0x00, 0x12, 0x34, 0x56]));
+}
+
+#[test]
+fn rgb565_channels_are_widened() {
+    // full red, and 1, 2 and 1 in the red, green and blue fields
+    let (frames, registers) = draw("rgb565", 2, 1, Format::Rgb565, SH, &[0xf800, 0x0841]);
+    assert_eq!(registers, vec![2, 2, 1, 16]);
+    assert_eq!(frames[0], image("P6\n2 1\n255\n", &[0xff, 0x00, 0x00, 0x08, 0x08, 0x08]));
+    assert_eq!(frames[1], image("P6\n2 1\n255\n", &[0x00, 0x00, 0x00, 0x08, 0x08, 0x08]));
+}
+
+#[test]
+fn gray8_is_a_pgm() {
+    let (frames, registers) = draw("gray8", 1, 2, Format::Gray8, SB, &[0x10, 0xff]);
+    assert_eq!(registers, vec![2, 1, 2, 8]);
+    assert_eq!(frames[0], image("P5\n1 2\n255\n", &[0x10, 0xff]));
+    assert_eq!(frames[1], image("P5\n1 2\n255\n", &[0x00, 0xff]));
+}
+
+#[test]
+fn parse_specs() {
+    let fb = Framebuffer::parse("");
+    assert_eq!((fb.width, fb.height, fb.format, fb.prefix.as_str()),
+               (320, 240, Format::Xrgb8888, "frame"));
+    let fb = Framebuffer::parse("width=64,format=gray8,prefix=out/f");
+    assert_eq!((fb.width, fb.height, fb.format), (64, 240, Format::Gray8));
+    assert_eq!(fb.frame_path(12), "out/f0012.ppm");
+}
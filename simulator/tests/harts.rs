@@ -0,0 +1,66 @@
+// Several harts taking turns on a shared bus.
+
+extern crate ksim;
+
+mod common;
+
+use common::{addi, bne, jal, EXIT};
+use ksim::csr::MHARTID;
+use ksim::{Limits, Machine, Stop};
+
+#[test]
+fn harts_know_their_ids() {
+    let machine = Machine::new(vec![EXIT], 3);
+    for (i, hart) in machine.harts.iter().enumerate() {
+        assert_eq!(hart.register(10), i as u64);
+        assert_eq!(hart.read_csr(MHARTID), Some(i as u64));
+    }
+}
+
+#[test]
+fn round_robin_quantum() {
+    let mut machine = Machine::new(vec![addi(1, 1, 1), jal(0, -4)], 2);
+    machine.set_quantum(3);
+    assert!(matches!(machine.run(Some(10)), Stop::Limit));
+    // 3 + 3 + 3 + 1
+    assert_eq!(machine.harts[0].instret(), 6);
+    assert_eq!(machine.harts[1].instret(), 4);
+}
+
+#[test]
+#[should_panic]
+fn quantum_of_zero() {
+    Machine::new(vec![EXIT], 2).set_quantum(0);
+}
+
+#[test]
+fn exit_ends_the_machine() {
+    // hart 0 spins, hart 1 exits
+    let program = vec![bne(10, 0, 8), jal(0, -4), EXIT];
+    let mut machine = Machine::new(program, 2);
+    machine.harts[1].capture_output();
+    assert!(matches!(machine.run(Some(1000)), Stop::Exit));
+    assert!(machine.harts[0].instret() < 10);
+}
+
+#[test]
+fn stuck_only_when_all_harts_are_stuck() {
+    let limits = Limits { detect_loops: true, ..Limits::default() };
+    // hart 0 is stuck at once, hart 1 counts to 50 first
+    let program = vec![
+        bne(10, 0, 8),         // 0x00
+        jal(0, 0),             // 0x04
+        addi(5, 5, 1),         // 0x08
+        addi(6, 0, 50),        // 0x0c
+        bne(5, 6, -8),         // 0x10
+        jal(0, 0),             // 0x14
+    ];
+    let mut machine = Machine::new(program.clone(), 2);
+    assert!(matches!(machine.run_with(&limits), Stop::InfiniteLoop(0x14)));
+    assert_eq!(machine.harts[1].register(5), 50);
+
+    // a hart that runs off the end of the program stops, leaving hart 0
+    let mut machine = Machine::new(program[..5].to_vec(), 2);
+    assert!(matches!(machine.run_with(&limits), Stop::InfiniteLoop(0x04)));
+    assert_eq!(machine.harts[1].register(5), 50);
+}
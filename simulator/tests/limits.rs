@@ -0,0 +1,73 @@
+// Instruction limits, timeouts and infinite-loop detection, and the exit
+// statuses they give the command-line simulator.
+
+extern crate ksim;
+
+mod common;
+
+use std::env;
+use std::fs;
+use std::process::Command;
+use std::time::Duration;
+
+use common::{addi, jal};
+use ksim::{Limits, State, Stop};
+
+// x1 counts up forever, so the loop is never stuck.
+fn counter() -> Vec<u32> {
+    vec![addi(1, 1, 1), jal(0, -4)]
+}
+
+#[test]
+fn instruction_limit() {
+    let mut state = State::init(common::sum_program(100));
+    assert!(matches!(state.run(Some(50)), Stop::Limit));
+    assert_eq!(state.instret(), 50);
+    // the limit counts from where the run starts
+    assert!(matches!(state.run(Some(7)), Stop::Limit));
+    assert_eq!(state.instret(), 57);
+}
+
+#[test]
+fn timeout() {
+    let mut state = State::init(counter());
+    let limits = Limits { timeout: Some(Duration::from_millis(20)), detect_loops: true,
+                          ..Limits::default() };
+    assert!(matches!(state.run_with(&limits), Stop::Timeout));
+    assert!(state.register(1) > 0);
+}
+
+#[test]
+fn infinite_loop() {
+    let program = vec![addi(1, 0, 5), jal(0, 0)];
+    let limits = Limits { detect_loops: true, ..Limits::default() };
+    let mut state = State::init(program.clone());
+    assert!(matches!(state.run_with(&limits), Stop::InfiniteLoop(4)));
+    assert_eq!(state.register(1), 5);
+
+    // without detection it is just a long run
+    let mut state = State::init(program);
+    let limits = Limits { instructions: Some(1000), ..Limits::default() };
+    assert!(matches!(state.run_with(&limits), Stop::Limit));
+}
+
+// Run the simulator on program with options and return its exit status.
+fn status(name: &str, program: &[u32], options: &[&str]) -> i32 {
+    let path = env::temp_dir().join(format!("ksim-test-{}-{}.txt", std::process::id(), name));
+    let text: String = program.iter().map(|w| format!("{:032b}\n", w)).collect();
+    fs::write(&path, text).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_ksim")).args(options).arg(&path)
+        .output().unwrap();
+    fs::remove_file(&path).unwrap();
+    output.status.code().unwrap()
+}
+
+#[test]
+fn exit_statuses() {
+    assert_eq!(status("exit", &common::sum_program(10), &[]), 0);
+    assert_eq!(status("limit", &counter(), &["--max-instructions", "100"]), 2);
+    assert_eq!(status("timeout", &counter(), &["--timeout", "0.05"]), 3);
+    assert_eq!(status("loop", &[jal(0, 0)], &[]), 4);
+    assert_eq!(status("bad-timeout", &counter(), &["--timeout", "inf"]), 1);
+    assert_eq!(status("nan-timeout", &counter(), &["--timeout", "nan"]), 1);
+}
@@ -0,0 +1,55 @@
+// The pipeline models on short sequences whose timing is worked out by
+// hand.
+
+extern crate ksim;
+
+mod common;
+
+use common::{addi, bne, r_type};
+use ksim::costs::CostModel;
+use ksim::pipeline::{Pipeline, REASONS};
+use ksim::State;
+
+fn mul(rd: u32, rs1: u32, rs2: u32) -> u32 {
+    r_type(0x33, 0, 1, rd, rs1, rs2)
+}
+
+fn run(program: Vec<u32>, pipeline: Pipeline) -> Pipeline {
+    let mut state = State::init(program);
+    state.observers.pipeline = Some(pipeline);
+    state.run(None);
+    state.observers.pipeline.take().unwrap()
+}
+
+fn stall(pipeline: &Pipeline, reason: &str) -> u64 {
+    pipeline.stats.stalls[REASONS.iter().position(|&r| r == reason).unwrap()]
+}
+
+#[test]
+fn dependences_and_serializing() {
+    let program = vec![
+        addi(1, 0, 5),  // dispatched in 0, issued in 1, committed in 2
+        mul(2, 1, 1),   // 0, waits for x1 until 2, 5
+        mul(3, 2, 1),   // 1, waits for x2 until 5, 8
+        addi(4, 0, 1),  // 1, 2, but commits in 8 in order
+        addi(5, 0, 2),  // 2, 3, 9 as only two commit per cycle
+        common::EXIT,   // waits for the ROB to drain: 10, 11, 12
+    ];
+    let pipeline = run(program, Pipeline::parse("ooo,width=2"));
+    assert_eq!((pipeline.stats.instructions, pipeline.stats.cycles), (6, 13));
+    assert_eq!(pipeline.ipc(), 6.0 / 13.0);
+    assert_eq!(stall(&pipeline, "operands not ready"), 1 + 3);
+    assert_eq!(stall(&pipeline, "serializing instruction"), 8);
+    let total: u64 = pipeline.stats.stalls.iter().sum();
+    assert_eq!(total, 12);
+}
+
+#[test]
+fn taken_branch_to_the_next_instruction() {
+    let mut pipeline = Pipeline::parse("ooo");
+    pipeline.set_latencies(CostModel::parse("branch-taken 5").unwrap().cycles());
+    // the bne is taken, even though it goes on at pc + 4: it completes in
+    // 2 + 5 and exit then issues in 9
+    let pipeline = run(vec![addi(1, 0, 1), bne(1, 0, 4), common::EXIT], pipeline);
+    assert_eq!(pipeline.stats.cycles, 11);
+}
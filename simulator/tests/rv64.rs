@@ -0,0 +1,194 @@
+// The RV64 word operations, the division corner cases and doubleword
+// memory accesses.
+
+extern crate ksim;
+
+mod common;
+
+use std::env;
+use std::fs;
+
+use common::{i_type, r_type, s_type};
+use ksim::sanitize::{Problem, Sanitizer};
+use ksim::symbols::Symbols;
+use ksim::vcd::Vcd;
+use ksim::State;
+
+const MIN32: u64 = 0xffff_ffff_8000_0000;
+
+// Execute one instruction with x1 = a and x2 = b and return x3.
+fn execute(xlen: u32, instruction: u32, a: u64, b: u64) -> u64 {
+    let mut state = State::init(vec![instruction]);
+    state.set_xlen(xlen);
+    state.set_register(1, a);
+    state.set_register(2, b);
+    state.step();
+    state.register(3)
+}
+
+// x3 = x1 op x2 with OP-32 (word) or OP (full width) encodings
+fn word(funct3: u32, funct7: u32, a: u64, b: u64) -> u64 {
+    execute(64, r_type(0x3b, funct3, funct7, 3, 1, 2), a, b)
+}
+
+fn full(xlen: u32, funct3: u32, funct7: u32, a: u64, b: u64) -> u64 {
+    execute(xlen, r_type(0x33, funct3, funct7, 3, 1, 2), a, b)
+}
+
+fn word_imm(funct3: u32, imm: i32, a: u64) -> u64 {
+    execute(64, i_type(0x1b, funct3, 3, 1, imm), a, 0)
+}
+
+#[test]
+fn word_arithmetic_sign_extends() {
+    // addw, subw
+    assert_eq!(word(0, 0, 0x7fff_ffff, 1), MIN32);
+    assert_eq!(word(0, 0, 0xdead_beef_0000_0005, 0x1234_5678_0000_0003), 8);
+    assert_eq!(word(0, 0x20, 0, 1), u64::MAX);
+    assert_eq!(word(0, 0x20, 0x8000_0000, 1), 0x7fff_ffff);
+    // addiw ignores the upper half of rs1
+    assert_eq!(word_imm(0, -1, 0xffff_ffff_0000_0000), u64::MAX);
+    assert_eq!(word_imm(0, 1, 0x0000_0001_7fff_ffff), MIN32);
+    // mulw keeps the low 32 bits of the product
+    assert_eq!(word(0, 1, 0x1_0000, 0x1_0000), 0);
+    assert_eq!(word(0, 1, 0x1_0000, 0x8000), MIN32);
+}
+
+#[test]
+fn word_shifts_use_five_bits() {
+    // sllw, srlw and sraw by 33 shift by 1
+    assert_eq!(word(1, 0, 0x4000_0000, 33), MIN32);
+    assert_eq!(word(5, 0, 0xffff_ffff_8000_0000, 4), 0x0800_0000);
+    assert_eq!(word(5, 0x20, 0x8000_0000, 4), 0xffff_ffff_f800_0000);
+    assert_eq!(word(5, 0, 0x8000_0000, 33), 0x4000_0000);
+    // slliw, srliw, sraiw
+    assert_eq!(word_imm(1, 31, 1), MIN32);
+    assert_eq!(word_imm(5, 31, 0xffff_ffff_8000_0000), 1);
+    assert_eq!(word_imm(5, 0x400 | 31, 0x8000_0000), u64::MAX);
+}
+
+#[test]
+fn word_division() {
+    let m = 0xffff_ffff_ffff_ffff; // -1
+    // divw, divuw, remw, remuw by zero
+    assert_eq!(word(4, 1, 7, 0), m);
+    assert_eq!(word(5, 1, 7, 0), m);
+    assert_eq!(word(6, 1, 0xffff_ffff_ffff_fff9, 0), 0xffff_ffff_ffff_fff9);
+    assert_eq!(word(7, 1, 0x8000_0000, 0), MIN32);
+    // overflow: the most negative word divided by -1
+    assert_eq!(word(4, 1, 0x8000_0000, m), MIN32);
+    assert_eq!(word(6, 1, 0x8000_0000, m), 0);
+    // only the low words take part, and the quotient is sign-extended
+    assert_eq!(word(4, 1, 0x1_ffff_fff8, 2), 0xffff_ffff_ffff_fffc);
+    assert_eq!(word(5, 1, 0x1_ffff_fff8, 2), 0x7fff_fffc);
+    assert_eq!(word(6, 1, 0xffff_fff9, 2), m);
+    assert_eq!(word(7, 1, 0xffff_fff9, 2), 1);
+}
+
+#[test]
+fn full_width_division() {
+    let min = 1 << 63;
+    // div, divu, rem, remu by zero
+    assert_eq!(full(64, 4, 1, 7, 0), u64::MAX);
+    assert_eq!(full(64, 5, 1, 7, 0), u64::MAX);
+    assert_eq!(full(64, 6, 1, min, 0), min);
+    assert_eq!(full(64, 7, 1, 7, 0), 7);
+    // overflow
+    assert_eq!(full(64, 4, 1, min, u64::MAX), min);
+    assert_eq!(full(64, 6, 1, min, u64::MAX), 0);
+    // the same on RV32, where registers read back as 32-bit values
+    assert_eq!(full(32, 4, 1, 0x8000_0000, 0xffff_ffff), 0x8000_0000);
+    assert_eq!(full(32, 6, 1, 0x8000_0000, 0xffff_ffff), 0);
+    assert_eq!(full(32, 5, 1, 7, 0), 0xffff_ffff);
+    // mulh and mulhu of the most negative value
+    assert_eq!(full(64, 1, 1, min, min), 1 << 62);
+    assert_eq!(full(64, 3, 1, min, 2), 1);
+}
+
+// Run program on an RV64 hart with x1 = 0x100 and x2 = value.
+fn run64(program: Vec<u32>, value: u64, setup: &dyn Fn(&mut State)) -> State {
+    let n = program.len();
+    let mut state = State::init(program);
+    state.set_xlen(64);
+    state.set_register(1, 0x100);
+    state.set_register(2, value);
+    setup(&mut state);
+    for _ in 0..n {
+        state.step();
+    }
+    state
+}
+
+#[test]
+fn doublewords_are_single_accesses() {
+    let value = 0x1122_3344_5566_7788;
+    let path = env::temp_dir().join(format!("ksim-test-{}.vcd", std::process::id()));
+    let path = path.to_str().unwrap();
+    // sd x2, 4(x1); ld x3, 4(x1)
+    let program = vec![s_type(3, 1, 2, 4), i_type(0x03, 3, 3, 1, 4)];
+    let mut state = run64(program, value, &|state| {
+        state.observers.sanitizer = Some(Sanitizer::new(Symbols::default()));
+        state.observers.vcd = Some(Vcd::create(path, state));
+    });
+    assert_eq!(state.register(3), value);
+
+    // both accesses are misaligned as doublewords
+    let reports = &state.observers.sanitizer.as_ref().unwrap().reports;
+    assert_eq!(reports.len(), 2);
+    assert!(reports.iter().all(|r| r.problem == Problem::Misaligned && r.width == 8));
+
+    state.observers.vcd.as_mut().unwrap().finish();
+    let dump = fs::read_to_string(path).unwrap();
+    fs::remove_file(path).unwrap();
+    assert!(dump.contains("$var wire 64 & rdata $end"));
+    assert!(dump.contains("$var wire 64 ) wdata $end"));
+    assert!(dump.contains("$var wire 4 * wsize $end"));
+    assert!(dump.contains(&format!("b{:b} )", value)));
+    assert!(dump.contains(&format!("b{:b} &", value)));
+    assert!(dump.contains("b1000 *"));
+}
+
+#[test]
+fn stores_to_the_upper_word_clear_a_reservation() {
+    // lr.d x3, (x1); sc.d x4, x2, (x1)
+    let program = vec![r_type(0x2f, 3, 0x08, 3, 1, 0), r_type(0x2f, 3, 0x0c, 4, 1, 2)];
+    let state = run64(program.clone(), 7, &|_| {});
+    assert_eq!(state.register(4), 0);
+    assert_eq!(state.read_memory(0x100, 4), Some(7));
+
+    let mut state = State::init(program);
+    state.set_xlen(64);
+    state.set_register(1, 0x100);
+    state.step();
+    // as another hart would
+    state.write_memory(0x104, 1, 4).unwrap();
+    state.step();
+    assert_eq!(state.register(4), 1);
+    assert_eq!(state.read_memory(0x104, 4), Some(1));
+}
+
+#[test]
+fn traps_keep_whole_addresses() {
+    let csrw = |csr: i32, rs1: u32| i_type(0x73, 1, 0, rs1, csr);
+    let csrr = |rd: u32, csr: i32| i_type(0x73, 2, rd, 0, csr);
+    let program = vec![
+        csrw(0x305, 3),           // 0x00  mtvec = x3
+        csrw(0x340, 2),           // 0x04  mscratch = x2
+        i_type(0x03, 3, 4, 2, 0), // 0x08  ld x4, 0(x2), past the address space
+        common::EXIT,             // 0x0c
+        csrr(5, 0x343),           // 0x10  handler: x5 = mtval
+        csrr(6, 0x340),           // 0x14  x6 = mscratch
+        csrr(7, 0x341),           // 0x18  x7 = mepc
+        common::EXIT,             // 0x1c
+    ];
+    let address = 0x1_0000_0100;
+    let mut state = State::init(program);
+    state.set_xlen(64);
+    state.set_register(2, address);
+    state.set_register(3, 0x10);
+    state.run(None);
+    assert_eq!(state.register(5), address);
+    assert_eq!(state.register(6), address);
+    assert_eq!(state.register(7), 8);
+    assert_eq!(state.read_csr(ksim::csr::MTVAL), Some(address));
+}
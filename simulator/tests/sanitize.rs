@@ -0,0 +1,51 @@
+// The sanitizer's reports of uninitialized reads and accesses below sp.
+
+extern crate ksim;
+
+mod common;
+
+use common::{addi, bne, lw, sw};
+use ksim::sanitize::Problem;
+use ksim::symbols::Symbols;
+use ksim::Machine;
+
+#[test]
+fn uninitialized_and_below_stack() {
+    let program = vec![
+        addi(2, 0, 0x400),  // 0x00  sp = 0x400
+        addi(2, 2, -16),    // 0x04  push a frame
+        addi(5, 0, 7),      // 0x08
+        sw(2, 5, 4),        // 0x0c
+        lw(6, 2, 4),        // 0x10  written just before
+        lw(7, 2, 8),        // 0x14  never written
+        addi(2, 2, 16),     // 0x18  pop it
+        addi(9, 0, 2),      // 0x1c
+        lw(8, 2, -12),      // 0x20  loop: the popped frame, twice
+        addi(9, 9, -1),     // 0x24
+        bne(9, 0, -8),      // 0x28
+        common::EXIT,       // 0x2c
+    ];
+    let mut machine = Machine::new(program, 1);
+    machine.sanitize(&Symbols::default());
+    machine.run(None);
+    assert!(machine.sanitizer_failed());
+    let state = &machine.harts[0];
+    assert_eq!(state.register(8), 7);
+
+    let reports = &state.observers.sanitizer.as_ref().unwrap().reports;
+    let found: Vec<_> = reports.iter()
+        .map(|r| (r.pc, r.problem, r.addr, r.width, r.is_write, r.sp, r.count)).collect();
+    assert_eq!(found, vec![(0x14, Problem::Uninitialized, 0x3f8, 4, false, 0x3f0, 1),
+                           (0x20, Problem::BelowStack, 0x3f4, 4, false, 0x400, 2)]);
+    assert_eq!(reports[0].to_string(), "4-byte read of uninitialized memory at 0x000003f8");
+    assert_eq!(reports[1].to_string(), "4-byte read at 0x000003f4, below sp 0x00000400");
+}
+
+#[test]
+fn clean_program() {
+    let mut machine = Machine::new(common::sum_program(10), 1);
+    machine.sanitize(&Symbols::default());
+    machine.run(None);
+    assert_eq!(machine.harts[0].register(10), 55);
+    assert!(!machine.sanitizer_failed());
+}
@@ -0,0 +1,77 @@
+// An ELF program's code is in RAM, so storing to it changes what runs.
+
+extern crate ksim;
+
+mod common;
+
+use common::{addi, bne, lui, sw, EXIT};
+use ksim::elf::{Elf, Segment};
+use ksim::symbols::Symbols;
+use ksim::{Machine, Stop};
+
+const BASE: u32 = 0x8000_0000;
+const FENCE_I: u32 = 0x0000_100f;
+
+// Runs a program at BASE and returns a0.
+fn run_program(program: &[u32], blocks: bool) -> u64 {
+    let data = program.iter().flat_map(|w| w.to_le_bytes().to_vec()).collect::<Vec<u8>>();
+    let size = data.len() as u32;
+    let elf = Elf {
+        xlen: 32,
+        entry: BASE,
+        segments: vec![Segment { addr: BASE, data, size }],
+        symbols: Symbols::default(),
+    };
+    let mut machine = Machine::from_elf(&elf, 1, 4096);
+    let state = &mut machine.harts[0];
+    state.blocks = blocks;
+    state.capture_output();
+    assert!(matches!(state.run(None), Stop::Exit));
+    state.register(10)
+}
+
+// Patches the instruction at 0x14 to set a0 to 42 instead of 1, then runs
+// it; `sync` goes between the store and the patched instruction.
+fn run(sync: u32, blocks: bool) -> u64 {
+    let patched = addi(10, 0, 42);
+    let program = [
+        0x0000_0297,                              // auipc t0, 0
+        lui(6, patched >> 12),
+        addi(6, 6, (patched & 0xfff) as i32),
+        sw(5, 6, 0x14),
+        sync,
+        addi(10, 0, 1),                           // 0x14
+        EXIT,
+    ];
+    run_program(&program, blocks)
+}
+
+#[test]
+fn stores_change_code() {
+    for &blocks in [false, true].iter() {
+        assert_eq!(run(FENCE_I, blocks), 42);
+        // the hart that stores sees its own change even without fence.i
+        assert_eq!(run(addi(0, 0, 0), blocks), 42);
+    }
+}
+
+#[test]
+fn decoded_instructions_are_refreshed() {
+    // the loop runs the instruction at 0x10 once as decoded first, then
+    // patches it to add 100 instead of 1, from inside its own block
+    let patched = addi(10, 10, 100);
+    let program = [
+        0x0000_0297,                              // auipc t0, 0
+        lui(6, patched >> 12),
+        addi(6, 6, (patched & 0xfff) as i32),
+        addi(7, 0, 2),
+        addi(10, 10, 1),                          // 0x10
+        sw(5, 6, 0x10),
+        addi(7, 7, -1),
+        bne(7, 0, -12),
+        EXIT,
+    ];
+    for &blocks in [false, true].iter() {
+        assert_eq!(run_program(&program, blocks), 101);
+    }
+}
@@ -0,0 +1,41 @@
+// Instruction statistics: branch outcomes and instructions that trapped.
+
+extern crate ksim;
+
+mod common;
+
+use std::env;
+use std::fs;
+
+use common::{addi, b_type, bne, i_type};
+use ksim::stats::Stats;
+use ksim::State;
+
+#[test]
+fn branches_and_traps() {
+    let program = vec![
+        addi(1, 0, 1),                // 0x00
+        addi(3, 0, 0x1c),             // 0x04
+        i_type(0x73, 1, 0, 3, 0x305), // 0x08  mtvec = x3
+        bne(1, 0, 4),                 // 0x0c  taken, to the next instruction
+        b_type(0, 1, 0, 8),           // 0x10  beq, not taken
+        0xffff_ffff,                  // 0x14  illegal, traps to 0x1c
+        common::EXIT,                 // 0x18
+        common::EXIT,                 // 0x1c
+    ];
+    let mut state = State::init(program);
+    state.observers.stats = Some(Stats::default());
+    state.run(None);
+    let path = env::temp_dir().join(format!("ksim-test-{}-stats.json", std::process::id()));
+    let path = path.to_str().unwrap();
+    state.observers.stats.as_ref().unwrap().write_json(path, state.cycle());
+    let json = fs::read_to_string(path).unwrap();
+    fs::remove_file(path).unwrap();
+    // the illegal instruction counts, but has no class or mnemonic
+    assert!(json.contains("\"instructions\": 7,"));
+    assert!(json.contains(
+        "\"classes\": {\"alu\": 2, \"branch\": 2, \"custom\": 1, \"system\": 1},"));
+    assert!(json.contains(
+        "\"mnemonics\": {\"addi\": 2, \"beq\": 1, \"bne\": 1, \"csrrw\": 1, \"exit\": 1},"));
+    assert!(json.contains("\"branches\": {\"taken\": 1, \"not_taken\": 1},"));
+}
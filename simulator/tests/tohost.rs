@@ -0,0 +1,43 @@
+// Test suites end a run by storing to tohost.
+
+extern crate ksim;
+
+mod common;
+
+use common::{addi, lui, sw};
+use ksim::elf::{Elf, Segment};
+use ksim::symbols::Symbols;
+use ksim::{Machine, Stop};
+
+const BASE: u32 = 0x8000_0000;
+
+// Stores value to the tohost word that follows the code.
+fn run(value: i32) -> Machine {
+    let program = [lui(5, BASE >> 12), addi(6, 0, value), sw(5, 6, 16), 0x0000_006f, 0];
+    let data = program.iter().flat_map(|w| w.to_le_bytes().to_vec()).collect::<Vec<u8>>();
+    let size = data.len() as u32;
+    let elf = Elf {
+        xlen: 32,
+        entry: BASE,
+        segments: vec![Segment { addr: BASE, data, size }],
+        symbols: Symbols::new(vec![(BASE + 16, "tohost".to_string())]),
+    };
+    let mut machine = Machine::from_elf(&elf, 1, 4096);
+    let stop = machine.run(None);
+    match value {
+        2 => assert!(matches!(stop, Stop::HostRequest(2))),
+        _ => assert!(matches!(stop, Stop::Exit)),
+    }
+    machine
+}
+
+#[test]
+fn exit_codes() {
+    assert_eq!(run(1).host_exit(), Some(0));
+    assert_eq!(run(7).host_exit(), Some(3));
+}
+
+#[test]
+fn other_requests_stop_the_run() {
+    assert_eq!(run(2).host_exit(), None);
+}
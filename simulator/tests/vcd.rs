@@ -0,0 +1,76 @@
+// The Value Change Dump of a short run, and the options it does not work
+// with.
+
+extern crate ksim;
+
+mod common;
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::process::Command;
+
+use common::{addi, lw, sw};
+use ksim::State;
+use ksim::vcd::Vcd;
+
+// The changes at each time step of a dump, as (signal name, value) pairs.
+fn changes(text: &str) -> Vec<(u64, Vec<(String, u64)>)> {
+    let mut names = HashMap::new();
+    let mut steps: Vec<(u64, Vec<(String, u64)>)> = Vec::new();
+    for line in text.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields[0] == "$var" {
+            names.insert(fields[3].to_string(), fields[4].to_string());
+        } else if let Some(time) = line.strip_prefix('#') {
+            steps.push((time.parse().unwrap(), Vec::new()));
+        } else if let Some(value) = fields[0].strip_prefix('b') {
+            let value = u64::from_str_radix(value, 2).unwrap();
+            steps.last_mut().unwrap().1.push((names[fields[1]].clone(), value));
+        } else if line.starts_with('0') || line.starts_with('1') {
+            let value = line[..1].parse().unwrap();
+            steps.last_mut().unwrap().1.push((names[&line[1..]].clone(), value));
+        }
+    }
+    steps
+}
+
+fn pairs(list: &[(&str, u64)]) -> Vec<(String, u64)> {
+    list.iter().map(|&(name, value)| (name.to_string(), value)).collect()
+}
+
+#[test]
+fn loads_stores_and_registers() {
+    let path = env::temp_dir().join(format!("ksim-test-{}.vcd", std::process::id()));
+    let program = vec![addi(10, 0, 0x100), addi(11, 0, 7), sw(10, 11, 0), lw(12, 10, 0),
+                       common::EXIT];
+    let mut state = State::init(program.clone());
+    state.observers.vcd = Some(Vcd::create(path.to_str().unwrap(), &state));
+    state.run(None);
+    state.observers.vcd.as_mut().unwrap().finish();
+    let text = fs::read_to_string(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+
+    let steps = changes(&text);
+    let times: Vec<u64> = steps.iter().map(|s| s.0).collect();
+    assert_eq!(times, vec![0, 1, 2, 3, 4, 5]);
+    // everything has a value at time 0, in machine mode
+    assert!(steps[0].1.contains(&("priv".to_string(), 3)));
+    assert!(steps[0].1.contains(&("a0".to_string(), 0)));
+    // only changes are written, and the pc of the first instruction is
+    // still 0
+    assert_eq!(steps[1].1, pairs(&[("insn", program[0] as u64), ("a0", 0x100)]));
+    assert_eq!(steps[3].1, pairs(&[("pc", 8), ("insn", program[2] as u64), ("we", 1),
+                                   ("waddr", 0x100), ("wdata", 7), ("wsize", 4)]));
+    assert_eq!(steps[4].1, pairs(&[("pc", 12), ("insn", program[3] as u64), ("re", 1),
+                                   ("raddr", 0x100), ("rdata", 7), ("we", 0), ("a2", 7)]));
+    assert_eq!(steps[5].1[..3], pairs(&[("pc", 16), ("insn", program[4] as u64), ("re", 0)])[..]);
+}
+
+#[test]
+fn not_with_the_debugger() {
+    let output = Command::new(env!("CARGO_BIN_EXE_ksim"))
+        .args(["--debug", "--vcd", "out.vcd", "program.txt"]).output().unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--vcd does not work with --debug"));
+}
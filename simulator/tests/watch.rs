@@ -0,0 +1,47 @@
+// Watchpoints on reads, writes and registers stop the run after the
+// instruction that hits them.
+
+extern crate ksim;
+
+mod common;
+
+use common::{addi, lw, sw};
+use ksim::watch::{Hit, Watchpoint, Watchpoints};
+use ksim::{State, Stop};
+
+fn hits(stop: Stop) -> Vec<String> {
+    match stop {
+        Stop::Watchpoint(hits) => hits.iter().map(Hit::to_string).collect(),
+        other => panic!("expected a watchpoint, got {:?}", other),
+    }
+}
+
+#[test]
+fn reads_writes_and_registers() {
+    let program = vec![
+        addi(10, 0, 0x100), // 0x00
+        addi(11, 0, 7),     // 0x04
+        sw(10, 11, 0),      // 0x08
+        lw(12, 10, 0),      // 0x0c
+        addi(13, 0, 1),     // 0x10
+        sw(10, 13, 4),      // 0x14  next to the watched bytes
+        common::EXIT,       // 0x18
+    ];
+    let list = ["0x100:r", "0x102:1:rw", "a3"].iter()
+        .map(|spec| Watchpoint::parse(spec).unwrap()).collect();
+    let mut state = State::init(program);
+    state.observers.watch = Some(Watchpoints { list, hits: Vec::new() });
+
+    assert_eq!(hits(state.run(None)), vec![
+        "Watchpoint (access of 0x00000102 (1 bytes)) at pc 0x00000008: mem[0x00000100] 0x0 -> 0x7",
+    ]);
+    assert_eq!(hits(state.run(None)), vec![
+        "Watchpoint (read of 0x00000100 (4 bytes)) at pc 0x0000000c: mem[0x00000100] read 0x7",
+        "Watchpoint (access of 0x00000102 (1 bytes)) at pc 0x0000000c: mem[0x00000100] read 0x7",
+    ]);
+    assert_eq!(hits(state.run(None)), vec![
+        "Watchpoint (write of a3) at pc 0x00000010: a3 0x0 -> 0x1",
+    ]);
+    assert!(matches!(state.run(None), Stop::Exit));
+    assert_eq!(state.register(12), 7);
+}